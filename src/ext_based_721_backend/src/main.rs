@@ -1,16 +1,37 @@
 use ic_cdk::export::candid::{candid_method, Nat};
 use ic_cdk::export::Principal;
-use ic_cdk_macros::{init, post_upgrade, pre_upgrade, query, update};
+use ic_cdk_macros::{heartbeat, init, post_upgrade, pre_upgrade, query, update};
 use prop::PropMetadata;
 mod module;
 mod prop;
+use crate::module::auction;
+use crate::module::cap;
+use crate::module::certification;
+use crate::module::craft;
+use crate::module::dedup;
+use crate::module::diagnostics;
 use crate::module::ledger;
 use crate::module::dip721;
+use crate::module::env;
+use crate::module::icrc3;
+use crate::module::icrc37;
+use crate::module::icrc7;
+use crate::module::job;
+use crate::module::lock;
+use crate::module::marketplace;
+use crate::module::mint_phase;
+use crate::module::mint_sale;
+use crate::module::mystery_box;
+use crate::module::rbac;
+use crate::module::reveal;
+use crate::module::royalty;
+use crate::module::swap;
 use crate::module::token_identifier;
 use crate::module::types::*;
+use crate::module::upgrade;
 use cap_sdk::IndefiniteEvent;
-use ic_cdk::{api::time, trap};
-use std::collections::{HashSet};
+use ic_cdk::trap;
+use std::collections::HashSet;
 
 #[init]
 #[candid_method(init)]
@@ -20,61 +41,113 @@ fn init(args: Option<InitArgs>) {
 
 #[update]
 #[candid_method(update)]
-fn init_prop() -> Vec<prop::PropMetadata> {
-    prop::init()
+fn init_prop() -> Result<Vec<prop::PropMetadata>, NftError> {
+    rbac::ensure_role(Role::MetadataEditor)?;
+    Ok(prop::init())
 }
 
 
 #[allow(non_snake_case)]
 #[update]
 #[candid_method(update)]
-fn mintNFT(mint_request: MintRequest) -> TokenIndex {
-    mint_internal(mint_request)
+fn mintNFT(mint_request: MintRequest) -> Result<TokenIndex, NftError> {
+    rbac::ensure_role(Role::Minter)?;
+    dedup::idempotent(
+        &env::caller(),
+        "mintNFT",
+        &mint_request,
+        mint_request.created_at_time,
+        || mint_internal(mint_request.clone()),
+    )?
 }
 
-fn mint_internal(mint_request: MintRequest) -> TokenIndex {
-    let token_id = dip721::new_token_id();
-    let class = mint_request.class;
-    let pid = ic_cdk::api::id();
-    let cid = token_identifier::CanisterId(pid);
-    let encode_idx = token_identifier::TokenIndex(token_id as u32);
-    let encoded_token = token_identifier::encode_token_id(cid, encode_idx);
+#[update]
+#[candid_method(update)]
+fn set_mint_sale(request: MintSaleRequest) -> Result<(), NftError> {
+    mint_sale::set_mint_sale(request)
+}
+
+#[update]
+#[candid_method(update)]
+fn remove_mint_sale(class: String) -> Result<(), NftError> {
+    mint_sale::remove_mint_sale(class)
+}
+
+#[query]
+#[candid_method(query)]
+fn mint_sales() -> Vec<MintSale> {
+    mint_sale::mint_sales()
+}
+
+#[query]
+#[candid_method(query)]
+fn mint_payment_address() -> String {
+    mint_sale::payment_address()
+}
+
+#[update]
+#[candid_method(update)]
+async fn claim(class: String) -> Result<Vec<TokenIndex>, NftError> {
+    mint_sale::claim(class).await
+}
+
+#[update]
+#[candid_method(update)]
+fn set_mint_phases(phases: Vec<MintPhase>) -> Result<(), NftError> {
+    mint_phase::set_mint_phases(phases)
+}
+
+#[update]
+#[candid_method(update)]
+fn add_allowlist(phase: String, entries: Vec<(String, u32)>) -> Result<u64, NftError> {
+    mint_phase::add_allowlist(phase, entries)
+}
 
-    let token_obj = token_identifier::decode_token_id(&encoded_token).unwrap();
-    let arg_mint = Nat::from(token_obj.index.get_value());
-    prop::add_token(&arg_mint, &encoded_token);
+#[update]
+#[candid_method(update)]
+fn clear_allowlist(phase: String) -> Result<(), NftError> {
+    mint_phase::clear_allowlist(phase)
+}
 
+#[query]
+#[candid_method(query)]
+fn mint_phases() -> Vec<MintPhase> {
+    mint_phase::mint_phases()
+}
+
+#[query]
+#[candid_method(query)]
+fn active_mint_phase() -> Option<MintPhase> {
+    mint_phase::active_mint_phase()
+}
+
+#[query]
+#[candid_method(query)]
+fn remaining_quota() -> Vec<(String, Option<u32>)> {
+    mint_phase::remaining_quota()
+}
+
+fn mint_internal(mint_request: MintRequest) -> Result<TokenIndex, NftError> {
+    let recipient = mint_request.to.clone();
+    mint_phase::consume(&recipient, 1)?;
+    mint_within_quota(mint_request).map_err(|err| {
+        mint_phase::release(&recipient, 1);
+        err
+    })
+}
+
+// Mints without touching the phase quota, for callers that consumed it up front.
+fn mint_within_quota(mint_request: MintRequest) -> Result<TokenIndex, NftError> {
     let to = match mint_request.to {
         User::principal(pid) => pid,
         User::address(_aid) => Principal::anonymous(),
     };
 
-    let properties = prop::with(|props| {
-        props
-            .iter()
-            .filter(|p| *p.class() == class)
-            .map(|p| {
-                vec![
-                    (
-                        String::from("class"),
-                        GeneralValue::TextContent(p.class().clone()),
-                    ),
-                    (
-                        String::from("desc"),
-                        GeneralValue::TextContent(p.desc().clone()),
-                    ),
-                    (
-                        String::from("imageUri"),
-                        GeneralValue::TextContent(p.image_uri().clone()),
-                    ),
-                ]
-            })
-            .next()
-            .unwrap_or(vec![])
-    });
+    let properties = prop::properties_of(&mint_request.class);
 
-    let res = dip721::dip721_mint(to, arg_mint, properties);
-    res.unwrap().to_string().parse::<u32>().unwrap()
+    let (token_identifier, _) = dip721::mint_next(env::caller(), to, properties)?;
+    u32::try_from(&token_identifier.0)
+        .map_err(|_| NftError::InvalidTokenIdentifier(token_identifier.to_string()))
 }
 
 #[query]
@@ -83,6 +156,9 @@ pub fn token_identifier(id: Nat) -> String {
     prop::tokens(&id)
 }
 
+// EXT's `metadata` keeps its standard shape and is not certified. Clients that
+// have to verify a token use `certified_token`, which witnesses the owner and
+// the same visible properties.
 #[query]
 #[candid_method(query)]
 pub fn metadata(token: token_identifier::TokenIdentifier) -> Option<TokenMetaDataExt> {
@@ -93,6 +169,7 @@ pub fn metadata(token: token_identifier::TokenIdentifier) -> Option<TokenMetaDat
     let metadata = dip721::dip721_token_metadata(Nat::from(token_id).to_owned());
     match metadata {
         Ok(data) => {
+            let properties = reveal::visible_properties(&data);
             let nest_value = GeneralValue::NestedContent(vec![
                 (
                     "token_identifier".into(),
@@ -104,7 +181,7 @@ pub fn metadata(token: token_identifier::TokenIdentifier) -> Option<TokenMetaDat
                 ),
                 (
                     "properties".into(),
-                    GeneralValue::NestedContent(data.properties),
+                    GeneralValue::NestedContent(properties),
                 ),
                 (
                     "minted_at".into(),
@@ -137,10 +214,7 @@ pub fn metadata(token: token_identifier::TokenIdentifier) -> Option<TokenMetaDat
                 // ),
             ]);
 
-            let value = match serde_json::to_vec(&nest_value) {
-                Ok(v) => Some(v),
-                Err(_) => None,
-            };
+            let value = serde_json::to_vec(&nest_value).ok();
 
             Some(TokenMetaDataExt::nonfungible({
                 MetaDataNonFungibleDetails { metadata: value }
@@ -150,11 +224,26 @@ pub fn metadata(token: token_identifier::TokenIdentifier) -> Option<TokenMetaDat
     }
 }
 
+// Not certified either; `certified_tokens_of` witnesses the same token list.
 #[query]
 #[candid_method(query)]
 fn tokens_ext(principal_id: Principal) -> NFTResult {
-    let vec_u8 =  tokensext(principal_id);
-    NFTResult::ok(vec![ResultDetail(0u32, None, vec_u8)])
+    let mut token_ids = match dip721::dip721_owner_token_identifiers(principal_id) {
+        Ok(token_ids) => token_ids.into_iter().collect::<Vec<_>>(),
+        Err(_) => return NFTResult::ok(vec![]),
+    };
+    token_ids.sort();
+
+    NFTResult::ok(
+        token_ids
+            .into_iter()
+            .filter_map(|id| u32::try_from(&id.0).ok())
+            .map(|id| {
+                let listing = ledger::with(|ledger| marketplace::listing_of(ledger, &Nat::from(id)));
+                ResultDetail(id, listing, get_token_metadata_by_u32(id).ok())
+            })
+            .collect(),
+    )
 }
 
 
@@ -163,32 +252,38 @@ fn tokens_ext(principal_id: Principal) -> NFTResult {
 #[candid_method(query)]
 pub fn getTokens() -> Vec<(TokenIndex, TokenMetaDataExt)> {
     let mut res = Vec::new();
-    let num = dip721::dip721_total_supply().to_string().parse::<u32>().unwrap();
-    for item in 1..(num + 1) {
-        match get_token_metadata_by_u32(item.to_owned()) {
-            Ok(token) => {
-                let token_metadata = 
-                TokenMetaDataExt::nonfungible({
-                    MetaDataNonFungibleDetails {
-                        metadata: Some(token),
-                    }
-                });
-                res.push((item.to_owned(), token_metadata.to_owned()));
-            }
-            Err(_) => {}
+    // Token indexes are not contiguous (e.g. after burns), so walk the ledger.
+    let mut indexes = ledger::with(|ledger| {
+        ledger
+            .tokens
+            .keys()
+            .filter_map(|id| u32::try_from(&id.0).ok())
+            .collect::<Vec<_>>()
+    });
+    indexes.sort_unstable();
+    for item in indexes {
+        if let Ok(token) = get_token_metadata_by_u32(item.to_owned()) {
+            let token_metadata = 
+            TokenMetaDataExt::nonfungible({
+                MetaDataNonFungibleDetails {
+                    metadata: Some(token),
+                }
+            });
+            res.push((item.to_owned(), token_metadata.to_owned()));
         };
     }
     res
  }
 
 fn get_token_metadata_by_u32(id: u32) -> Result<Vec<u8>, CommonError> {
-    let pid = ic_cdk::api::id();
+    let pid = env::id();
     let cid = token_identifier::CanisterId(pid);
     let encode_idx = token_identifier::TokenIndex(id);
     let encoded_token = token_identifier::encode_token_id(cid, encode_idx);
     let metadata = dip721::dip721_token_metadata(Nat::from(id));
     let res = match metadata {
         Ok(data) => {
+            let properties = reveal::visible_properties(&data);
             let nest_value = GeneralValue::NestedContent(vec![
                 (
                     "token_identifier".into(),
@@ -200,7 +295,7 @@ fn get_token_metadata_by_u32(id: u32) -> Result<Vec<u8>, CommonError> {
                 ),
                 (
                     "properties".into(),
-                    GeneralValue::NestedContent(data.properties),
+                    GeneralValue::NestedContent(properties),
                 ),
                 (
                     "minted_at".into(),
@@ -208,52 +303,14 @@ fn get_token_metadata_by_u32(id: u32) -> Result<Vec<u8>, CommonError> {
                 ),
                 ("minted_by".into(), GeneralValue::Principal(data.minted_by)),
             ]); 
-            let value = match serde_json::to_vec(&nest_value) {
-                Ok(v) => Some(v),
-                Err(_) => None,
-            };
-            value
+            serde_json::to_vec(&nest_value)
+                .map_err(|err| NftError::InvalidMetadata(err.to_string()))?
         },
         Err(_) => return Err(CommonError::InvalidToken(encoded_token)),
     };
-    Ok(res.unwrap())
-}
-
-pub fn tokensext(pid: Principal) -> Option<Vec<u8>> {
-    let mut vec_u8 = Vec::new();
-    // let pid = AccountIdentifier_shiku::from_hex(&accountid);
-    // match pid {
-    //     Ok(principal) => {
-    //         let token_identi = dip721_owner_token_identifiers(principal);
-    //         match token_identi {
-    //             Ok(token) => {
-    //                 token.iter().for_each(|id| {
-    //                     let item = get_token_metadata_by_u32(id.to_string().parse::<u32>().unwrap()).unwrap();
-    //                     vec_u8.append(item.to_owned().as_mut());
-    //                 });
-    //                 Some(vec_u8)
-    //             },
-    //             Err(_) => Some(Vec::new())
-    //         }
-    //     },
-    //     Err(_) => Some(Vec::new())
-    // }
-
-    let token_identi = dip721::dip721_owner_token_identifiers(pid);
-            match token_identi {
-                Ok(token) => {
-                    token.iter().for_each(|id| {
-                        let item = get_token_metadata_by_u32(id.to_string().parse::<u32>().unwrap()).unwrap();
-                        vec_u8.append(item.to_owned().as_mut());
-                    });
-                    Some(vec_u8)
-                },
-                Err(_) => Some(Vec::new())
-            }
-    
+    Ok(res)
 }
 
-
 #[query]
 #[candid_method(query)]
 fn supply() -> Result_2 {
@@ -263,18 +320,14 @@ fn supply() -> Result_2 {
 
 #[update]
 #[candid_method(update)]
-fn approve(approve_request: ApproveRequest) -> bool {
+fn approve(approve_request: ApproveRequest) -> Result<(), NftError> {
     let spender = approve_request.spender;
     let token = approve_request.token;
-    let token_obj = token_identifier::decode_token_id(&token).unwrap();
+    let token_obj = token_identifier::decode_token_id(&token)
+        .map_err(|_| NftError::InvalidTokenIdentifier(token.clone()))?;
     let token_index = Nat::from(token_obj.index.get_value());
-    let approve_res = if let Some(value) = dip721::dip721_approve(spender, token_index).ok() {
-        let _v = value;
-        true
-    } else {
-        false
-    };
-    approve_res
+    dip721::dip721_approve(spender, token_index)?;
+    Ok(())
 }
 
 #[update]
@@ -286,10 +339,22 @@ fn burn(token_identifier: Token_ID) -> Result<Nat, NftError> {
 #[update]
 #[candid_method(update)]
 fn transfer(transfer_request: TransferRequest) -> TransferResponse {
-    transfer_internal(transfer_request)
+    match dedup::idempotent(
+        &env::caller(),
+        "transfer",
+        &transfer_request,
+        transfer_request.created_at_time,
+        || transfer_internal(transfer_request.clone()),
+    ) {
+        Ok(Ok(balance)) => TransferResponse::ok(balance),
+        Ok(Err(err)) => TransferResponse::err(err),
+        Err(err) => {
+            TransferResponse::err(TransferResponseDetails::Other(NftError::from(err).to_string()))
+        }
+    }
 }
 
-fn transfer_internal(transfer_request: TransferRequest) -> TransferResponse {
+fn transfer_internal(transfer_request: TransferRequest) -> Result<Balance, TransferResponseDetails> {
     let from = transfer_request.from;
     let to = transfer_request.to;
     let token = transfer_request.token;
@@ -301,37 +366,510 @@ fn transfer_internal(transfer_request: TransferRequest) -> TransferResponse {
         User::principal(pid) => pid,
         User::address(ref _aid) => Principal::anonymous(),
     };
-    let token_obj = token_identifier::decode_token_id(&token).unwrap();
+    let token_obj = match token_identifier::decode_token_id(&token) {
+        Ok(token_obj) => token_obj,
+        Err(_) => return Err(TransferResponseDetails::InvalidToken(token)),
+    };
     let token_index = Nat::from(token_obj.index.get_value());
 
-    match dip721::dip721_transfer_from(from_pid, to_pid, token_index) {
-        Ok(resp) => TransferResponse::ok(resp),
-        Err(NftError::UnauthorizedOwner) => TransferResponse::err(
-            TransferResponseDetails::Unauthorized(User::aid(from.clone())),
-        ),
-        Err(_) => {
-            TransferResponse::err(TransferResponseDetails::Other(String::from("Unkown Error")))
+    dip721::dip721_transfer_from(from_pid, to_pid, token_index)
+        .map_err(|err| transfer_error(err, &from))
+}
+
+// Maps an error onto the EXT transfer error shape.
+fn transfer_error(err: NftError, from: &User) -> TransferResponseDetails {
+    match err {
+        NftError::UnauthorizedOwner | NftError::UnauthorizedOperator => {
+            TransferResponseDetails::Unauthorized(User::aid(from.clone()))
         }
+        NftError::InvalidTokenIdentifier(token) => TransferResponseDetails::InvalidToken(token),
+        err => TransferResponseDetails::Other(err.to_string()),
     }
 }
 
 #[update]
 #[candid_method(update)]
-fn add(args: String) -> bool {
-    let prop = match PropMetadata::new(&args) {
-        Ok(prop) => prop,
-        Err(_) => return false,
-    };
+fn add(args: String) -> Result<(), NftError> {
+    rbac::ensure_role(Role::MetadataEditor)?;
+    let prop =
+        PropMetadata::new(&args).map_err(|err| NftError::InvalidMetadata(err.to_string()))?;
 
     prop::with_mut(|props| props.push(prop));
 
-    true
+    Ok(())
+}
+
+#[update]
+#[candid_method(update)]
+fn pause(operation: Operation) -> Result<(), NftError> {
+    dip721::dip721_set_paused(operation, true)
+}
+
+#[update]
+#[candid_method(update)]
+fn unpause(operation: Operation) -> Result<(), NftError> {
+    dip721::dip721_set_paused(operation, false)
+}
+
+#[update]
+#[candid_method(update)]
+fn set_name(name: String) -> Result<(), NftError> {
+    dip721::dip721_set_name(name)
+}
+
+#[update]
+#[candid_method(update)]
+fn set_logo(logo: String) -> Result<(), NftError> {
+    dip721::dip721_set_logo(logo)
+}
+
+#[update]
+#[candid_method(update)]
+fn set_symbol(symbol: String) -> Result<(), NftError> {
+    dip721::dip721_set_symbol(symbol)
+}
+
+#[update]
+#[candid_method(update)]
+fn grant_role(principal: Principal, role: Role) -> Result<(), NftError> {
+    rbac::grant_role(principal, role)
+}
+
+#[update]
+#[candid_method(update)]
+fn revoke_role(principal: Principal, role: Role) -> Result<(), NftError> {
+    rbac::revoke_role(principal, role)
+}
+
+#[query]
+#[candid_method(query)]
+fn roles_of(principal: Principal) -> Vec<Role> {
+    rbac::roles_of(principal)
+}
+
+#[query]
+#[candid_method(query)]
+fn role_members() -> Vec<(Principal, Vec<Role>)> {
+    rbac::role_members()
+}
+
+#[update]
+#[candid_method(update)]
+fn set_collection_royalty(royalty: Option<Royalty>) -> Result<(), NftError> {
+    royalty::set_collection_royalty(royalty)
+}
+
+#[update]
+#[candid_method(update)]
+fn set_class_royalty(class: String, royalty: Option<Royalty>) -> Result<(), NftError> {
+    royalty::set_class_royalty(class, royalty)
+}
+
+#[query(name = "royalty")]
+#[candid_method(query, rename = "royalty")]
+fn royalty_ext(token: token_identifier::TokenIdentifier) -> RoyaltyResponse {
+    let token_index = match token_identifier::decode_token_id(&token) {
+        Ok(obj) => Nat::from(obj.index.get_value()),
+        Err(e) => return RoyaltyResponse::err(e),
+    };
+    match royalty::royalty_of(&token_index) {
+        Ok(royalty) => RoyaltyResponse::ok(royalty),
+        Err(_) => RoyaltyResponse::err(CommonError::InvalidToken(token)),
+    }
+}
+
+#[query]
+#[candid_method(query)]
+fn royalty_info(
+    token_identifier: Token_ID,
+    sale_price: u64,
+) -> Result<Option<(AccountIdentifier, u64)>, NftError> {
+    royalty::royalty_info(&token_identifier, sale_price)
+}
+
+#[update]
+#[candid_method(update)]
+fn set_class_soulbound(class: String, soulbound: bool) -> Result<(), NftError> {
+    dip721::dip721_set_class_soulbound(class, soulbound)
+}
+
+#[update]
+#[candid_method(update)]
+fn set_token_soulbound(token_identifier: Token_ID, soulbound: bool) -> Result<(), NftError> {
+    dip721::dip721_set_token_soulbound(token_identifier, soulbound)
+}
+
+#[query]
+#[candid_method(query)]
+fn is_transferable(token_identifier: Token_ID) -> Result<bool, NftError> {
+    dip721::dip721_is_transferable(token_identifier)
+}
+
+#[update]
+#[candid_method(update)]
+fn lock_token(token_identifier: Token_ID, reason: String, expires_at: u64) -> Result<(), NftError> {
+    lock::lock(token_identifier, reason, expires_at)
+}
+
+#[update]
+#[candid_method(update)]
+fn unlock_token(token_identifier: Token_ID) -> Result<(), NftError> {
+    lock::unlock(token_identifier)
+}
+
+#[query]
+#[candid_method(query)]
+fn token_lock(token_identifier: Token_ID) -> Option<TokenLock> {
+    lock::lock_of(token_identifier)
+}
+
+#[query]
+#[candid_method(query)]
+fn locked_tokens(owner: Principal) -> Vec<(Token_ID, TokenLock)> {
+    lock::locked_tokens_of(owner)
+}
+
+#[update]
+#[candid_method(update)]
+fn add_recipe(recipe: Recipe) -> Result<(), NftError> {
+    craft::add_recipe(recipe)
+}
+
+#[update]
+#[candid_method(update)]
+fn remove_recipe(id: String) -> Result<(), NftError> {
+    craft::remove_recipe(id)
+}
+
+#[query]
+#[candid_method(query)]
+fn recipes() -> Vec<Recipe> {
+    craft::recipes_info()
+}
+
+#[update(name = "craft")]
+#[candid_method(update, rename = "craft")]
+fn craft_ext(recipe_id: String) -> Result<Vec<Token_ID>, NftError> {
+    craft::craft(recipe_id)
+}
+
+#[update]
+#[candid_method(update)]
+fn set_mystery_box(mystery_box: MysteryBox) -> Result<(), NftError> {
+    mystery_box::set_mystery_box(mystery_box)
+}
+
+#[update]
+#[candid_method(update)]
+fn remove_mystery_box(class: String) -> Result<(), NftError> {
+    mystery_box::remove_mystery_box(class)
+}
+
+#[query]
+#[candid_method(query)]
+fn mystery_boxes() -> Vec<MysteryBox> {
+    mystery_box::boxes_info()
+}
+
+#[update]
+#[candid_method(update)]
+async fn open_box(token_identifier: Token_ID) -> Result<Token_ID, NftError> {
+    mystery_box::open_box(token_identifier).await
+}
+
+#[update]
+#[candid_method(update)]
+async fn configure_reveal(
+    class: Option<String>,
+    placeholder: Vec<(String, GeneralValue)>,
+) -> Result<Vec<u8>, NftError> {
+    reveal::configure(class, placeholder).await
+}
+
+#[update(name = "reveal")]
+#[candid_method(update, rename = "reveal")]
+fn reveal_ext(class: Option<String>) -> Result<Vec<u8>, NftError> {
+    reveal::reveal(class)
+}
+
+#[query]
+#[candid_method(query)]
+fn reveal_status(class: Option<String>) -> Option<RevealStatus> {
+    reveal::status(class)
+}
+
+#[query]
+#[candid_method(query)]
+fn reveal_commitment(token_identifier: Token_ID) -> Result<Option<Vec<u8>>, NftError> {
+    reveal::reveal_commitment(token_identifier)
+}
+
+#[query]
+#[candid_method(query)]
+fn verify_reveal(token_identifier: Token_ID) -> Result<bool, NftError> {
+    reveal::verify(token_identifier)
+}
+
+// Serves token metadata as JSON at `/<token index>` or `/?tokenid=<token identifier>`.
+#[query]
+#[candid_method(query)]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    let path = request.url.split('?').next().unwrap_or_default();
+    let token_index = match request.url.split_once("?tokenid=") {
+        Some((_, token)) => token_identifier::decode_token_id(&token.to_string())
+            .ok()
+            .map(|obj| obj.index.get_value()),
+        None => path.trim_start_matches('/').parse::<u32>().ok(),
+    };
+
+    match token_index.map(get_token_metadata_by_u32) {
+        Some(Ok(body)) => HttpResponse {
+            status_code: 200,
+            headers: vec![("Content-Type".into(), "application/json".into())],
+            body,
+        },
+        _ => HttpResponse {
+            status_code: 404,
+            headers: vec![("Content-Type".into(), "text/plain".into())],
+            body: b"token not found".to_vec(),
+        },
+    }
+}
+
+#[update]
+#[candid_method(update)]
+fn set_user(
+    token_identifier: Token_ID,
+    user: Option<Principal>,
+    expires: u64,
+) -> Result<(), NftError> {
+    dip721::dip721_set_user(token_identifier, user, expires)
+}
+
+#[query]
+#[candid_method(query)]
+fn user_of(token_identifier: Token_ID) -> Result<Option<Principal>, NftError> {
+    dip721::dip721_user_of(token_identifier)
+}
+
+#[query]
+#[candid_method(query)]
+fn user_expires(token_identifier: Token_ID) -> Result<Option<u64>, NftError> {
+    dip721::dip721_user_expires(token_identifier)
+}
+
+#[update]
+#[candid_method(update)]
+fn propose_swap(proposal: SwapProposal) -> Result<u64, NftError> {
+    swap::propose(proposal)
+}
+
+#[update]
+#[candid_method(update)]
+fn accept_swap(id: u64) -> Result<(), NftError> {
+    swap::accept(id)
+}
+
+#[update]
+#[candid_method(update)]
+fn reject_swap(id: u64) -> Result<(), NftError> {
+    swap::reject(id)
+}
+
+#[update]
+#[candid_method(update)]
+fn cancel_swap(id: u64) -> Result<(), NftError> {
+    swap::cancel(id)
+}
+
+#[query]
+#[candid_method(query)]
+fn swap_offer(id: u64) -> Option<SwapOffer> {
+    swap::offer(id)
+}
+
+#[query]
+#[candid_method(query)]
+fn swap_offers_of(principal: Principal) -> Vec<SwapOffer> {
+    swap::offers_of(principal)
+}
+
+#[update]
+#[candid_method(update)]
+fn set_marketplace_fee(fee: Option<Royalty>) -> Result<(), NftError> {
+    marketplace::set_marketplace_fee(fee)
+}
+
+#[update]
+#[candid_method(update)]
+fn list(request: ListRequest) -> ExtResult {
+    match marketplace::list(request) {
+        Ok(()) => ExtResult::ok(()),
+        Err(err) => ExtResult::err(err),
+    }
+}
+
+#[update(name = "lock")]
+#[candid_method(update, rename = "lock")]
+async fn lock_ext(
+    token: token_identifier::TokenIdentifier,
+    price: u64,
+    address: AccountIdentifier,
+    _subaccount: SubAccount,
+) -> LockResponse {
+    match marketplace::lock(token, price, address).await {
+        Ok(payment_address) => LockResponse::ok(payment_address),
+        Err(err) => LockResponse::err(err),
+    }
+}
+
+#[update]
+#[candid_method(update)]
+async fn settle(token: token_identifier::TokenIdentifier) -> ExtResult {
+    match marketplace::settle(token).await {
+        Ok(()) => ExtResult::ok(()),
+        Err(err) => ExtResult::err(err),
+    }
+}
+
+#[query]
+#[candid_method(query)]
+fn listings() -> Vec<(TokenIndex, Listing, TokenMetaDataExt)> {
+    marketplace::listings()
+        .into_iter()
+        .filter_map(|(token_identifier, listing)| {
+            let id = u32::try_from(&token_identifier.0).ok()?;
+            let metadata = TokenMetaDataExt::nonfungible(MetaDataNonFungibleDetails {
+                metadata: get_token_metadata_by_u32(id).ok(),
+            });
+            Some((id, listing, metadata))
+        })
+        .collect()
+}
+
+#[query]
+#[candid_method(query)]
+fn details(token: token_identifier::TokenIdentifier) -> DetailsResponse {
+    match marketplace::details(token) {
+        Ok(details) => DetailsResponse::ok(details),
+        Err(err) => DetailsResponse::err(err),
+    }
+}
+
+#[query]
+#[candid_method(query)]
+fn failed_payouts() -> Vec<Payout> {
+    marketplace::failed_payouts()
+}
+
+#[update]
+#[candid_method(update)]
+async fn retry_failed_payouts() -> Result<u64, NftError> {
+    marketplace::retry_failed_payouts().await
+}
+
+#[update]
+#[candid_method(update)]
+fn start_auction(request: AuctionRequest) -> Result<u64, NftError> {
+    auction::start(request)
+}
+
+#[query]
+#[candid_method(query)]
+fn bid_address(id: u64) -> Result<String, NftError> {
+    auction::bid_address(id)
+}
+
+#[update]
+#[candid_method(update)]
+async fn bid(id: u64, amount: u64) -> Result<(), NftError> {
+    auction::bid(id, amount).await
+}
+
+#[update]
+#[candid_method(update)]
+async fn refund_bid(id: u64) -> Result<(), NftError> {
+    auction::refund_bid(id).await
+}
+
+#[update]
+#[candid_method(update)]
+fn cancel_auction(id: u64) -> Result<(), NftError> {
+    auction::cancel(id)
+}
+
+#[update]
+#[candid_method(update)]
+async fn settle_auction(id: u64) -> Result<(), NftError> {
+    auction::settle(id).await
+}
+
+#[query(name = "auction")]
+#[candid_method(query, rename = "auction")]
+fn auction_info(id: u64) -> Option<Auction> {
+    auction::auction(id)
+}
+
+#[query]
+#[candid_method(query)]
+fn auctions() -> Vec<Auction> {
+    auction::active_auctions()
+}
+
+#[heartbeat]
+fn heartbeat() {
+    auction::settle_ended();
+    job::process_queued();
+    cap::flush_due();
+    icrc3::archive_due();
+}
+
+#[query]
+#[candid_method(query)]
+fn is_paused(operation: Operation) -> bool {
+    dip721::dip721_is_paused(operation)
+}
+
+#[query]
+#[candid_method(query)]
+fn paused_operations() -> Vec<Operation> {
+    dip721::dip721_paused_operations()
 }
 
 #[query]
 #[candid_method(query)]
 fn pending_transactions() -> Vec<IndefiniteEvent> {
-    cap_sdk::pending_transactions()
+    cap::pending()
+}
+
+#[query(name = "diagnostics")]
+#[candid_method(query, rename = "diagnostics")]
+fn diagnostics_log() -> Result<Vec<Diagnostic>, NftError> {
+    diagnostics::diagnostics()
+}
+
+#[update]
+#[candid_method(update)]
+fn clear_diagnostics() -> Result<(), NftError> {
+    diagnostics::clear_diagnostics()
+}
+
+#[query]
+#[candid_method(query)]
+fn cap_metrics() -> CapMetrics {
+    cap::metrics()
+}
+
+#[update]
+#[candid_method(update)]
+async fn flush_cap() -> Result<CapMetrics, NftError> {
+    cap::force_flush().await
+}
+
+#[update]
+#[candid_method(update)]
+fn set_cap_enabled(enabled: bool) -> Result<(), NftError> {
+    cap::set_enabled(enabled)
 }
 
 
@@ -340,16 +878,45 @@ fn pending_transactions() -> Vec<IndefiniteEvent> {
 fn batch_mint(
     mint_request: MintRequest,
     num: Option<u32>,
-) -> Vec<TokenIndex> {
-    let mut tids = vec![];
+) -> Result<Vec<TokenIndex>, NftError> {
+    rbac::ensure_role(Role::Minter)?;
+    dedup::idempotent(
+        &env::caller(),
+        "batch_mint",
+        &(mint_request.clone(), num),
+        mint_request.created_at_time,
+        || batch_mint_internal(mint_request.clone(), num),
+    )?
+}
 
-    if let Some(num) = num {
-        for _i in 0..num {
-            let tid = mint_internal(mint_request.clone());
-            tids.push(tid)
-        }
+fn batch_mint_internal(
+    mint_request: MintRequest,
+    num: Option<u32>,
+) -> Result<Vec<TokenIndex>, NftError> {
+    let num = match num {
+        Some(num) if num > 0 => num,
+        _ => return Err(NftError::InvalidQuantity),
     };
-    tids
+    mint_phase::consume(&mint_request.to, num)?;
+
+    let mut tids = vec![];
+    for _i in 0..num {
+        match mint_within_quota(mint_request.clone()) {
+            Ok(tid) => tids.push(tid),
+            Err(err) => {
+                // Hand back the quota of the tokens that were not minted.
+                mint_phase::release(&mint_request.to, num - tids.len() as u32);
+                return Err(err);
+            }
+        }
+    }
+    Ok(tids)
+}
+
+#[update]
+#[candid_method(update)]
+fn airdrop(entries: Vec<AirdropEntry>) -> Result<u64, NftError> {
+    job::submit_airdrop(entries)
 }
 
 // #[query]
@@ -368,11 +935,23 @@ fn batch_mint(
 
 #[update]
 #[candid_method(update)]
-fn batch_transfer_v1(transfer_request: TransferRequestV1) -> Vec<Nat> {
+fn batch_transfer_v1(transfer_request: TransferRequestV1) -> Result<Vec<Nat>, NftError> {
+    dedup::idempotent(
+        &env::caller(),
+        "batch_transfer_v1",
+        &transfer_request,
+        transfer_request.created_at_time,
+        || batch_transfer_v1_internal(transfer_request.clone()),
+    )?
+}
+
+fn batch_transfer_v1_internal(
+    transfer_request: TransferRequestV1,
+) -> Result<Vec<Nat>, NftError> {
     let class = transfer_request.class.clone();
     let from = transfer_request.from.clone();
     let to = transfer_request.to;
-    let num = transfer_request.num.clone();
+    let num = transfer_request.num as usize;
     let from_pid = match from {
         User::principal(pid) => pid,
         User::address(ref _aid) => Principal::anonymous(),
@@ -381,37 +960,68 @@ fn batch_transfer_v1(transfer_request: TransferRequestV1) -> Vec<Nat> {
         User::principal(pid) => pid,
         User::address(ref _aid) => Principal::anonymous(),
     };
-    let owner_token_id_set = dip721::dip721_owner_token_identifiers(from_pid.clone())
-                                .map(|token_set| {
-                                    token_set.into_iter()
-                                    .filter(|token_id| 
-                                    dip721::find_class_of_token_metadata(token_id.to_owned()) ==GeneralValue::TextContent(class.clone())     
-                                )
-                                .collect::<Vec<_>>()
-                                })
-                                .unwrap();
-    
-    let mut vec_res = Vec::new();
-    for item in 0..num {
-        let res = dip721::dip721_transfer_from(from_pid, to_pid, owner_token_id_set[item].to_owned()).unwrap();
-        vec_res.push(res);
+    let mut owner_token_id_set = dip721::dip721_owner_token_identifiers(from_pid)?
+        .into_iter()
+        .filter(|token_id| {
+            dip721::find_class_of_token_metadata(token_id.to_owned())
+                == Some(GeneralValue::TextContent(class.clone()))
+        })
+        .filter(|token_id| {
+            dip721::dip721_is_transferable(token_id.to_owned()).unwrap_or(false)
+        })
+        .collect::<Vec<_>>();
+    if owner_token_id_set.len() < num {
+        return Err(NftError::InsufficientInputs(class));
     }
-    vec_res
+    owner_token_id_set.sort();
+    owner_token_id_set.truncate(num);
+
+    transfer_all(from_pid, to_pid, owner_token_id_set)
 }
 
-fn valid_token_id(owner_token_set: &HashSet<Nat>, list_id: &Nat) {
-    if !owner_token_set.contains(list_id) {
-        panic!("the token id not in owner's token set");
-    }
+// Validates every transfer before applying any, so a rejected token leaves the
+// whole batch untouched.
+fn transfer_all(
+    from: Principal,
+    to: Principal,
+    token_list: Vec<Token_ID>,
+) -> Result<Vec<Nat>, NftError> {
+    let caller = env::caller();
+    let mut seen = HashSet::new();
+    ledger::with(|ledger| {
+        token_list.iter().try_for_each(|token_id| {
+            if !seen.insert(token_id) {
+                return Err(NftError::DuplicateToken(token_id.clone()));
+            }
+            dip721::check_transfer_from(ledger, caller, from, to, token_id)
+        })
+    })?;
+
+    token_list
+        .into_iter()
+        .map(|token_id| dip721::dip721_transfer_from(from, to, token_id))
+        .collect()
 }
 
 #[update]
 #[candid_method(update)]
-fn batch_transfer_v2(transfer_request: TransferRequestV2) -> Vec<Nat> {
+fn batch_transfer_v2(transfer_request: TransferRequestV2) -> Result<Vec<Nat>, NftError> {
+    dedup::idempotent(
+        &env::caller(),
+        "batch_transfer_v2",
+        &transfer_request,
+        transfer_request.created_at_time,
+        || batch_transfer_v2_internal(transfer_request.clone()),
+    )?
+}
+
+fn batch_transfer_v2_internal(
+    transfer_request: TransferRequestV2,
+) -> Result<Vec<Nat>, NftError> {
     let token_list = transfer_request.token_list;
     let from = transfer_request.from;
     let to = transfer_request.to;
-   
+
     let from_pid = match from {
         User::principal(pid) => pid,
         User::address(ref _aid) => Principal::anonymous(),
@@ -421,75 +1031,344 @@ fn batch_transfer_v2(transfer_request: TransferRequestV2) -> Vec<Nat> {
         User::address(ref _aid) => Principal::anonymous(),
     };
 
-    let hash_set = dip721::dip721_owner_token_identifiers(from_pid.clone()).unwrap();
-    token_list.iter().for_each(|id| valid_token_id(&hash_set, id));
+    transfer_all(from_pid, to_pid, token_list)
+}
 
-    let mut vec_res = Vec::new();
-    for item in token_list.iter() {
-        let res = dip721::dip721_transfer_from(from_pid, to_pid, item.to_owned()).unwrap();
-        vec_res.push(res);
-    }
-    vec_res
+#[update]
+#[candid_method(update)]
+fn submit_batch_mint(mint_request: MintRequest, num: u32) -> Result<u64, NftError> {
+    dedup::idempotent(
+        &env::caller(),
+        "submit_batch_mint",
+        &(mint_request.clone(), num),
+        mint_request.created_at_time,
+        || job::submit_batch_mint(mint_request.clone(), num),
+    )?
+}
 
+#[update]
+#[candid_method(update)]
+fn submit_batch_transfer_v1(transfer_request: TransferRequestV1) -> Result<u64, NftError> {
+    dedup::idempotent(
+        &env::caller(),
+        "submit_batch_transfer_v1",
+        &transfer_request,
+        transfer_request.created_at_time,
+        || job::submit_batch_transfer_v1(transfer_request.clone()),
+    )?
+}
+
+#[update]
+#[candid_method(update)]
+fn submit_batch_transfer_v2(transfer_request: TransferRequestV2) -> Result<u64, NftError> {
+    dedup::idempotent(
+        &env::caller(),
+        "submit_batch_transfer_v2",
+        &transfer_request,
+        transfer_request.created_at_time,
+        || job::submit_batch_transfer_v2(transfer_request.clone()),
+    )?
+}
+
+#[query(name = "job")]
+#[candid_method(query, rename = "job")]
+fn job_progress(id: u64) -> Option<JobProgress> {
+    job::job_progress(id)
+}
+
+#[query]
+#[candid_method(query)]
+fn job_outcomes(id: u64, offset: u64, limit: u64) -> Vec<JobOutcome> {
+    job::job_outcomes(id, offset, limit)
+}
+
+#[query]
+#[candid_method(query)]
+fn icrc7_collection_metadata() -> Vec<(String, Value)> {
+    icrc7::collection_metadata()
+}
+
+#[query]
+#[candid_method(query)]
+fn icrc7_supported_standards() -> Vec<SupportedStandard> {
+    icrc7::supported_standards()
+}
+
+#[query]
+#[candid_method(query)]
+fn icrc7_token_metadata(token_ids: Vec<Nat>) -> Vec<Option<Vec<(String, Value)>>> {
+    icrc7::token_metadata(token_ids)
+}
+
+#[query]
+#[candid_method(query)]
+fn icrc7_owner_of(token_ids: Vec<Nat>) -> Vec<Option<Account>> {
+    icrc7::owner_of(token_ids)
+}
+
+#[query]
+#[candid_method(query)]
+fn icrc7_balance_of(accounts: Vec<Account>) -> Vec<Nat> {
+    icrc7::balance_of(accounts)
+}
+
+#[query]
+#[candid_method(query)]
+fn icrc7_tokens(prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    icrc7::tokens(prev, take)
+}
+
+#[query]
+#[candid_method(query)]
+fn icrc7_tokens_of(account: Account, prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    icrc7::tokens_of(account, prev, take)
+}
+
+#[update]
+#[candid_method(update)]
+fn icrc7_transfer(args: Vec<Icrc7TransferArg>) -> Vec<Option<Icrc7TransferResult>> {
+    icrc7::transfer(args)
+}
+
+#[update]
+#[candid_method(update)]
+fn icrc37_approve_tokens(args: Vec<ApproveTokenArg>) -> Vec<Option<ApproveTokenResult>> {
+    icrc37::approve_tokens(args)
+}
+
+#[update]
+#[candid_method(update)]
+fn icrc37_approve_collection(
+    args: Vec<ApproveCollectionArg>,
+) -> Vec<Option<ApproveCollectionResult>> {
+    icrc37::approve_collection(args)
+}
+
+#[update]
+#[candid_method(update)]
+fn icrc37_revoke_token_approvals(
+    args: Vec<RevokeTokenApprovalArg>,
+) -> Vec<Option<RevokeTokenApprovalResult>> {
+    icrc37::revoke_token_approvals(args)
+}
+
+#[update]
+#[candid_method(update)]
+fn icrc37_revoke_collection_approvals(
+    args: Vec<RevokeCollectionApprovalArg>,
+) -> Vec<Option<RevokeCollectionApprovalResult>> {
+    icrc37::revoke_collection_approvals(args)
+}
+
+#[query]
+#[candid_method(query)]
+fn icrc37_is_approved(args: Vec<IsApprovedArg>) -> Vec<bool> {
+    icrc37::is_approved(args)
+}
+
+#[query]
+#[candid_method(query)]
+fn icrc37_get_token_approvals(
+    token_id: Nat,
+    prev: Option<TokenApproval>,
+    take: Option<Nat>,
+) -> Vec<TokenApproval> {
+    icrc37::get_token_approvals(token_id, prev, take)
+}
+
+#[query]
+#[candid_method(query)]
+fn icrc37_get_collection_approvals(
+    owner: Account,
+    prev: Option<CollectionApproval>,
+    take: Option<Nat>,
+) -> Vec<CollectionApproval> {
+    icrc37::get_collection_approvals(owner, prev, take)
+}
+
+#[update]
+#[candid_method(update)]
+fn icrc37_transfer_from(args: Vec<TransferFromArg>) -> Vec<Option<TransferFromResult>> {
+    icrc37::transfer_from(args)
+}
+
+#[query]
+#[candid_method(query)]
+fn icrc3_get_blocks(args: Vec<GetBlocksArgs>) -> GetBlocksResult {
+    icrc3::get_blocks(args)
+}
+
+#[query]
+#[candid_method(query)]
+fn icrc3_get_tip_certificate() -> Option<DataCertificate> {
+    icrc3::get_tip_certificate()
+}
+
+#[query]
+#[candid_method(query)]
+fn icrc3_supported_block_types() -> Vec<SupportedBlockType> {
+    icrc3::supported_block_types()
+}
+
+#[query]
+#[candid_method(query)]
+fn icrc3_get_archives(args: GetArchivesArgs) -> Vec<ArchiveInfo> {
+    icrc3::get_archives(args)
+}
+
+#[update]
+#[candid_method(update)]
+fn set_block_archive(canister: Principal) -> Result<(), NftError> {
+    icrc3::set_archive(canister)
+}
+
+#[query]
+#[candid_method(query)]
+fn certified_token(token_id: Nat) -> Result<CertifiedToken, NftError> {
+    certification::certified_token(token_id)
+}
+
+#[query]
+#[candid_method(query)]
+fn certified_tokens_of(owner: Principal) -> Result<CertifiedTokens, NftError> {
+    certification::certified_tokens_of(owner)
 }
 
 #[pre_upgrade]
 fn pre_upgrade() {
-    
-    ledger::with(|ledger| {
-        if let Err(err) = ic_cdk::storage::stable_save::<(
-            &ledger::Ledger,
-            cap_sdk::Archive,
-            u32,
-            Vec<PropMetadata>,
-        )>((
-            ledger,
-            cap_sdk::archive(),
-            dip721::tid_info(),
-            prop::prop_info(),
-        )) {
-            trap(&format!(
-                "An error occurred when saving to stable memory (pre_upgrade): {:?}",
-                err
-            ));
-        };
-    }) 
+    let state = upgrade::snapshot();
+    if let Err(err) = ic_cdk::storage::stable_save((
+        upgrade::STATE_VERSION,
+        state,
+        cap_sdk::archive(),
+    )) {
+        trap(&format!(
+            "An error occurred when saving to stable memory (pre_upgrade): {:?}",
+            err
+        ));
+    };
 }
 
 #[post_upgrade]
 fn post_upgrade() {
-    ledger::with_mut(|ledger| {
-        match ic_cdk::storage::stable_restore::<(
-            ledger::Ledger,
-            cap_sdk::Archive,
-            u32,
-            Vec<PropMetadata>,
-        )>() {
-            Ok((ledger_store, cap_store, tid, prop)) => {
-                *ledger = ledger_store;
-                ledger.metadata_mut().upgraded_at = time();
-                cap_sdk::from_archive(cap_store);
-                dip721::restore_tid_info(tid);
-                prop::restore_prop_info(prop);
-            }
-            Err(err) => {
-                trap(&format!(
-                    "An error occurred when loading from stable memory (post_upgrade): {:?}",
-                    err
-                ));
-            }
+    match upgrade::decode::<cap_sdk::Archive>(&ic_cdk::api::stable::stable_bytes()) {
+        Ok((state, cap_store)) => {
+            cap_sdk::from_archive(cap_store);
+            upgrade::restore(state);
         }
-    })
+        Err(err) => {
+            trap(&format!(
+                "An error occurred when loading from stable memory (post_upgrade): {:?}",
+                err
+            ));
+        }
+    }
 }
 
-#[query(name = "__get_candid_interface_tmp_hack")]
 fn export_candid() -> String {
     ic_cdk::export::candid::export_service!();
     __export_service()
 }
+
+#[query(name = "__get_candid_interface")]
+fn get_candid_interface() -> String {
+    export_candid()
+}
+
+// Kept for dfx versions that only look up the interface under this name.
+#[query(name = "__get_candid_interface_tmp_hack")]
+fn get_candid_interface_tmp_hack() -> String {
+    export_candid()
+}
+
+// `cargo run -p ext_based_721_backend > src/ext_based_721_backend/ext_based_721_backend.did`
+// regenerates the checked-in interface.
 #[cfg(not(any(target_arch = "wasm32", test)))]
 fn main() {
     std::print!("{}", export_candid());
 }
 #[cfg(any(target_arch = "wasm32", test))]
 fn main() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::testing::{self, custodian, principal};
+
+    #[test]
+    fn checked_in_candid_interface_is_up_to_date() {
+        let checked_in = include_str!("../ext_based_721_backend.did");
+        assert!(
+            checked_in.trim_end() == export_candid().trim_end(),
+            "ext_based_721_backend.did is out of date; regenerate it with \
+             `cargo run -p ext_based_721_backend > src/ext_based_721_backend/ext_based_721_backend.did`"
+        );
+    }
+
+    fn mint_request(to: Principal) -> MintRequest {
+        MintRequest {
+            to: User::principal(to),
+            metadata: None,
+            class: "H".into(),
+            created_at_time: None,
+        }
+    }
+
+    #[test]
+    fn rejected_mints_do_not_use_up_token_indexes() {
+        testing::setup();
+        let next = dip721::tid_info();
+        ledger::with_mut(|ledger| ledger.set_paused(Operation::Mint, true));
+        assert!(mint_within_quota(mint_request(principal(1))).is_err());
+        assert_eq!(dip721::tid_info(), next);
+
+        ledger::with_mut(|ledger| ledger.set_paused(Operation::Mint, false));
+        assert_eq!(mint_within_quota(mint_request(principal(1))).unwrap(), next);
+        assert_eq!(testing::owner_of(&Nat::from(next)), Some(principal(1)));
+        assert_eq!(dip721::tid_info(), next + 1);
+    }
+
+    #[test]
+    fn certified_queries_cover_what_the_ext_queries_report() {
+        testing::setup();
+        env::set_data_certificate(Some(vec![7; 8]));
+        let first = testing::mint(principal(1));
+        testing::mint(custodian());
+        let second = testing::mint(principal(1));
+
+        let listed = match tokens_ext(principal(1)) {
+            NFTResult::ok(details) => details
+                .into_iter()
+                .map(|ResultDetail(index, _, _)| Nat::from(index))
+                .collect::<Vec<_>>(),
+            _ => panic!("tokens_ext failed"),
+        };
+        let certified = certification::certified_tokens_of(principal(1)).unwrap();
+        assert_eq!(listed, vec![first.clone(), second]);
+        assert_eq!(certified.token_identifiers, listed);
+
+        let certified = certification::certified_token(first.clone()).unwrap();
+        let shown = ledger::with(|ledger| {
+            reveal::visible_properties(ledger.token_metadata(&first).unwrap())
+        });
+        assert!(metadata(prop::tokens(&first)).is_some());
+        assert!(certified.properties == shown);
+    }
+
+    #[test]
+    fn get_tokens_lists_tokens_after_a_gap() {
+        testing::setup();
+        let first = testing::mint(principal(1));
+        let far = Nat::from(1_000u32);
+        ledger::with_mut(|ledger| {
+            dip721::mint_token(ledger, custodian(), principal(1), &far, prop::properties_of("H"))
+        })
+        .unwrap();
+
+        let indexes = getTokens()
+            .into_iter()
+            .map(|(index, _)| Nat::from(index))
+            .collect::<Vec<_>>();
+        assert_eq!(indexes, vec![first, far]);
+    }
+}
@@ -0,0 +1,683 @@
+use crate::module::cap::insert_sync;
+use crate::module::dip721;
+use crate::module::env::{self, time};
+use crate::module::icp_ledger;
+use crate::module::ledger::{self, Ledger};
+use crate::module::marketplace;
+use crate::module::types::{
+    Auction, AuctionKind, AuctionRequest, AuctionStatus, Bid, NftError, Payout, Sale, TokenLock,
+};
+use cap_sdk::{DetailValue, IndefiniteEvent};
+use ic_cdk::export::Principal;
+
+// Escrowed tokens stay locked this long after the end so settlement can finish.
+const SETTLEMENT_GRACE: u64 = 24 * 60 * 60 * 1_000_000_000;
+const REFUND_MEMO: u64 = 0x5245_464e;
+// A failed settlement is retried after a delay that doubles every attempt, and
+// the auction is cancelled with the winning bid refunded after the last one.
+const SETTLE_BACKOFF: u64 = 60 * 1_000_000_000;
+const MAX_SETTLE_ATTEMPTS: u32 = 8;
+// A settlement still marked as running after this long is assumed to have
+// trapped in a callback and may be started again.
+const SETTLE_TIMEOUT: u64 = 10 * 60 * 1_000_000_000;
+
+fn escrow_reason(id: u64) -> String {
+    format!("auction {}", id)
+}
+
+// Each bidder pays into their own subaccount per auction.
+fn bid_subaccount(id: u64, bidder: &Principal) -> Vec<u8> {
+    icp_ledger::derive_subaccount(b"bid", &[&id.to_be_bytes()[..], bidder.as_slice()])
+        .0
+        .to_vec()
+}
+
+impl Auction {
+    // The price a bid has to reach right now.
+    pub fn minimum_bid(&self, now: u64) -> u64 {
+        match &self.kind {
+            AuctionKind::English { min_bid, increment } => match &self.highest_bid {
+                Some(bid) => bid.amount + increment,
+                None => *min_bid,
+            },
+            AuctionKind::Dutch {
+                start_price,
+                end_price,
+            } => {
+                if now >= self.end_at {
+                    return *end_price;
+                }
+                let elapsed = now.saturating_sub(self.start_at) as u128;
+                let duration = (self.end_at - self.start_at) as u128;
+                let decay = (start_price - end_price) as u128 * elapsed / duration;
+                start_price - decay as u64
+            }
+        }
+    }
+}
+
+pub fn start(request: AuctionRequest) -> Result<u64, NftError> {
+    ledger::with_mut(|ledger| {
+        let caller = env::caller();
+        let now = time();
+        if request.end_at <= now {
+            return Err(NftError::InvalidExpiry);
+        }
+        match &request.kind {
+            AuctionKind::English { min_bid, .. } if *min_bid == 0 => {
+                return Err(NftError::InvalidAuction)
+            }
+            AuctionKind::Dutch {
+                start_price,
+                end_price,
+            } if start_price < end_price || *end_price == 0 => {
+                return Err(NftError::InvalidAuction)
+            }
+            _ => {}
+        }
+        let token = ledger.token_metadata(&request.token)?;
+        let seller = token.owner.ok_or(NftError::OwnerNotFound)?;
+        if seller != caller && !ledger.is_custodian(&caller) {
+            return Err(NftError::UnauthorizedOwner);
+        }
+        if dip721::is_soulbound(token) {
+            return Err(NftError::NonTransferable);
+        }
+        if ledger.is_locked(&request.token) {
+            return Err(NftError::TokenLocked);
+        }
+
+        let id = ledger.auction_count;
+        ledger.auction_count += 1;
+        ledger.listings.remove(&request.token);
+        ledger.escrow(
+            request.token.clone(),
+            TokenLock {
+                owner: seller,
+                locked_by: env::id(),
+                reason: escrow_reason(id),
+                locked_at: now,
+                expires_at: request.end_at + SETTLEMENT_GRACE,
+            },
+        );
+        ledger.auctions.insert(
+            id,
+            Auction {
+                id,
+                token: request.token.clone(),
+                seller,
+                kind: request.kind.clone(),
+                start_at: now,
+                end_at: request.end_at,
+                highest_bid: None,
+                status: AuctionStatus::Open,
+                settle_attempts: 0,
+                next_settle_at: 0,
+            },
+        );
+
+        insert_sync(IndefiniteEvent {
+            caller,
+            operation: "startAuction".into(),
+            details: vec![
+                ("auction".into(), DetailValue::U64(id)),
+                (
+                    "token_identifier".into(),
+                    DetailValue::from(request.token.to_string()),
+                ),
+                ("end_at".into(), DetailValue::U64(request.end_at)),
+            ],
+        });
+
+        Ok(id)
+    })
+}
+
+pub fn bid_address(id: u64) -> Result<String, NftError> {
+    ledger::with(|ledger| ledger.auctions.get(&id).map(|_| ()))
+        .ok_or(NftError::AuctionNotFound)?;
+    let subaccount = bid_subaccount(id, &env::caller());
+    Ok(icp_ledger::canister_account(&marketplace::to_subaccount(
+        &subaccount,
+    )))
+}
+
+fn open_auction(ledger: &Ledger, id: u64) -> Result<Auction, NftError> {
+    let auction = ledger.auctions.get(&id).cloned().ok_or(NftError::AuctionNotFound)?;
+    if auction.status != AuctionStatus::Open || auction.end_at <= time() {
+        return Err(NftError::AuctionEnded);
+    }
+    Ok(auction)
+}
+
+// Places a bid backed by what the caller has sent to their `bid_address`. In
+// an English auction the previous leader is refunded; in a Dutch auction the
+// first bid at the current price wins and settles immediately.
+pub async fn bid(id: u64, amount: u64) -> Result<(), NftError> {
+    let caller = env::caller();
+    let auction = ledger::with(|ledger| open_auction(ledger, id))?;
+    if auction.seller == caller {
+        return Err(NftError::InvalidAuction);
+    }
+    if amount < auction.minimum_bid(time()) {
+        return Err(NftError::BidTooLow);
+    }
+
+    let subaccount = bid_subaccount(id, &caller);
+    let balance = icp_ledger::balance_of(&marketplace::to_subaccount(&subaccount))
+        .await
+        .map_err(NftError::LedgerError)?;
+    if balance < amount {
+        return Err(NftError::InsufficientFunds);
+    }
+
+    let (outbid, is_dutch) = ledger::with_mut(|ledger| {
+        let auction = open_auction(ledger, id)?;
+        let now = time();
+        if amount < auction.minimum_bid(now) {
+            return Err(NftError::BidTooLow);
+        }
+        let is_dutch = matches!(auction.kind, AuctionKind::Dutch { .. });
+        let entry = ledger.auctions.get_mut(&id).expect("auction disappeared");
+        let outbid = entry.highest_bid.replace(Bid {
+            bidder: caller,
+            amount,
+            placed_at: now,
+        });
+        if is_dutch {
+            // The first bid at the current price closes a Dutch auction.
+            entry.end_at = now;
+        }
+
+        insert_sync(IndefiniteEvent {
+            caller,
+            operation: "bid".into(),
+            details: vec![
+                ("auction".into(), DetailValue::U64(id)),
+                ("amount".into(), DetailValue::U64(amount)),
+            ],
+        });
+
+        Ok((outbid, is_dutch))
+    })?;
+
+    if let Some(outbid) = outbid.filter(|outbid| outbid.bidder != caller) {
+        refund(id, &outbid.bidder).await;
+    }
+    if is_dutch {
+        complete(id).await;
+    }
+
+    Ok(())
+}
+
+// Whether `bidder`'s funds still back the auction's winning bid.
+fn is_leading(auction: &Auction, bidder: &Principal) -> bool {
+    auction.status != AuctionStatus::Unsold
+        && auction.status != AuctionStatus::Cancelled
+        && auction.highest_bid.as_ref().map(|bid| &bid.bidder) == Some(bidder)
+}
+
+// Returns whatever is left in a bidder's subaccount to their default account,
+// unless the bidder has taken the lead again while the balance was read.
+async fn refund(id: u64, bidder: &Principal) {
+    let subaccount = bid_subaccount(id, bidder);
+    let balance = match icp_ledger::balance_of(&marketplace::to_subaccount(&subaccount)).await {
+        Ok(balance) => balance,
+        Err(_) => return,
+    };
+    let leading = ledger::with(|ledger| {
+        ledger
+            .auctions
+            .get(&id)
+            .map_or(false, |auction| is_leading(auction, bidder))
+    });
+    if leading {
+        return;
+    }
+    if balance > icp_ledger::fee() {
+        marketplace::payout(Payout {
+            subaccount,
+            to: icp_ledger::default_account(bidder),
+            amount: balance,
+            memo: REFUND_MEMO,
+            created_at_time: time(),
+            error: String::new(),
+        })
+        .await;
+    }
+}
+
+// Lets a bidder who is not (or no longer) leading recover their funds.
+pub async fn refund_bid(id: u64) -> Result<(), NftError> {
+    let caller = env::caller();
+    let leading = ledger::with(|ledger| {
+        ledger
+            .auctions
+            .get(&id)
+            .map(|auction| is_leading(auction, &caller))
+            .ok_or(NftError::AuctionNotFound)
+    })?;
+    if leading {
+        return Err(NftError::InvalidAuction);
+    }
+    refund(id, &caller).await;
+    Ok(())
+}
+
+pub fn cancel(id: u64) -> Result<(), NftError> {
+    ledger::with_mut(|ledger| {
+        let caller = env::caller();
+        let auction = open_auction(ledger, id)?;
+        if auction.seller != caller && !ledger.is_custodian(&caller) {
+            return Err(NftError::UnauthorizedOwner);
+        }
+        if auction.highest_bid.is_some() {
+            return Err(NftError::InvalidAuction);
+        }
+        release(ledger, &auction);
+        ledger.auctions.get_mut(&id).expect("auction disappeared").status = AuctionStatus::Cancelled;
+
+        insert_sync(IndefiniteEvent {
+            caller,
+            operation: "cancelAuction".into(),
+            details: vec![("auction".into(), DetailValue::U64(id))],
+        });
+
+        Ok(())
+    })
+}
+
+// Removes the auction's escrow, leaving any other hold in place.
+fn release(ledger: &mut Ledger, auction: &Auction) -> Option<TokenLock> {
+    ledger.release_escrow(&auction.token, &escrow_reason(auction.id))
+}
+
+// Whether `complete` may start on the auction now.
+fn is_settleable(auction: &Auction, now: u64) -> bool {
+    match auction.status {
+        AuctionStatus::Open => true,
+        AuctionStatus::Settling => auction.next_settle_at <= now,
+        _ => false,
+    }
+}
+
+// The token can only be handed over if it is still the seller's and still
+// escrowed for this auction; otherwise the auction is void.
+fn is_intact(ledger: &Ledger, auction: &Auction) -> bool {
+    ledger.owner_of(&auction.token).ok().flatten() == Some(auction.seller)
+        && ledger
+            .active_escrow(&auction.token)
+            .map_or(false, |escrow| escrow.reason == escrow_reason(auction.id))
+}
+
+fn set_status(ledger: &mut Ledger, id: u64, status: AuctionStatus) {
+    ledger.auctions.get_mut(&id).expect("auction disappeared").status = status;
+}
+
+// Puts the auction back for a later heartbeat, or cancels it once it has
+// failed too often. Returns whether it was cancelled.
+fn retry_later(ledger: &mut Ledger, auction: &Auction) -> bool {
+    let entry = ledger.auctions.get_mut(&auction.id).expect("auction disappeared");
+    entry.settle_attempts += 1;
+    if entry.settle_attempts >= MAX_SETTLE_ATTEMPTS {
+        entry.status = AuctionStatus::Cancelled;
+        release(ledger, auction);
+        return true;
+    }
+    entry.status = AuctionStatus::Open;
+    entry.next_settle_at = time() + (SETTLE_BACKOFF << (entry.settle_attempts - 1));
+    false
+}
+
+enum Settlement {
+    Sold,
+    Retry,
+    Void,
+}
+
+// Hands the token to the winner and pays the seller from the winning bid, or
+// releases the token when nobody bid. If the token is no longer the seller's
+// or no longer escrowed, or settlement keeps failing, the auction is
+// cancelled and the winning bid refunded.
+async fn complete(id: u64) {
+    let now = time();
+    let auction = ledger::with_mut(|ledger| {
+        let auction = ledger.auctions.get_mut(&id)?;
+        if !is_settleable(auction, now) {
+            return None;
+        }
+        auction.status = AuctionStatus::Settling;
+        auction.next_settle_at = now + SETTLE_TIMEOUT;
+        Some(auction.clone())
+    });
+    let auction = match auction {
+        Some(auction) => auction,
+        None => return,
+    };
+    let winner = match auction.highest_bid.clone() {
+        Some(winner) => winner,
+        None => {
+            ledger::with_mut(|ledger| {
+                release(ledger, &auction);
+                set_status(ledger, id, AuctionStatus::Unsold);
+            });
+            return;
+        }
+    };
+
+    let subaccount = bid_subaccount(id, &winner.bidder);
+    let balance = icp_ledger::balance_of(&marketplace::to_subaccount(&subaccount))
+        .await
+        .unwrap_or(0);
+
+    let settlement = ledger::with_mut(|ledger| {
+        if !is_intact(ledger, &auction) {
+            release(ledger, &auction);
+            set_status(ledger, id, AuctionStatus::Cancelled);
+            return Settlement::Void;
+        }
+        if balance < winner.amount {
+            // The escrowed bid could not be verified; try again later.
+            return if retry_later(ledger, &auction) {
+                Settlement::Void
+            } else {
+                Settlement::Retry
+            };
+        }
+
+        let escrow = release(ledger, &auction);
+        let canister = env::id();
+        if dip721::transfer_token(ledger, canister, &auction.token, winner.bidder).is_err() {
+            // e.g. transfers are paused; keep the token escrowed and retry later.
+            if let Some(escrow) = escrow {
+                ledger.escrow(auction.token.clone(), escrow);
+            }
+            return if retry_later(ledger, &auction) {
+                Settlement::Void
+            } else {
+                Settlement::Retry
+            };
+        }
+        set_status(ledger, id, AuctionStatus::Sold);
+
+        insert_sync(IndefiniteEvent {
+            caller: env::id(),
+            operation: "auctionSettled".into(),
+            details: vec![
+                ("auction".into(), DetailValue::U64(id)),
+                (
+                    "token_identifier".into(),
+                    DetailValue::from(auction.token.to_string()),
+                ),
+                ("seller".into(), DetailValue::from(auction.seller)),
+                ("buyer".into(), DetailValue::from(winner.bidder)),
+                ("price".into(), DetailValue::U64(winner.amount)),
+            ],
+        });
+        ledger.inc_tx();
+        Settlement::Sold
+    });
+
+    match settlement {
+        Settlement::Sold => {
+            let sale = Sale {
+                buyer: winner.bidder,
+                seller: auction.seller,
+                price: winner.amount,
+                subaccount,
+                expires_at: auction.end_at,
+            };
+            marketplace::disburse(&auction.token, &sale, balance).await;
+        }
+        Settlement::Void => {
+            insert_sync(IndefiniteEvent {
+                caller: env::id(),
+                operation: "cancelAuction".into(),
+                details: vec![("auction".into(), DetailValue::U64(id))],
+            });
+            refund(id, &winner.bidder).await;
+        }
+        Settlement::Retry => {}
+    }
+}
+
+pub async fn settle(id: u64) -> Result<(), NftError> {
+    let auction = ledger::with(|ledger| ledger.auctions.get(&id).cloned())
+        .ok_or(NftError::AuctionNotFound)?;
+    let now = time();
+    if !is_settleable(&auction, now) {
+        return Err(NftError::AuctionEnded);
+    }
+    if auction.end_at > now {
+        return Err(NftError::AuctionNotEnded);
+    }
+    complete(id).await;
+    Ok(())
+}
+
+// Ended auctions the heartbeat should settle now, leaving out those waiting
+// out the backoff after a failed attempt.
+fn due_for_settlement(ledger: &Ledger, now: u64) -> Vec<u64> {
+    ledger
+        .auctions
+        .values()
+        .filter(|auction| {
+            is_settleable(auction, now) && auction.end_at <= now && auction.next_settle_at <= now
+        })
+        .map(|auction| auction.id)
+        .collect()
+}
+
+// Called from the heartbeat.
+pub fn settle_ended() {
+    let now = time();
+    for id in ledger::with(|ledger| due_for_settlement(ledger, now)) {
+        ic_cdk::spawn(complete(id));
+    }
+}
+
+pub fn auction(id: u64) -> Option<Auction> {
+    ledger::with(|ledger| ledger.auctions.get(&id).cloned())
+}
+
+pub fn active_auctions() -> Vec<Auction> {
+    ledger::with(|ledger| {
+        ledger
+            .auctions
+            .values()
+            .filter(|auction| auction.status == AuctionStatus::Open)
+            .cloned()
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::icp_ledger::stand_in;
+    use crate::module::testing::{self, owner_of, principal};
+    use crate::module::types::{Operation, Token_ID};
+
+    const HOUR: u64 = 60 * 60 * 1_000_000_000;
+    const MIN_BID: u64 = 100_000_000;
+    const INCREMENT: u64 = 10_000_000;
+
+    fn english(seller: Principal) -> (u64, Token_ID) {
+        let token = testing::mint(seller);
+        env::set_caller(seller);
+        let id = start(AuctionRequest {
+            token: token.clone(),
+            kind: AuctionKind::English {
+                min_bid: MIN_BID,
+                increment: INCREMENT,
+            },
+            end_at: time() + HOUR,
+        })
+        .unwrap();
+        (id, token)
+    }
+
+    fn place_bid(id: u64, bidder: Principal, amount: u64) -> Result<(), NftError> {
+        env::set_caller(bidder);
+        stand_in::deposit(&bid_address(id).unwrap(), amount);
+        env::block_on(bid(id, amount))
+    }
+
+    fn status(id: u64) -> AuctionStatus {
+        auction(id).unwrap().status
+    }
+
+    fn account(principal: Principal) -> String {
+        icp_ledger::default_account(&principal)
+    }
+
+    #[test]
+    fn english_auctions_go_to_the_highest_bidder() {
+        testing::setup();
+        let (seller, first, second) = (principal(1), principal(2), principal(3));
+        let (id, token) = english(seller);
+
+        place_bid(id, first, MIN_BID).unwrap();
+        place_bid(id, second, MIN_BID + INCREMENT).unwrap();
+        // The outbid bidder gets their money back straight away.
+        assert_eq!(stand_in::balance(&account(first)), MIN_BID - icp_ledger::fee());
+
+        assert!(matches!(
+            env::block_on(settle(id)),
+            Err(NftError::AuctionNotEnded)
+        ));
+        env::advance_time(HOUR);
+        env::block_on(settle(id)).unwrap();
+
+        assert_eq!(status(id), AuctionStatus::Sold);
+        assert_eq!(owner_of(&token), Some(second));
+        assert_eq!(
+            stand_in::balance(&account(seller)),
+            MIN_BID + INCREMENT - icp_ledger::fee()
+        );
+    }
+
+    #[test]
+    fn a_late_refund_leaves_a_bidder_who_leads_again_alone() {
+        testing::setup();
+        let (seller, first, second) = (principal(1), principal(2), principal(3));
+        let (id, token) = english(seller);
+
+        place_bid(id, first, MIN_BID).unwrap();
+        place_bid(id, second, MIN_BID + INCREMENT).unwrap();
+        let rebid = MIN_BID + 2 * INCREMENT;
+        place_bid(id, first, rebid).unwrap();
+        // The refund owed for being outbid only lands after the re-bid.
+        env::block_on(refund(id, &first));
+
+        env::set_caller(first);
+        let address = bid_address(id).unwrap();
+        assert_eq!(stand_in::balance(&address), rebid);
+        assert_eq!(stand_in::balance(&account(first)), MIN_BID - icp_ledger::fee());
+
+        env::advance_time(HOUR);
+        env::block_on(settle(id)).unwrap();
+        assert_eq!(owner_of(&token), Some(first));
+        assert_eq!(stand_in::balance(&account(seller)), rebid - icp_ledger::fee());
+    }
+
+    #[test]
+    fn bids_below_the_minimum_are_rejected() {
+        testing::setup();
+        let (id, _) = english(principal(1));
+
+        assert!(matches!(
+            place_bid(id, principal(2), MIN_BID - 1),
+            Err(NftError::BidTooLow)
+        ));
+        place_bid(id, principal(2), MIN_BID).unwrap();
+        assert!(matches!(
+            place_bid(id, principal(3), MIN_BID + INCREMENT - 1),
+            Err(NftError::BidTooLow)
+        ));
+    }
+
+    #[test]
+    fn dutch_prices_fall_linearly() {
+        let auction = Auction {
+            id: 0,
+            token: Token_ID::from(1u32),
+            seller: principal(1),
+            kind: AuctionKind::Dutch {
+                start_price: 1_000,
+                end_price: 200,
+            },
+            start_at: 0,
+            end_at: 100,
+            highest_bid: None,
+            status: AuctionStatus::Open,
+            settle_attempts: 0,
+            next_settle_at: 0,
+        };
+
+        assert_eq!(auction.minimum_bid(0), 1_000);
+        assert_eq!(auction.minimum_bid(50), 600);
+        assert_eq!(auction.minimum_bid(100), 200);
+        assert_eq!(auction.minimum_bid(1_000), 200);
+    }
+
+    #[test]
+    fn auctions_without_bids_release_the_token() {
+        testing::setup();
+        let (id, token) = english(principal(1));
+
+        env::advance_time(HOUR);
+        env::block_on(settle(id)).unwrap();
+
+        assert_eq!(status(id), AuctionStatus::Unsold);
+        assert_eq!(owner_of(&token), Some(principal(1)));
+        assert!(!ledger::with(|ledger| ledger.is_locked(&token)));
+    }
+
+    #[test]
+    fn failed_settlements_back_off_and_eventually_refund() {
+        testing::setup();
+        let (seller, bidder) = (principal(1), principal(2));
+        let (id, token) = english(seller);
+        place_bid(id, bidder, MIN_BID).unwrap();
+        env::advance_time(HOUR);
+        ledger::with_mut(|ledger| ledger.set_paused(Operation::Transfer, true));
+
+        env::block_on(settle(id)).unwrap();
+        let retry = auction(id).unwrap();
+        assert_eq!(retry.status, AuctionStatus::Open);
+        assert_eq!(retry.settle_attempts, 1);
+        assert!(ledger::with(|ledger| due_for_settlement(ledger, time())).is_empty());
+        assert_eq!(
+            ledger::with(|ledger| due_for_settlement(ledger, retry.next_settle_at)),
+            vec![id]
+        );
+
+        for _ in 1..MAX_SETTLE_ATTEMPTS {
+            env::set_time(auction(id).unwrap().next_settle_at);
+            env::block_on(settle(id)).unwrap();
+        }
+
+        assert_eq!(status(id), AuctionStatus::Cancelled);
+        assert_eq!(owner_of(&token), Some(seller));
+        assert!(!ledger::with(|ledger| ledger.is_locked(&token)));
+        assert_eq!(stand_in::balance(&account(bidder)), MIN_BID - icp_ledger::fee());
+        assert!(ledger::with(|ledger| due_for_settlement(ledger, u64::MAX)).is_empty());
+    }
+
+    #[test]
+    fn an_auction_whose_escrow_lapsed_is_void() {
+        testing::setup();
+        let (seller, bidder) = (principal(1), principal(2));
+        let (id, token) = english(seller);
+        place_bid(id, bidder, MIN_BID).unwrap();
+
+        env::advance_time(HOUR + SETTLEMENT_GRACE);
+        env::block_on(settle(id)).unwrap();
+
+        assert_eq!(status(id), AuctionStatus::Cancelled);
+        assert_eq!(owner_of(&token), Some(seller));
+        assert_eq!(stand_in::balance(&account(bidder)), MIN_BID - icp_ledger::fee());
+    }
+}
@@ -0,0 +1,150 @@
+use crate::module::env::time;
+use crate::module::rbac;
+use crate::module::types::{CapMetrics, CapOutbox, NftError, Role};
+use cap_sdk::IndefiniteEvent;
+use std::cell::RefCell;
+
+// Most events the heartbeat delivers in one flush.
+pub const MAX_FLUSH_SIZE: usize = 50;
+const MIN_BACKOFF: u64 = 5 * 1_000_000_000;
+const MAX_BACKOFF: u64 = 60 * 60 * 1_000_000_000;
+// A flush still running after this long is assumed to have trapped in a
+// callback and no longer blocks new ones.
+const FLUSH_TIMEOUT: u64 = 10 * 60 * 1_000_000_000;
+
+thread_local! {
+    static OUTBOX: RefCell<CapOutbox> = RefCell::new(CapOutbox::default());
+}
+
+pub fn with<T, F: FnOnce(&CapOutbox) -> T>(f: F) -> T {
+    OUTBOX.with(|outbox| f(&outbox.borrow()))
+}
+
+pub fn with_mut<T, F: FnOnce(&mut CapOutbox) -> T>(f: F) -> T {
+    OUTBOX.with(|outbox| f(&mut outbox.borrow_mut()))
+}
+
+pub fn outbox_info() -> CapOutbox {
+    with(|outbox| outbox.clone())
+}
+
+pub fn restore_outbox_info(mut outbox_info: CapOutbox) {
+    outbox_info.flush_started_at = None;
+    with_mut(|outbox| *outbox = outbox_info);
+}
+
+// Drop-in replacement for `cap_sdk::insert_sync`: the event is queued here and
+// delivered by the heartbeat, so a CAP outage delays history instead of losing
+// it. Events are discarded while delivery is disabled.
+pub fn insert_sync(event: IndefiniteEvent) {
+    with_mut(|outbox| {
+        if outbox.disabled {
+            outbox.dropped += 1;
+        } else {
+            outbox.pending.push(event);
+        }
+    })
+}
+
+fn is_flushing(outbox: &CapOutbox, now: u64) -> bool {
+    outbox
+        .flush_started_at
+        .map_or(false, |started_at| started_at.saturating_add(FLUSH_TIMEOUT) > now)
+}
+
+// Called from the heartbeat; starts a flush once the backoff has elapsed.
+pub fn flush_due() {
+    let now = time();
+    let due = with(|outbox| {
+        !outbox.disabled
+            && !outbox.pending.is_empty()
+            && outbox.next_attempt_at <= now
+            && !is_flushing(outbox, now)
+    });
+    if due {
+        ic_cdk::spawn(async {
+            flush(MAX_FLUSH_SIZE).await;
+        });
+    }
+}
+
+// Delivers up to `limit` events in order. The first failure stops the flush
+// and doubles the delay before the next attempt.
+async fn flush(limit: usize) -> u64 {
+    with_mut(|outbox| outbox.flush_started_at = Some(time()));
+    let mut delivered = 0;
+    while (delivered as usize) < limit {
+        let event = match with(|outbox| outbox.pending.first().cloned()) {
+            Some(event) => event,
+            None => break,
+        };
+        match cap_sdk::insert(event).await {
+            Ok(_) => {
+                with_mut(|outbox| {
+                    if !outbox.pending.is_empty() {
+                        outbox.pending.remove(0);
+                    }
+                    outbox.delivered += 1;
+                    outbox.backoff = 0;
+                });
+                delivered += 1;
+            }
+            Err(err) => {
+                with_mut(|outbox| {
+                    outbox.failed += 1;
+                    outbox.backoff =
+                        outbox.backoff.saturating_mul(2).clamp(MIN_BACKOFF, MAX_BACKOFF);
+                    outbox.next_attempt_at = time().saturating_add(outbox.backoff);
+                    outbox.last_error = Some(format!("{:?}", err));
+                });
+                break;
+            }
+        }
+    }
+    with_mut(|outbox| outbox.flush_started_at = None);
+    delivered
+}
+
+// Delivers everything pending right away, ignoring the backoff.
+pub async fn force_flush() -> Result<CapMetrics, NftError> {
+    rbac::ensure_role(Role::Admin)?;
+    if with(|outbox| is_flushing(outbox, time())) {
+        return Err(NftError::FlushInProgress);
+    }
+    if !with(|outbox| outbox.disabled) {
+        flush(usize::MAX).await;
+    }
+    Ok(metrics())
+}
+
+// Turning delivery off discards the pending events; the in-canister history
+// (ICRC-3 blocks) is unaffected.
+pub fn set_enabled(enabled: bool) -> Result<(), NftError> {
+    rbac::ensure_role(Role::Admin)?;
+    with_mut(|outbox| {
+        outbox.disabled = !enabled;
+        if !enabled {
+            outbox.dropped += outbox.pending.len() as u64;
+            outbox.pending.clear();
+        }
+        outbox.backoff = 0;
+        outbox.next_attempt_at = 0;
+    });
+    Ok(())
+}
+
+pub fn pending() -> Vec<IndefiniteEvent> {
+    with(|outbox| outbox.pending.clone())
+}
+
+pub fn metrics() -> CapMetrics {
+    with(|outbox| CapMetrics {
+        enabled: !outbox.disabled,
+        pending: outbox.pending.len() as u64,
+        delivered: outbox.delivered,
+        failed: outbox.failed,
+        dropped: outbox.dropped,
+        next_attempt_at: outbox.next_attempt_at,
+        last_error: outbox.last_error.clone(),
+    })
+}
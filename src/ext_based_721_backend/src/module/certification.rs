@@ -0,0 +1,300 @@
+use crate::module::env;
+use crate::module::icrc3;
+use crate::module::ledger::{self, Ledger};
+use crate::module::reveal;
+use crate::module::types::{
+    CertifiedToken, CertifiedTokens, DataCertificate, GeneralValue, NftError, TokenMetaData,
+    Token_ID, Value,
+};
+use ic_cdk::export::Principal;
+use ic_certified_map::{fork, labeled, AsHashTree, Hash, HashTree, RbTree};
+use serde::Serialize;
+use std::cell::RefCell;
+
+// The certified root is
+//   fork(fork(last_block_hash, last_block_index), fork(owners, tokens))
+// where the first half is the ICRC-3 tip (absent until the first block) and
+// the second half holds one leaf per owner and per token. The trees are
+// derived from `Ledger` and rebuilt after an upgrade rather than persisted.
+thread_local! {
+    static TOKENS: RefCell<RbTree<Vec<u8>, Hash>> = const { RefCell::new(RbTree::new()) };
+    static OWNERS: RefCell<RbTree<Vec<u8>, Hash>> = const { RefCell::new(RbTree::new()) };
+}
+
+// Tokens are keyed by their decimal identifier, owners by their raw principal.
+// `Nat`'s `Display` groups digits with underscores, so format the inner value.
+fn token_key(token_identifier: &Token_ID) -> Vec<u8> {
+    token_identifier.0.to_string().into_bytes()
+}
+
+fn owner_key(owner: &Principal) -> Vec<u8> {
+    owner.as_slice().to_vec()
+}
+
+// The ICRC-3 hash of { owner = [blob]; metadata = map } over the properties
+// clients currently see; `owner` is left out once the token is burned.
+pub fn token_hash(token_metadata: &TokenMetaData) -> Hash {
+    let mut entries = vec![(
+        "metadata".to_string(),
+        Value::from(GeneralValue::NestedContent(reveal::visible_properties(
+            token_metadata,
+        ))),
+    )];
+    if let Some(owner) = token_metadata.owner {
+        entries.push(("owner".to_string(), icrc3::account(owner)));
+    }
+    icrc3::hash_value(&Value::Map(entries))
+}
+
+// The ICRC-3 hash of the owner's token identifiers in ascending order.
+pub fn owner_hash(token_identifiers: &[Token_ID]) -> Hash {
+    icrc3::hash_value(&Value::Array(
+        token_identifiers.iter().map(icrc3::token).collect(),
+    ))
+}
+
+fn owned_tokens(ledger: &Ledger, owner: &Principal) -> Vec<Token_ID> {
+    let mut token_identifiers = ledger
+        .owner_token_identifiers(owner)
+        .map(|token_identifiers| token_identifiers.iter().cloned().collect::<Vec<_>>())
+        .unwrap_or_default();
+    token_identifiers.sort();
+    token_identifiers
+}
+
+// Refreshes the leaves of a token and of the owners it moved between. The new
+// root is only published by the next `certify`, which `icrc3::append` does.
+pub fn update(ledger: &Ledger, token_identifier: &Token_ID, owners: &[Option<Principal>]) {
+    TOKENS.with(|tokens| {
+        let mut tokens = tokens.borrow_mut();
+        match ledger.token_metadata(token_identifier) {
+            Ok(token_metadata) => {
+                tokens.insert(token_key(token_identifier), token_hash(token_metadata))
+            }
+            Err(_) => tokens.delete(&token_key(token_identifier)),
+        }
+    });
+    OWNERS.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        for owner in owners.iter().flatten() {
+            let token_identifiers = owned_tokens(ledger, owner);
+            if token_identifiers.is_empty() {
+                tree.delete(&owner_key(owner));
+            } else {
+                tree.insert(owner_key(owner), owner_hash(&token_identifiers));
+            }
+        }
+    });
+}
+
+// Recomputes every leaf, e.g. after an upgrade or when hidden metadata is
+// revealed, and publishes the new root.
+pub fn rebuild(ledger: &Ledger) {
+    TOKENS.with(|tokens| {
+        let mut tokens = tokens.borrow_mut();
+        *tokens = RbTree::new();
+        for (token_identifier, token_metadata) in ledger.tokens.iter() {
+            tokens.insert(token_key(token_identifier), token_hash(token_metadata));
+        }
+    });
+    OWNERS.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        *tree = RbTree::new();
+        for owner in ledger.owners.keys() {
+            tree.insert(owner_key(owner), owner_hash(&owned_tokens(ledger, owner)));
+        }
+    });
+    certify(ledger);
+}
+
+fn tree<'a>(ledger: &'a Ledger, owners: HashTree<'a>, tokens: HashTree<'a>) -> HashTree<'a> {
+    let state = fork(labeled(b"owners", owners), labeled(b"tokens", tokens));
+    match icrc3::tip(ledger) {
+        Some(tip) => fork(tip, state),
+        None => state,
+    }
+}
+
+pub fn certify(ledger: &Ledger) {
+    let root = OWNERS.with(|owners| {
+        TOKENS.with(|tokens| {
+            tree(
+                ledger,
+                HashTree::Pruned(owners.borrow().root_hash()),
+                HashTree::Pruned(tokens.borrow().root_hash()),
+            )
+            .reconstruct()
+        })
+    });
+    env::set_certified_data(&root);
+}
+
+fn encode(tree: &HashTree) -> Vec<u8> {
+    let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
+    serializer.self_describe().expect("failed to encode hash tree");
+    tree.serialize(&mut serializer)
+        .expect("failed to encode hash tree");
+    serializer.into_inner()
+}
+
+// Witnesses the ICRC-3 tip only, with the ownership subtrees pruned.
+pub fn tip_certificate(ledger: &Ledger) -> Option<DataCertificate> {
+    let certificate = env::data_certificate()?;
+    icrc3::tip(ledger)?;
+    let hash_tree = OWNERS.with(|owners| {
+        TOKENS.with(|tokens| {
+            encode(&tree(
+                ledger,
+                HashTree::Pruned(owners.borrow().root_hash()),
+                HashTree::Pruned(tokens.borrow().root_hash()),
+            ))
+        })
+    });
+    Some(DataCertificate {
+        certificate,
+        hash_tree,
+    })
+}
+
+pub fn certified_token(token_identifier: Token_ID) -> Result<CertifiedToken, NftError> {
+    let certificate = env::data_certificate().ok_or(NftError::CertificateUnavailable)?;
+    ledger::with(|ledger| {
+        let token_metadata = ledger.token_metadata(&token_identifier)?;
+        let hash_tree = OWNERS.with(|owners| {
+            TOKENS.with(|tokens| {
+                encode(&tree(
+                    ledger,
+                    HashTree::Pruned(owners.borrow().root_hash()),
+                    tokens.borrow().witness(&token_key(&token_identifier)),
+                ))
+            })
+        });
+        Ok(CertifiedToken {
+            token_identifier: token_identifier.clone(),
+            owner: token_metadata.owner,
+            properties: reveal::visible_properties(token_metadata),
+            certificate: DataCertificate {
+                certificate,
+                hash_tree,
+            },
+        })
+    })
+}
+
+// An owner without tokens is proven by an absence witness.
+pub fn certified_tokens_of(owner: Principal) -> Result<CertifiedTokens, NftError> {
+    let certificate = env::data_certificate().ok_or(NftError::CertificateUnavailable)?;
+    ledger::with(|ledger| {
+        let hash_tree = OWNERS.with(|owners| {
+            TOKENS.with(|tokens| {
+                encode(&tree(
+                    ledger,
+                    owners.borrow().witness(&owner_key(&owner)),
+                    HashTree::Pruned(tokens.borrow().root_hash()),
+                ))
+            })
+        });
+        Ok(CertifiedTokens {
+            owner,
+            token_identifiers: owned_tokens(ledger, &owner),
+            certificate: DataCertificate {
+                certificate,
+                hash_tree,
+            },
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::dip721;
+    use crate::module::testing::{self, custodian, mint, principal};
+
+    #[test]
+    fn every_update_publishes_a_new_root() {
+        testing::setup();
+        let token_identifier = mint(principal(1));
+        let after_mint = env::certified_data();
+        assert_eq!(after_mint.len(), 32);
+
+        ledger::with_mut(|ledger| {
+            dip721::transfer_token(ledger, principal(1), &token_identifier, principal(2))
+        })
+        .unwrap();
+        assert_ne!(env::certified_data(), after_mint);
+    }
+
+    #[test]
+    fn rebuild_matches_incremental_updates() {
+        testing::setup();
+        let first = mint(principal(1));
+        mint(principal(1));
+        ledger::with_mut(|ledger| {
+            dip721::transfer_token(ledger, principal(1), &first, principal(2))
+        })
+        .unwrap();
+        let incremental = env::certified_data();
+
+        ledger::with(rebuild);
+        assert_eq!(env::certified_data(), incremental);
+    }
+
+    #[test]
+    fn burned_tokens_are_hashed_without_an_owner() {
+        testing::setup();
+        let token_identifier = mint(principal(1));
+        let minted =
+            ledger::with(|ledger| token_hash(ledger.token_metadata(&token_identifier).unwrap()));
+        ledger::with_mut(|ledger| dip721::burn_token(ledger, principal(1), &token_identifier))
+            .unwrap();
+        let burned =
+            ledger::with(|ledger| token_hash(ledger.token_metadata(&token_identifier).unwrap()));
+        assert_ne!(minted, burned);
+    }
+
+    #[test]
+    fn certificates_need_a_data_certificate() {
+        testing::setup();
+        let token_identifier = mint(principal(1));
+        assert!(matches!(
+            certified_token(token_identifier),
+            Err(NftError::CertificateUnavailable)
+        ));
+        assert!(matches!(
+            certified_tokens_of(principal(1)),
+            Err(NftError::CertificateUnavailable)
+        ));
+        assert!(ledger::with(tip_certificate).is_none());
+    }
+
+    #[test]
+    fn certified_token_reports_owner_and_certificate() {
+        testing::setup();
+        let token_identifier = mint(principal(1));
+        env::set_data_certificate(Some(vec![7; 8]));
+
+        let certified = certified_token(token_identifier.clone()).unwrap();
+        assert_eq!(certified.token_identifier, token_identifier);
+        assert_eq!(certified.owner, Some(principal(1)));
+        assert_eq!(certified.certificate.certificate, vec![7; 8]);
+        assert!(!certified.certificate.hash_tree.is_empty());
+        assert!(ledger::with(tip_certificate).is_some());
+    }
+
+    #[test]
+    fn certified_tokens_of_lists_identifiers_in_order() {
+        testing::setup();
+        env::set_data_certificate(Some(vec![7; 8]));
+        let first = mint(principal(1));
+        mint(custodian());
+        let second = mint(principal(1));
+
+        let certified = certified_tokens_of(principal(1)).unwrap();
+        assert_eq!(certified.token_identifiers, vec![first, second]);
+        assert!(certified_tokens_of(principal(3))
+            .unwrap()
+            .token_identifiers
+            .is_empty());
+    }
+}
@@ -0,0 +1,316 @@
+use crate::module::cap::insert_sync;
+use crate::module::dip721;
+use crate::module::env;
+use crate::module::ledger;
+use crate::module::rbac;
+use crate::module::types::{NftError, Operation, Recipe, Role, Token_ID};
+use crate::prop;
+use cap_sdk::{DetailValue, IndefiniteEvent};
+use ic_cdk::export::Principal;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+thread_local! {
+    static RECIPES: RefCell<HashMap<String, Recipe>> = RefCell::new(HashMap::new());
+}
+
+pub fn with<T, F: FnOnce(&HashMap<String, Recipe>) -> T>(f: F) -> T {
+    RECIPES.with(|recipes| f(&recipes.borrow()))
+}
+
+pub fn with_mut<T, F: FnOnce(&mut HashMap<String, Recipe>) -> T>(f: F) -> T {
+    RECIPES.with(|recipes| f(&mut recipes.borrow_mut()))
+}
+
+pub fn recipes_info() -> Vec<Recipe> {
+    with(|recipes| recipes.values().cloned().collect())
+}
+
+pub fn restore_recipes_info(recipes_info: Vec<Recipe>) {
+    with_mut(|recipes| {
+        recipes_info.into_iter().for_each(|recipe| {
+            recipes.insert(recipe.id.clone(), recipe);
+        })
+    });
+}
+
+pub fn add_recipe(recipe: Recipe) -> Result<(), NftError> {
+    rbac::ensure_role(Role::MetadataEditor)?;
+    if recipe.inputs.is_empty()
+        || recipe.outputs.is_empty()
+        || recipe
+            .inputs
+            .iter()
+            .chain(recipe.outputs.iter())
+            .any(|item| item.quantity == 0)
+    {
+        return Err(NftError::InvalidRecipe);
+    }
+    if let Some(item) = recipe
+        .inputs
+        .iter()
+        .chain(recipe.outputs.iter())
+        .find(|item| prop::properties_of(&item.class).is_empty())
+    {
+        return Err(NftError::ClassNotFound(item.class.clone()));
+    }
+
+    let id = recipe.id.clone();
+    with_mut(|recipes| recipes.insert(id.clone(), recipe));
+
+    insert_sync(IndefiniteEvent {
+        caller: env::caller(),
+        operation: "addRecipe".into(),
+        details: vec![("recipe".into(), DetailValue::from(id))],
+    });
+
+    Ok(())
+}
+
+pub fn remove_recipe(id: String) -> Result<(), NftError> {
+    rbac::ensure_role(Role::MetadataEditor)?;
+    if with_mut(|recipes| recipes.remove(&id)).is_none() {
+        return Err(NftError::RecipeNotFound(id));
+    }
+
+    insert_sync(IndefiniteEvent {
+        caller: env::caller(),
+        operation: "removeRecipe".into(),
+        details: vec![("recipe".into(), DetailValue::from(id))],
+    });
+
+    Ok(())
+}
+
+// Picks, for every input of the recipe, the lowest token indices of that class
+// the owner can currently burn.
+fn select_inputs(
+    ledger: &ledger::Ledger,
+    owner: &Principal,
+    recipe: &Recipe,
+) -> Result<Vec<Token_ID>, NftError> {
+    let owned = ledger.owner_token_identifiers(owner)?;
+    let mut selected = Vec::new();
+    for item in recipe.inputs.iter() {
+        let mut candidates = owned
+            .iter()
+            .filter(|token_identifier| {
+                !ledger.is_locked(token_identifier) && !ledger.has_pending_sale(token_identifier)
+            })
+            .filter(|token_identifier| {
+                ledger
+                    .token_metadata(token_identifier)
+                    .map_or(false, |token| token.class() == Some(&item.class))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        if candidates.len() < item.quantity as usize {
+            return Err(NftError::InsufficientInputs(item.class.clone()));
+        }
+        candidates.sort();
+        selected.extend(candidates.into_iter().take(item.quantity as usize));
+    }
+    Ok(selected)
+}
+
+// Burns the recipe inputs and mints its outputs to the caller in a single
+// message. Everything that can fail is checked before the first mutation; a
+// failure after that point traps so the canister state is rolled back.
+pub fn craft(recipe_id: String) -> Result<Vec<Token_ID>, NftError> {
+    let caller = env::caller();
+    let recipe = with(|recipes| recipes.get(&recipe_id).cloned())
+        .ok_or_else(|| NftError::RecipeNotFound(recipe_id.clone()))?;
+
+    let inputs = ledger::with(|ledger| {
+        for operation in [Operation::Burn, Operation::Mint] {
+            if ledger.is_paused(&operation) {
+                return Err(NftError::OperationPaused(operation));
+            }
+        }
+        select_inputs(ledger, &caller, &recipe)
+    })?;
+
+    let outputs = recipe
+        .outputs
+        .iter()
+        .flat_map(|item| (0..item.quantity).map(move |_| item.class.clone()))
+        .map(|class| (dip721::allocate_token_identifier(), prop::properties_of(&class)))
+        .collect::<Vec<_>>();
+
+    ledger::with_mut(|ledger| {
+        for token_identifier in inputs.iter() {
+            if let Err(err) = dip721::burn_token(ledger, caller, token_identifier) {
+                ic_cdk::trap(&format!("craft: failed to burn input {}: {:?}", token_identifier, err));
+            }
+        }
+        for (token_identifier, properties) in outputs.iter() {
+            if let Err(err) =
+                dip721::mint_token(ledger, caller, caller, token_identifier, properties.clone())
+            {
+                ic_cdk::trap(&format!("craft: failed to mint output {}: {:?}", token_identifier, err));
+            }
+        }
+
+        insert_sync(IndefiniteEvent {
+            caller,
+            operation: "craft".into(),
+            details: vec![
+                ("recipe".into(), DetailValue::from(recipe_id)),
+                (
+                    "burned".into(),
+                    DetailValue::Vec(
+                        inputs
+                            .iter()
+                            .map(|token_identifier| DetailValue::from(token_identifier.to_string()))
+                            .collect(),
+                    ),
+                ),
+                (
+                    "minted".into(),
+                    DetailValue::Vec(
+                        outputs
+                            .iter()
+                            .map(|(token_identifier, _)| DetailValue::from(token_identifier.to_string()))
+                            .collect(),
+                    ),
+                ),
+            ],
+        });
+        ledger.inc_tx();
+    });
+
+    Ok(outputs.into_iter().map(|(token_identifier, _)| token_identifier).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::lock;
+    use crate::module::testing::{self, mint_class, owner_of, principal};
+    use crate::module::types::RecipeItem;
+
+    fn item(class: &str, quantity: u32) -> RecipeItem {
+        RecipeItem {
+            class: class.into(),
+            quantity,
+        }
+    }
+
+    // Two hydrogen and one helium make a lithium.
+    fn setup() -> Principal {
+        testing::setup();
+        add_recipe(Recipe {
+            id: "fusion".into(),
+            inputs: vec![item("H", 2), item("He", 1)],
+            outputs: vec![item("Li", 1)],
+        })
+        .unwrap();
+        principal(1)
+    }
+
+    fn class_of(token_identifier: &Token_ID) -> Option<String> {
+        ledger::with(|ledger| {
+            ledger
+                .token_metadata(token_identifier)
+                .ok()
+                .and_then(|token| token.class().cloned())
+        })
+    }
+
+    #[test]
+    fn craft_burns_the_inputs_and_mints_the_outputs() {
+        let crafter = setup();
+        let inputs = [
+            mint_class(crafter, "H"),
+            mint_class(crafter, "H"),
+            mint_class(crafter, "He"),
+        ];
+        let spare = mint_class(crafter, "H");
+
+        env::set_caller(crafter);
+        let outputs = craft("fusion".into()).unwrap();
+
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(owner_of(&outputs[0]), Some(crafter));
+        assert_eq!(class_of(&outputs[0]), Some("Li".into()));
+        for input in inputs.iter() {
+            assert_eq!(owner_of(input), None);
+        }
+        assert_eq!(owner_of(&spare), Some(crafter));
+    }
+
+    #[test]
+    fn craft_changes_nothing_when_an_input_is_missing() {
+        let crafter = setup();
+        let owned = [mint_class(crafter, "H"), mint_class(crafter, "He")];
+        let next = dip721::tid_info();
+
+        env::set_caller(crafter);
+        assert!(matches!(
+            craft("fusion".into()),
+            Err(NftError::InsufficientInputs(class)) if class == "H"
+        ));
+        for token_identifier in owned.iter() {
+            assert_eq!(owner_of(token_identifier), Some(crafter));
+        }
+        assert_eq!(dip721::tid_info(), next);
+    }
+
+    #[test]
+    fn locked_tokens_are_not_used_as_inputs() {
+        let crafter = setup();
+        let locked = mint_class(crafter, "He");
+        let owned = [mint_class(crafter, "H"), mint_class(crafter, "H")];
+        lock::lock(locked.clone(), "quest".into(), env::time() + 1_000).unwrap();
+
+        env::set_caller(crafter);
+        assert!(matches!(
+            craft("fusion".into()),
+            Err(NftError::InsufficientInputs(class)) if class == "He"
+        ));
+        assert_eq!(owner_of(&locked), Some(crafter));
+        for token_identifier in owned.iter() {
+            assert_eq!(owner_of(token_identifier), Some(crafter));
+        }
+    }
+
+    #[test]
+    fn craft_is_refused_while_burning_is_paused() {
+        let crafter = setup();
+        mint_class(crafter, "H");
+        mint_class(crafter, "H");
+        mint_class(crafter, "He");
+        ledger::with_mut(|ledger| ledger.set_paused(Operation::Burn, true));
+
+        env::set_caller(crafter);
+        assert!(matches!(
+            craft("fusion".into()),
+            Err(NftError::OperationPaused(Operation::Burn))
+        ));
+    }
+
+    #[test]
+    fn unknown_recipes_and_classes_are_rejected() {
+        setup();
+        assert!(matches!(
+            craft("missing".into()),
+            Err(NftError::RecipeNotFound(_))
+        ));
+        assert!(matches!(
+            add_recipe(Recipe {
+                id: "bad".into(),
+                inputs: vec![item("Xx", 1)],
+                outputs: vec![item("Li", 1)],
+            }),
+            Err(NftError::ClassNotFound(_))
+        ));
+        assert!(matches!(
+            add_recipe(Recipe {
+                id: "empty".into(),
+                inputs: vec![item("H", 0)],
+                outputs: vec![item("Li", 1)],
+            }),
+            Err(NftError::InvalidRecipe)
+        ));
+    }
+}
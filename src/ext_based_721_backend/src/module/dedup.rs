@@ -0,0 +1,185 @@
+use crate::module::env::time;
+use crate::module::ledger::{self, Ledger};
+use crate::module::types::NftError;
+use ic_cdk::export::candid::{decode_one, encode_one, CandidType};
+use ic_cdk::export::Principal;
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+
+// Requests carrying a `created_at_time` are remembered for this long so a
+// retried call is recognised instead of applied twice.
+pub const TX_WINDOW: u64 = 24 * 60 * 60 * 1_000_000_000;
+// How far ahead of the canister's clock a client's `created_at_time` may be.
+pub const PERMITTED_DRIFT: u64 = 2 * 60 * 1_000_000_000;
+
+pub enum TimeError {
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+}
+
+impl From<TimeError> for NftError {
+    fn from(err: TimeError) -> Self {
+        match err {
+            TimeError::TooOld => NftError::TooOld,
+            TimeError::CreatedInFuture { ledger_time } => NftError::CreatedInFuture(ledger_time),
+        }
+    }
+}
+
+pub fn check_time(created_at_time: u64) -> Result<(), TimeError> {
+    let now = time();
+    if is_expired(created_at_time, now) {
+        return Err(TimeError::TooOld);
+    }
+    if created_at_time > now.saturating_add(PERMITTED_DRIFT) {
+        return Err(TimeError::CreatedInFuture { ledger_time: now });
+    }
+    Ok(())
+}
+
+// Identifies a request by its caller, method and full argument, including
+// `created_at_time`, so only an exact retry matches.
+pub fn key<T: CandidType>(caller: &Principal, method: &str, arg: &T) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(caller.as_slice());
+    hasher.update(method.as_bytes());
+    hasher.update(encode_one(arg).expect("failed to encode request"));
+    hasher.finalize().to_vec()
+}
+
+// The result recorded for an earlier identical request, if any. Only
+// successful results are recorded.
+pub fn find<R: CandidType + DeserializeOwned>(ledger: &Ledger, key: &[u8]) -> Option<R> {
+    ledger
+        .recent_transactions
+        .get(key)
+        .and_then(|(_, result)| decode_one(result).ok())
+}
+
+fn is_expired(created_at_time: u64, now: u64) -> bool {
+    created_at_time.saturating_add(TX_WINDOW + PERMITTED_DRIFT) < now
+}
+
+pub fn record<R: CandidType>(ledger: &mut Ledger, key: Vec<u8>, created_at_time: u64, result: &R) {
+    let now = time();
+    let encoded = encode_one(result).expect("failed to encode result");
+    ledger
+        .recent_transactions
+        .retain(|_, (created_at, _)| !is_expired(*created_at, now));
+    ledger
+        .recent_transactions
+        .insert(key, (created_at_time, encoded));
+}
+
+// Runs `f` at most once per distinct successful request. Calls without
+// `created_at_time` are not deduplicated; with it, an identical retry inside
+// the window gets the result of the first successful call back instead of
+// running `f` again. Failures are not recorded, so a request that failed,
+// e.g. while an operation was paused, can be retried as is.
+pub fn idempotent<A, R, E, F>(
+    caller: &Principal,
+    method: &str,
+    arg: &A,
+    created_at_time: Option<u64>,
+    f: F,
+) -> Result<Result<R, E>, TimeError>
+where
+    A: CandidType,
+    R: CandidType + DeserializeOwned,
+    F: FnOnce() -> Result<R, E>,
+{
+    let created_at_time = match created_at_time {
+        Some(created_at_time) => created_at_time,
+        None => return Ok(f()),
+    };
+    check_time(created_at_time)?;
+    let key = key(caller, method, arg);
+    if let Some(result) = ledger::with(|ledger| find(ledger, &key)) {
+        return Ok(Ok(result));
+    }
+
+    let result = f();
+    if let Ok(value) = &result {
+        ledger::with_mut(|ledger| record(ledger, key, created_at_time, value));
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::env;
+    use crate::module::testing::principal;
+    use std::cell::Cell;
+
+    fn call(calls: &Cell<u32>, created_at_time: Option<u64>, fail: bool) -> Result<u64, String> {
+        // Like every request, the argument carries its own `created_at_time`.
+        idempotent(&principal(1), "method", &(7u64, created_at_time), created_at_time, || {
+            calls.set(calls.get() + 1);
+            if fail {
+                Err("failed".to_string())
+            } else {
+                Ok(calls.get() as u64)
+            }
+        })
+        .map_err(|_| "bad time".to_string())?
+    }
+
+    #[test]
+    fn retries_get_the_first_result_back() {
+        let calls = Cell::new(0);
+        let now = env::time();
+
+        assert_eq!(call(&calls, Some(now), false), Ok(1));
+        assert_eq!(call(&calls, Some(now), false), Ok(1));
+        assert_eq!(calls.get(), 1);
+        // A different `created_at_time` is a different request.
+        assert_eq!(call(&calls, Some(now + 1), false), Ok(2));
+    }
+
+    #[test]
+    fn failures_are_not_recorded() {
+        let calls = Cell::new(0);
+        let now = env::time();
+
+        assert!(call(&calls, Some(now), true).is_err());
+        assert_eq!(call(&calls, Some(now), false), Ok(2));
+        assert_eq!(call(&calls, Some(now), false), Ok(2));
+    }
+
+    #[test]
+    fn requests_without_a_time_always_run() {
+        let calls = Cell::new(0);
+
+        assert_eq!(call(&calls, None, false), Ok(1));
+        assert_eq!(call(&calls, None, false), Ok(2));
+    }
+
+    #[test]
+    fn times_outside_the_window_are_rejected() {
+        env::set_time(TX_WINDOW * 2);
+        let now = env::time();
+
+        assert!(matches!(
+            check_time(now - TX_WINDOW - PERMITTED_DRIFT - 1),
+            Err(TimeError::TooOld)
+        ));
+        assert!(matches!(
+            check_time(now + PERMITTED_DRIFT + 1),
+            Err(TimeError::CreatedInFuture { ledger_time }) if ledger_time == now
+        ));
+        assert!(check_time(now + PERMITTED_DRIFT).is_ok());
+    }
+
+    #[test]
+    fn expired_entries_are_pruned() {
+        let calls = Cell::new(0);
+        let start = env::time();
+        call(&calls, Some(start), false).unwrap();
+
+        env::advance_time(TX_WINDOW + PERMITTED_DRIFT + 1);
+        call(&calls, Some(env::time()), false).unwrap();
+
+        ledger::with(|ledger| assert_eq!(ledger.recent_transactions.len(), 1));
+    }
+}
@@ -0,0 +1,43 @@
+use crate::module::env::time;
+use crate::module::rbac;
+use crate::module::types::{Diagnostic, NftError, Role};
+use cap_sdk::DetailValue;
+use ic_cdk::export::Principal;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+// Oldest entries are dropped past this many.
+pub const MAX_DIAGNOSTICS: usize = 1_000;
+
+// Rejected requests are kept here rather than in the public history, which
+// only records state changes. The log is not persisted across upgrades.
+thread_local! {
+    static DIAGNOSTICS: RefCell<VecDeque<Diagnostic>> = RefCell::new(VecDeque::new());
+}
+
+pub fn log(caller: Principal, operation: &str, details: Vec<(String, DetailValue)>) {
+    DIAGNOSTICS.with(|diagnostics| {
+        let mut diagnostics = diagnostics.borrow_mut();
+        if diagnostics.len() == MAX_DIAGNOSTICS {
+            diagnostics.pop_front();
+        }
+        diagnostics.push_back(Diagnostic {
+            recorded_at: time(),
+            caller,
+            operation: operation.into(),
+            details,
+        });
+    })
+}
+
+// Oldest first.
+pub fn diagnostics() -> Result<Vec<Diagnostic>, NftError> {
+    rbac::ensure_role(Role::Admin)?;
+    Ok(DIAGNOSTICS.with(|diagnostics| diagnostics.borrow().iter().cloned().collect()))
+}
+
+pub fn clear_diagnostics() -> Result<(), NftError> {
+    rbac::ensure_role(Role::Admin)?;
+    DIAGNOSTICS.with(|diagnostics| diagnostics.borrow_mut().clear());
+    Ok(())
+}
@@ -1,11 +1,18 @@
+use crate::module::cap::insert_sync;
+use crate::module::certification;
+use crate::module::diagnostics;
+use crate::module::env::{self, time};
+use crate::module::icrc3;
+use crate::module::icrc37;
 use crate::module::ledger;
-// use crate::module::token_identifier;
+use crate::module::reveal;
+use crate::prop;
+use crate::module::token_identifier;
 use crate::module::types::{
-     GeneralValue, InitArgs,
-    NftError, Token_ID, TokenMetaData,
+     ApprovalInfo, GeneralValue, InitArgs,
+    NftError, Operation, Role, Token_ID, TokenMetaData, Value,
 };
-use cap_sdk::{insert_sync, DetailValue, IndefiniteEvent};
-use ic_cdk::api::time;
+use cap_sdk::{DetailValue, IndefiniteEvent};
 use ic_cdk::export::candid::Nat;
 use ic_cdk::export::Principal;
 use std::cell::RefCell;
@@ -13,17 +20,27 @@ use std::ops::Not;
 use std::sync::atomic::AtomicU32;
 use std::collections::HashSet;
 thread_local! {
-    static TID: RefCell<AtomicU32> = RefCell::new(AtomicU32::new(1));
+    static TID: RefCell<AtomicU32> = const { RefCell::new(AtomicU32::new(1)) };
 }
 
 pub fn new_token_id() -> u32 {
     TID.with(|tid| {
         let token = tid.borrow_mut();
-        let new_id = token.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-        new_id
+        token.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
     })
 }
 
+// Reserves the next token index and registers its EXT token identifier.
+pub fn allocate_token_identifier() -> Token_ID {
+    let token_index = new_token_id();
+    let cid = token_identifier::CanisterId(env::id());
+    let encoded_token =
+        token_identifier::encode_token_id(cid, token_identifier::TokenIndex(token_index));
+    let token_identifier = Nat::from(token_index);
+    prop::add_token(&token_identifier, &encoded_token);
+    token_identifier
+}
+
 pub fn tid_info() -> u32 {
     TID.with(|tid| {
         tid.borrow_mut().fetch_add(0, std::sync::atomic::Ordering::SeqCst)
@@ -37,7 +54,77 @@ pub fn restore_tid_info(token_index: u32) {
 }
 
 pub fn dip721_init(args: Option<InitArgs>) {
-    ledger::with_mut(|ledger| ledger.init_metadata(ic_cdk::api::caller(), args));
+    ledger::with_mut(|ledger| ledger.init_metadata(env::caller(), args));
+}
+
+pub fn dip721_set_paused(operation: Operation, paused: bool) -> Result<(), NftError> {
+    ledger::with_mut(|ledger| {
+        let caller = env::caller();
+        if !ledger.has_role(&caller, &Role::Pauser) {
+            return Err(NftError::MissingRole(Role::Pauser));
+        }
+        ledger.set_paused(operation, paused);
+
+        insert_sync(IndefiniteEvent {
+            caller,
+            operation: if paused { "pause" } else { "unpause" }.into(),
+            details: vec![(
+                "operation".into(),
+                DetailValue::from(format!("{:?}", operation)),
+            )],
+        });
+
+        Ok(())
+    })
+}
+
+pub fn dip721_is_paused(operation: Operation) -> bool {
+    ledger::with(|ledger| ledger.is_paused(&operation))
+}
+
+pub fn dip721_paused_operations() -> Vec<Operation> {
+    ledger::with(|ledger| ledger.metadata.paused.iter().cloned().collect())
+}
+
+pub fn dip721_set_name(name: String) -> Result<(), NftError> {
+    update_metadata("setName", "name", name, |ledger, name| {
+        ledger.metadata_mut().name = Some(name)
+    })
+}
+
+pub fn dip721_set_logo(logo: String) -> Result<(), NftError> {
+    update_metadata("setLogo", "logo", logo, |ledger, logo| {
+        ledger.metadata_mut().logo = Some(logo)
+    })
+}
+
+pub fn dip721_set_symbol(symbol: String) -> Result<(), NftError> {
+    update_metadata("setSymbol", "symbol", symbol, |ledger, symbol| {
+        ledger.metadata_mut().symbol = Some(symbol)
+    })
+}
+
+fn update_metadata<F: FnOnce(&mut ledger::Ledger, String)>(
+    operation: &str,
+    field: &str,
+    value: String,
+    f: F,
+) -> Result<(), NftError> {
+    ledger::with_mut(|ledger| {
+        let caller = env::caller();
+        if !ledger.has_role(&caller, &Role::MetadataEditor) {
+            return Err(NftError::MissingRole(Role::MetadataEditor));
+        }
+        f(ledger, value.clone());
+
+        insert_sync(IndefiniteEvent {
+            caller,
+            operation: operation.into(),
+            details: vec![(field.into(), DetailValue::from(value))],
+        });
+
+        Ok(())
+    })
 }
 
 pub fn dip721_total_supply() -> Nat {
@@ -56,50 +143,21 @@ pub fn dip721_transfer_from(
     owner: Principal,
     to: Principal,
     token_identifier: Token_ID,
+) -> Result<Nat, NftError> {
+    transfer_from_by(env::caller(), owner, to, token_identifier)
+}
+
+// `dip721_transfer_from` on behalf of `caller`, for work that runs outside the
+// message that requested it.
+pub fn transfer_from_by(
+    caller: Principal,
+    owner: Principal,
+    to: Principal,
+    token_identifier: Token_ID,
 ) -> Result<Nat, NftError> {
     ledger::with_mut(|ledger| {
-        let caller = ic_cdk::api::caller();
-        if owner.eq(&to) {
-            insert_sync(IndefiniteEvent {
-                caller: ic_cdk::api::caller(),
-                operation: "verify owner".into(),
-                details: vec![("owner".into(), DetailValue::from(owner.clone()))],
-            });
-            return Err(NftError::UnauthorizedOwner);
-        }
-        let old_owner = match ledger.owner_of(&token_identifier).ok() {
-            Some(owner) => owner,
-            None => return Err(NftError::OwnerNotFound),
-        };
-        let old_operator = match ledger.operator_of(&token_identifier).ok() {
-            Some(operator) => operator,
-            None => return Err(NftError::OperatorNotFound),
-        };
-        if old_owner.ne(&Some(owner)) {
-            insert_sync(IndefiniteEvent {
-                caller: ic_cdk::api::caller(),
-                operation: "verify old owner".into(),
-                details: vec![(
-                    "old owner".into(),
-                    DetailValue::from(old_owner.unwrap().clone()),
-                )],
-            });
-            return Err(NftError::UnauthorizedOwner);
-        }
-        if old_operator.ne(&Some(caller)) {
-            insert_sync(IndefiniteEvent {
-                caller: ic_cdk::api::caller(),
-                operation: "verify old operator".into(),
-                details: vec![(
-                    "old operator".into(),
-                    DetailValue::from(old_operator.unwrap().clone()),
-                )],
-            });
-            return Err(NftError::UnauthorizedOperator);
-        }
-        ledger.update_owner_cache(&token_identifier, old_owner, Some(to));
-        ledger.update_operator_cache(&token_identifier, old_operator, Some(to));
-        ledger.transfer(caller, &token_identifier, Some(to));
+        check_transfer_from(ledger, caller, owner, to, &token_identifier)?;
+        transfer_token(ledger, caller, &token_identifier, to)?;
 
         insert_sync(IndefiniteEvent {
             caller,
@@ -114,99 +172,242 @@ pub fn dip721_transfer_from(
             ],
         });
 
-        Ok(Nat::from(ledger.inc_tx() - 1))
+        Ok(ledger.inc_tx() - 1)
     })
 }
 
-pub fn dip721_mint(
+// Everything `transfer_from_by` verifies before moving a token, so batches can
+// be validated as a whole before any of their transfers is applied.
+pub fn check_transfer_from(
+    ledger: &ledger::Ledger,
+    caller: Principal,
+    owner: Principal,
+    to: Principal,
+    token_identifier: &Token_ID,
+) -> Result<(), NftError> {
+    check_transfer_authority(ledger, caller, owner, to, token_identifier)?;
+    check_transferable(ledger, token_identifier, None)
+}
+
+// Whether `caller` may move `owner`'s token to `to`, leaving aside whether the
+// token may change hands right now.
+pub fn check_transfer_authority(
+    ledger: &ledger::Ledger,
+    caller: Principal,
+    owner: Principal,
+    to: Principal,
+    token_identifier: &Token_ID,
+) -> Result<(), NftError> {
+    if owner.eq(&to) {
+        diagnostics::log(
+            caller,
+            "verify owner",
+            vec![("owner".into(), DetailValue::from(owner))],
+        );
+        return Err(NftError::UnauthorizedOwner);
+    }
+    let old_owner = match ledger.owner_of(token_identifier).ok() {
+        Some(owner) => owner,
+        None => return Err(NftError::OwnerNotFound),
+    };
+    let old_operator = match ledger.operator_of(token_identifier).ok() {
+        Some(operator) => operator,
+        None => return Err(NftError::OperatorNotFound),
+    };
+    if old_owner.ne(&Some(owner)) {
+        diagnostics::log(
+            caller,
+            "verify old owner",
+            old_owner
+                .map(|old_owner| ("old owner".into(), DetailValue::from(old_owner)))
+                .into_iter()
+                .collect(),
+        );
+        return Err(NftError::UnauthorizedOwner);
+    }
+    if old_operator.ne(&Some(caller))
+        || ledger
+            .approvals
+            .get(token_identifier)
+            .map_or(false, |approval| !approval.is_active(time()))
+    {
+        diagnostics::log(
+            caller,
+            "verify old operator",
+            old_operator
+                .map(|operator| ("old operator".into(), DetailValue::from(operator)))
+                .into_iter()
+                .collect(),
+        );
+        return Err(NftError::UnauthorizedOperator);
+    }
+    Ok(())
+}
+
+// Whether the token may change hands right now, regardless of who moves it. A
+// token held in escrow for `escrow` counts as free, so the operation holding
+// it can validate its own transfers before releasing it.
+pub fn check_transferable(
+    ledger: &ledger::Ledger,
+    token_identifier: &Token_ID,
+    escrow: Option<&str>,
+) -> Result<(), NftError> {
+    if ledger.is_paused(&Operation::Transfer) {
+        return Err(NftError::OperationPaused(Operation::Transfer));
+    }
+    if ledger.active_lock(token_identifier).is_some() {
+        return Err(NftError::TokenLocked);
+    }
+    match (ledger.active_escrow(token_identifier), escrow) {
+        (None, _) => {}
+        (Some(held), Some(reason)) if held.reason == reason => {}
+        _ => return Err(NftError::TokenLocked),
+    }
+    if ledger.has_pending_sale(token_identifier) {
+        return Err(NftError::SalePending);
+    }
+    if is_soulbound(ledger.token_metadata(token_identifier)?) {
+        return Err(NftError::NonTransferable);
+    }
+    Ok(())
+}
+
+// Transfer counterpart of `mint_token`. Callers are responsible for checking
+// that the token may be moved on the owner's behalf. Returns the index of the
+// ICRC-3 block recording the transfer.
+pub fn transfer_token(
+    ledger: &mut ledger::Ledger,
+    transferred_by: Principal,
+    token_identifier: &Token_ID,
     to: Principal,
-    token_identifier: Token_ID,
-    properties: Vec<(String, GeneralValue)>,
 ) -> Result<Nat, NftError> {
+    check_transferable(ledger, token_identifier, None)?;
+    let old_owner = ledger.owner_of(token_identifier)?;
+    let old_operator = ledger.operator_of(token_identifier)?;
+    ledger.update_owner_cache(token_identifier, old_owner, Some(to));
+    ledger.update_operator_cache(token_identifier, old_operator, Some(to));
+    ledger.transfer(transferred_by, token_identifier, Some(to));
+    certification::update(ledger, token_identifier, &[old_owner, Some(to)]);
+
+    let mut tx = vec![("tid", icrc3::token(token_identifier)), ("to", icrc3::account(to))];
+    if let Some(from) = old_owner {
+        tx.push(("from", icrc3::account(from)));
+    }
+    if old_owner == Some(transferred_by) {
+        Ok(icrc3::append(ledger, "7xfer", tx))
+    } else {
+        tx.push(("spender", icrc3::account(transferred_by)));
+        Ok(icrc3::append(ledger, "37xfer", tx))
+    }
+}
+
+// Mints under the next token index. The index is only taken once the mint has
+// passed validation, so rejected mints leave no gaps.
+pub fn mint_next(
+    caller: Principal,
+    to: Principal,
+    properties: Vec<(String, GeneralValue)>,
+) -> Result<(Token_ID, Nat), NftError> {
     ledger::with_mut(|ledger| {
-        let caller = ic_cdk::api::caller();
-        if properties.is_empty() {
-            insert_sync(IndefiniteEvent {
-                caller: ic_cdk::api::caller(),
-                operation: "verify properties".into(),
-                details: vec![(
-                    "properties has no metadata".into(),
-                    DetailValue::from(token_identifier.clone()),
-                )],
-            });
-        }
-        if !ledger.is_token_existed(&token_identifier).not() {
-            insert_sync(IndefiniteEvent {
-                caller: ic_cdk::api::caller(),
-                operation: "verify token exist".into(),
-                details: vec![(
-                    "existed token identifier".into(),
-                    DetailValue::from(token_identifier.clone()),
-                )],
-            });
-            return Err(NftError::ExistedNFT);
-        }
-        ledger.add_token_metadata(
-            token_identifier.clone(),
-            TokenMetaData {
-                token_identifier: token_identifier.clone(),
-                owner: Some(to),
-                operator: Some(caller),
-                properties,
-                is_burned: false,
-                minted_at: time(),
-                minted_by: to,
-                transferred_at: None,
-                transferred_by: None,
-                approved_at: None,
-                approved_by: None,
-                burned_at: None,
-                burned_by: None,
-            },
+        let token_identifier = Nat::from(tid_info());
+        mint_token(ledger, caller, to, &token_identifier, properties)?;
+        allocate_token_identifier();
+        let tx_id = record_mint(ledger, caller, to, &token_identifier);
+        Ok((token_identifier, tx_id))
+    })
+}
+
+fn record_mint(
+    ledger: &mut ledger::Ledger,
+    caller: Principal,
+    to: Principal,
+    token_identifier: &Token_ID,
+) -> Nat {
+    insert_sync(IndefiniteEvent {
+        caller,
+        operation: "mint".into(),
+        details: vec![
+            ("to".into(), DetailValue::from(to)),
+            (
+                "token_identifier".into(),
+                DetailValue::from(token_identifier.to_string()),
+            ),
+        ],
+    });
+
+    ledger.inc_tx() - 1
+}
+
+// Validates and applies a mint without recording a transaction, so composite
+// operations can report several mints and burns as a single event.
+pub fn mint_token(
+    ledger: &mut ledger::Ledger,
+    caller: Principal,
+    to: Principal,
+    token_identifier: &Token_ID,
+    properties: Vec<(String, GeneralValue)>,
+) -> Result<(), NftError> {
+    if ledger.is_paused(&Operation::Mint) {
+        return Err(NftError::OperationPaused(Operation::Mint));
+    }
+    if properties.is_empty() {
+        diagnostics::log(
+            caller,
+            "verify properties",
+            vec![(
+                "properties has no metadata".into(),
+                DetailValue::from(token_identifier.clone()),
+            )],
         );
-        ledger.update_owner_cache(&token_identifier, None, Some(to));
-        ledger.update_operator_cache(&token_identifier, None, Some(caller));
-        insert_sync(IndefiniteEvent {
+    }
+    if !ledger.is_token_existed(token_identifier).not() {
+        diagnostics::log(
             caller,
-            operation: "mint".into(),
-            details: vec![
-                ("to".into(), DetailValue::from(to)),
-                (
-                    "token_identifier".into(),
-                    DetailValue::from(token_identifier.to_string()),
-                ),
-            ],
-        });
+            "verify token exist",
+            vec![(
+                "existed token identifier".into(),
+                DetailValue::from(token_identifier.clone()),
+            )],
+        );
+        return Err(NftError::ExistedNFT);
+    }
+    let mut token_metadata = TokenMetaData {
+        token_identifier: token_identifier.clone(),
+        owner: Some(to),
+        operator: Some(caller),
+        properties,
+        is_burned: false,
+        minted_at: time(),
+        minted_by: to,
+        transferred_at: None,
+        transferred_by: None,
+        approved_at: None,
+        approved_by: None,
+        burned_at: None,
+        burned_by: None,
+        soulbound: false,
+        reveal_commitment: None,
+        user: None,
+        user_expires: None,
+    };
+    token_metadata.reveal_commitment = reveal::commit(token_identifier, token_metadata.class());
+    ledger.add_token_metadata(token_identifier.clone(), token_metadata);
+    ledger.update_owner_cache(token_identifier, None, Some(to));
+    ledger.update_operator_cache(token_identifier, None, Some(caller));
+    certification::update(ledger, token_identifier, &[Some(to)]);
+    icrc3::append(
+        ledger,
+        "7mint",
+        vec![("tid", icrc3::token(token_identifier)), ("to", icrc3::account(to))],
+    );
 
-        Ok(Nat::from(ledger.inc_tx() - 1))
-    })
+    Ok(())
 }
 
 pub fn dip721_burn(token_identifier: Token_ID) -> Result<Nat, NftError> {
     ledger::with_mut(|ledger| {
-        let caller = ic_cdk::api::caller();
-        let old_owner = match ledger.owner_of(&token_identifier).ok() {
-            Some(owner) => owner,
-            None => return Err(NftError::OwnerNotFound),
-        };
-        if old_owner.ne(&Some(caller)) {
-            insert_sync(IndefiniteEvent {
-                caller: ic_cdk::api::caller(),
-                operation: "verify old owner".into(),
-                details: vec![(
-                    "unauthozied owner".into(),
-                    DetailValue::from(caller.clone()),
-                )],
-            });
-            return Err(NftError::UnauthorizedOwner);
-        }
-        let old_operator = match ledger.operator_of(&token_identifier).ok() {
-            Some(operator) => operator,
-            None => return Err(NftError::OperatorNotFound),
-        };
-        ledger.update_owner_cache(&token_identifier, old_owner, None);
-        ledger.update_operator_cache(&token_identifier, old_operator, None);
-        ledger.burn(caller, &token_identifier);
+        let caller = env::caller();
+        burn_token(ledger, caller, &token_identifier)?;
 
         insert_sync(IndefiniteEvent {
             caller,
@@ -217,45 +418,66 @@ pub fn dip721_burn(token_identifier: Token_ID) -> Result<Nat, NftError> {
             )],
         });
 
-        Ok(Nat::from(ledger.inc_tx() - 1))
+        Ok(ledger.inc_tx() - 1)
     })
 }
 
+// Burn counterpart of `mint_token`.
+pub fn burn_token(
+    ledger: &mut ledger::Ledger,
+    caller: Principal,
+    token_identifier: &Token_ID,
+) -> Result<(), NftError> {
+    if ledger.is_paused(&Operation::Burn) {
+        return Err(NftError::OperationPaused(Operation::Burn));
+    }
+    if ledger.is_locked(token_identifier) {
+        return Err(NftError::TokenLocked);
+    }
+    if ledger.has_pending_sale(token_identifier) {
+        return Err(NftError::SalePending);
+    }
+    let old_owner = match ledger.owner_of(token_identifier).ok() {
+        Some(owner) => owner,
+        None => return Err(NftError::OwnerNotFound),
+    };
+    // Burners may only burn tokens they have been approved for; the role alone
+    // does not reach into other holders' wallets.
+    let is_burner = ledger.has_role(&caller, &Role::Burner)
+        && icrc37::is_approved_for(ledger, &caller, token_identifier);
+    if old_owner.ne(&Some(caller)) && !is_burner {
+        diagnostics::log(
+            caller,
+            "verify old owner",
+            vec![("unauthozied owner".into(), DetailValue::from(caller))],
+        );
+        return Err(NftError::UnauthorizedOwner);
+    }
+    let old_operator = match ledger.operator_of(token_identifier).ok() {
+        Some(operator) => operator,
+        None => return Err(NftError::OperatorNotFound),
+    };
+    ledger.update_owner_cache(token_identifier, old_owner, None);
+    ledger.update_operator_cache(token_identifier, old_operator, None);
+    ledger.burn(caller, token_identifier);
+    certification::update(ledger, token_identifier, &[old_owner]);
+
+    let mut tx = vec![("tid", icrc3::token(token_identifier))];
+    if let Some(from) = old_owner {
+        tx.push(("from", icrc3::account(from)));
+    }
+    icrc3::append(ledger, "7burn", tx);
+
+    Ok(())
+}
+
 pub fn dip721_approve(
     operator: Principal,
     token_identifier: Token_ID,
 ) -> Result<Nat, NftError> {
     ledger::with_mut(|ledger| {
-        let caller = ic_cdk::api::caller();
-        if operator.eq(&caller) {
-            insert_sync(IndefiniteEvent {
-                caller: ic_cdk::api::caller(),
-                operation: "verify caller".into(),
-                details: vec![("operator".into(), DetailValue::from(operator.to_string()))],
-            });
-            return Err(NftError::SelfApprove);
-        };
-        let owner = match ledger.owner_of(&token_identifier).ok() {
-            Some(owner) => owner,
-            None => return Err(NftError::OwnerNotFound),
-        };
-        if owner.ne(&Some(caller)) {
-            insert_sync(IndefiniteEvent {
-                caller: ic_cdk::api::caller(),
-                operation: "verify owner".into(),
-                details: vec![(
-                    "owner".into(),
-                    DetailValue::from(owner.unwrap().to_string()),
-                )],
-            });
-            return Err(NftError::UnauthorizedOwner);
-        }
-        ledger.update_operator_cache(
-            &token_identifier,
-            ledger.operator_of(&token_identifier)?,
-            Some(operator),
-        );
-        ledger.approve(caller, &token_identifier, Some(operator));
+        let caller = env::caller();
+        approve_token(ledger, caller, operator, &token_identifier, None)?;
 
         insert_sync(IndefiniteEvent {
             caller,
@@ -269,18 +491,221 @@ pub fn dip721_approve(
             ],
         });
 
-        Ok(Nat::from(ledger.inc_tx() - 1))
+        Ok(ledger.inc_tx() - 1)
+    })
+}
+
+// Approval counterpart of `mint_token`: makes `operator` the token's operator.
+// `approval` carries the ICRC-37 details such as expiry, when there are any.
+// Returns the index of the ICRC-3 block recording the approval.
+pub fn approve_token(
+    ledger: &mut ledger::Ledger,
+    caller: Principal,
+    operator: Principal,
+    token_identifier: &Token_ID,
+    approval: Option<ApprovalInfo>,
+) -> Result<Nat, NftError> {
+    if ledger.is_paused(&Operation::Approve) {
+        return Err(NftError::OperationPaused(Operation::Approve));
+    }
+    if ledger.is_locked(token_identifier) {
+        return Err(NftError::TokenLocked);
+    }
+    if operator.eq(&caller) {
+        diagnostics::log(
+            caller,
+            "verify caller",
+            vec![("operator".into(), DetailValue::from(operator.to_string()))],
+        );
+        return Err(NftError::SelfApprove);
+    };
+    let owner = match ledger.owner_of(token_identifier).ok() {
+        Some(owner) => owner,
+        None => return Err(NftError::OwnerNotFound),
+    };
+    if owner.ne(&Some(caller)) {
+        diagnostics::log(
+            caller,
+            "verify owner",
+            owner
+                .map(|owner| ("owner".into(), DetailValue::from(owner.to_string())))
+                .into_iter()
+                .collect(),
+        );
+        return Err(NftError::UnauthorizedOwner);
+    }
+    if is_soulbound(ledger.token_metadata(token_identifier)?) {
+        return Err(NftError::NonTransferable);
+    }
+    ledger.update_operator_cache(
+        token_identifier,
+        ledger.operator_of(token_identifier)?,
+        Some(operator),
+    );
+    ledger.approve(caller, token_identifier, Some(operator));
+
+    let mut tx = vec![
+        ("tid", icrc3::token(token_identifier)),
+        ("from", icrc3::account(caller)),
+        ("spender", icrc3::account(operator)),
+    ];
+    if let Some(expires_at) = approval.as_ref().and_then(|approval| approval.expires_at) {
+        tx.push(("exp", Value::Nat(Nat::from(expires_at))));
+    }
+    let block_index = icrc3::append(ledger, "37approve", tx);
+    match approval {
+        Some(approval) => ledger.approvals.insert(token_identifier.clone(), approval),
+        None => ledger.approvals.remove(token_identifier),
+    };
+
+    Ok(block_index)
+}
+
+// ERC-4907 style rental: the owner or operator lends the token to `user` until
+// `expires`, without giving up ownership. Passing `None` ends the rental early.
+pub fn dip721_set_user(
+    token_identifier: Token_ID,
+    user: Option<Principal>,
+    expires: u64,
+) -> Result<(), NftError> {
+    ledger::with_mut(|ledger| {
+        let caller = env::caller();
+        let owner = ledger.owner_of(&token_identifier)?;
+        let operator = ledger.operator_of(&token_identifier)?;
+        if owner.ne(&Some(caller)) && operator.ne(&Some(caller)) {
+            return Err(NftError::UnauthorizedOperator);
+        }
+        if user.is_some() && expires <= time() {
+            return Err(NftError::InvalidExpiry);
+        }
+        let expires = user.map(|_| expires);
+        ledger.set_user(&token_identifier, user, expires);
+
+        let mut details = vec![(
+            "token_identifier".into(),
+            DetailValue::from(token_identifier.to_string()),
+        )];
+        if let (Some(user), Some(expires)) = (user, expires) {
+            details.push(("user".into(), DetailValue::from(user)));
+            details.push(("expires".into(), DetailValue::U64(expires)));
+        }
+        insert_sync(IndefiniteEvent {
+            caller,
+            operation: "setUser".into(),
+            details,
+        });
+
+        Ok(())
+    })
+}
+
+pub fn dip721_user_of(token_identifier: Token_ID) -> Result<Option<Principal>, NftError> {
+    ledger::with(|ledger| {
+        ledger
+            .token_metadata(&token_identifier)
+            .map(|token_metadata| token_metadata.active_user(time()))
+    })
+}
+
+pub fn dip721_user_expires(token_identifier: Token_ID) -> Result<Option<u64>, NftError> {
+    ledger::with(|ledger| {
+        ledger.token_metadata(&token_identifier).map(|token_metadata| {
+            token_metadata
+                .active_user(time())
+                .and(token_metadata.user_expires)
+        })
+    })
+}
+
+// A token is soulbound either on its own or because its whole class is.
+pub fn is_soulbound(token_metadata: &TokenMetaData) -> bool {
+    token_metadata.soulbound
+        || token_metadata
+            .class()
+            .map_or(false, |class| prop::is_soulbound_class(class))
+}
+
+pub fn dip721_is_transferable(token_identifier: Token_ID) -> Result<bool, NftError> {
+    ledger::with(|ledger| {
+        ledger
+            .token_metadata(&token_identifier)
+            .map(|token_metadata| !token_metadata.is_burned && !is_soulbound(token_metadata))
+    })
+}
+
+pub fn dip721_set_token_soulbound(
+    token_identifier: Token_ID,
+    soulbound: bool,
+) -> Result<(), NftError> {
+    ledger::with_mut(|ledger| {
+        let caller = env::caller();
+        if !ledger.has_role(&caller, &Role::MetadataEditor) {
+            return Err(NftError::MissingRole(Role::MetadataEditor));
+        }
+        ledger.token_metadata(&token_identifier)?;
+        ledger.set_soulbound(&token_identifier, soulbound);
+
+        insert_sync(IndefiniteEvent {
+            caller,
+            operation: "setTokenSoulbound".into(),
+            details: vec![
+                (
+                    "token_identifier".into(),
+                    DetailValue::from(token_identifier.to_string()),
+                ),
+                (
+                    "soulbound".into(),
+                    if soulbound { DetailValue::True } else { DetailValue::False },
+                ),
+            ],
+        });
+
+        Ok(())
     })
 }
 
+pub fn dip721_set_class_soulbound(class: String, soulbound: bool) -> Result<(), NftError> {
+    let caller = env::caller();
+    if !ledger::with(|ledger| ledger.has_role(&caller, &Role::MetadataEditor)) {
+        return Err(NftError::MissingRole(Role::MetadataEditor));
+    }
+    prop::with_mut(|props| {
+        props
+            .iter_mut()
+            .find(|p| *p.class() == class)
+            .map(|p| p.set_soulbound(soulbound))
+            .ok_or_else(|| NftError::ClassNotFound(class.clone()))
+    })?;
+
+    insert_sync(IndefiniteEvent {
+        caller,
+        operation: "setClassSoulbound".into(),
+        details: vec![
+            ("class".into(), DetailValue::from(class)),
+            (
+                "soulbound".into(),
+                if soulbound { DetailValue::True } else { DetailValue::False },
+            ),
+        ],
+    });
+
+    Ok(())
+}
+
 pub fn dip721_token_metadata(token_identifier: Token_ID) -> Result<TokenMetaData, NftError> {
     ledger::with(|ledger| ledger.token_metadata(&token_identifier).cloned())
 }
 
-pub fn find_class_of_token_metadata(token_identifier: Token_ID) -> GeneralValue {
-    ledger::with(|ledger| {let general_value = ledger.token_metadata(&token_identifier).unwrap().properties.iter().find(|item| item.0 == "class".to_string());
-                        general_value.unwrap().1.clone()}
-)
+pub fn find_class_of_token_metadata(token_identifier: Token_ID) -> Option<GeneralValue> {
+    ledger::with(|ledger| {
+        ledger
+            .token_metadata(&token_identifier)
+            .ok()?
+            .properties
+            .iter()
+            .find(|item| item.0 == "class")
+            .map(|item| item.1.clone())
+    })
 }
 
 pub fn dip721_owner_token_identifiers(
@@ -292,3 +717,164 @@ pub fn dip721_owner_token_identifiers(
 
 
 //pub fn dip721_owner_of(token_identifier: )
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::testing::{self, owner_of, principal};
+
+    #[test]
+    fn owners_can_burn_their_tokens() {
+        testing::setup();
+        let token = testing::mint(principal(1));
+
+        env::set_caller(principal(2));
+        assert!(matches!(
+            dip721_burn(token.clone()),
+            Err(NftError::UnauthorizedOwner)
+        ));
+        env::set_caller(principal(1));
+        dip721_burn(token.clone()).unwrap();
+        assert_eq!(owner_of(&token), None);
+    }
+
+    #[test]
+    fn burners_can_only_burn_tokens_they_are_approved_for() {
+        testing::setup();
+        let burner = principal(2);
+        testing::grant(burner, Role::Burner);
+        let token = testing::mint(principal(1));
+
+        env::set_caller(burner);
+        assert!(matches!(
+            dip721_burn(token.clone()),
+            Err(NftError::UnauthorizedOwner)
+        ));
+
+        env::set_caller(principal(1));
+        dip721_approve(burner, token.clone()).unwrap();
+        env::set_caller(burner);
+        dip721_burn(token.clone()).unwrap();
+        assert_eq!(owner_of(&token), None);
+    }
+
+    #[test]
+    fn approval_without_the_burner_role_is_not_enough() {
+        testing::setup();
+        let operator = principal(2);
+        let token = testing::mint(principal(1));
+
+        env::set_caller(principal(1));
+        dip721_approve(operator, token.clone()).unwrap();
+        env::set_caller(operator);
+        assert!(matches!(
+            dip721_burn(token.clone()),
+            Err(NftError::UnauthorizedOwner)
+        ));
+        assert_eq!(owner_of(&token), Some(principal(1)));
+    }
+
+    #[test]
+    fn pausers_pause_and_unpause_operations() {
+        testing::setup();
+        let pauser = principal(2);
+        testing::grant(pauser, Role::Pauser);
+        env::set_caller(pauser);
+
+        dip721_set_paused(Operation::Mint, true).unwrap();
+        assert!(dip721_is_paused(Operation::Mint));
+        assert_eq!(dip721_paused_operations(), vec![Operation::Mint]);
+
+        dip721_set_paused(Operation::Mint, false).unwrap();
+        assert!(!dip721_is_paused(Operation::Mint));
+    }
+
+    #[test]
+    fn pausing_needs_the_pauser_role() {
+        testing::setup();
+        env::set_caller(principal(2));
+
+        assert!(matches!(
+            dip721_set_paused(Operation::Transfer, true),
+            Err(NftError::MissingRole(Role::Pauser))
+        ));
+        assert!(!dip721_is_paused(Operation::Transfer));
+    }
+
+    #[test]
+    fn paused_operations_are_refused() {
+        testing::setup();
+        let owner = principal(1);
+        let token = testing::mint(owner);
+        for operation in [Operation::Mint, Operation::Transfer, Operation::Approve, Operation::Burn] {
+            ledger::with_mut(|ledger| ledger.set_paused(operation, true));
+        }
+        env::set_caller(owner);
+
+        assert!(matches!(
+            mint_next(owner, owner, prop::properties_of("H")),
+            Err(NftError::OperationPaused(Operation::Mint))
+        ));
+        assert!(matches!(
+            ledger::with_mut(|ledger| transfer_token(ledger, owner, &token, principal(2))),
+            Err(NftError::OperationPaused(Operation::Transfer))
+        ));
+        assert!(matches!(
+            dip721_approve(principal(2), token.clone()),
+            Err(NftError::OperationPaused(Operation::Approve))
+        ));
+        assert!(matches!(
+            dip721_burn(token.clone()),
+            Err(NftError::OperationPaused(Operation::Burn))
+        ));
+        assert_eq!(owner_of(&token), Some(owner));
+    }
+
+    #[test]
+    fn the_user_role_lapses_at_its_expiry() {
+        testing::setup();
+        let owner = principal(1);
+        let token = testing::mint(owner);
+        let expires = time() + 100;
+
+        env::set_caller(owner);
+        dip721_set_user(token.clone(), Some(principal(2)), expires).unwrap();
+        assert_eq!(dip721_user_of(token.clone()).unwrap(), Some(principal(2)));
+        assert_eq!(dip721_user_expires(token.clone()).unwrap(), Some(expires));
+
+        env::set_time(expires);
+        assert_eq!(dip721_user_of(token.clone()).unwrap(), None);
+        assert_eq!(dip721_user_expires(token).unwrap(), None);
+    }
+
+    #[test]
+    fn transfers_clear_the_user() {
+        testing::setup();
+        let owner = principal(1);
+        let token = testing::mint(owner);
+        env::set_caller(owner);
+        dip721_set_user(token.clone(), Some(principal(2)), time() + 100).unwrap();
+
+        ledger::with_mut(|ledger| transfer_token(ledger, owner, &token, principal(3))).unwrap();
+        assert_eq!(dip721_user_of(token.clone()).unwrap(), None);
+        assert_eq!(dip721_user_expires(token).unwrap(), None);
+    }
+
+    #[test]
+    fn only_the_owner_or_operator_sets_an_unexpired_user() {
+        testing::setup();
+        let token = testing::mint(principal(1));
+
+        env::set_caller(principal(2));
+        assert!(matches!(
+            dip721_set_user(token.clone(), Some(principal(2)), time() + 100),
+            Err(NftError::UnauthorizedOperator)
+        ));
+        env::set_caller(principal(1));
+        assert!(matches!(
+            dip721_set_user(token.clone(), Some(principal(2)), time()),
+            Err(NftError::InvalidExpiry)
+        ));
+        assert_eq!(dip721_user_of(token).unwrap(), None);
+    }
+}
@@ -0,0 +1,119 @@
+// The parts of the system API the modules depend on. Inside a canister these
+// forward to `ic_cdk::api`; unit tests run natively, where the system API is
+// unavailable, so there they read per-thread stand-ins the tests control.
+
+pub use imp::*;
+
+#[cfg(not(test))]
+mod imp {
+    use ic_cdk::export::Principal;
+
+    pub fn time() -> u64 {
+        ic_cdk::api::time()
+    }
+
+    pub fn caller() -> Principal {
+        ic_cdk::api::caller()
+    }
+
+    pub fn id() -> Principal {
+        ic_cdk::api::id()
+    }
+
+    pub fn set_certified_data(data: &[u8]) {
+        ic_cdk::api::set_certified_data(data)
+    }
+
+    pub fn data_certificate() -> Option<Vec<u8>> {
+        ic_cdk::api::data_certificate()
+    }
+
+    pub fn instruction_counter() -> u64 {
+        ic_cdk::api::instruction_counter()
+    }
+}
+
+#[cfg(test)]
+mod imp {
+    use ic_cdk::export::Principal;
+    use std::cell::{Cell, RefCell};
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+    thread_local! {
+        static TIME: Cell<u64> = const { Cell::new(1_000_000_000) };
+        static CALLER: Cell<Principal> = const { Cell::new(Principal::anonymous()) };
+        static CERTIFIED_DATA: RefCell<Vec<u8>> = const { RefCell::new(vec![]) };
+        static CERTIFICATE: RefCell<Option<Vec<u8>>> = const { RefCell::new(None) };
+        static INSTRUCTIONS: Cell<u64> = const { Cell::new(0) };
+    }
+
+    pub fn time() -> u64 {
+        TIME.with(|time| time.get())
+    }
+
+    pub fn caller() -> Principal {
+        CALLER.with(|caller| caller.get())
+    }
+
+    pub fn id() -> Principal {
+        Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 1, 1, 1])
+    }
+
+    pub fn set_certified_data(data: &[u8]) {
+        CERTIFIED_DATA.with(|certified| *certified.borrow_mut() = data.to_vec())
+    }
+
+    pub fn data_certificate() -> Option<Vec<u8>> {
+        CERTIFICATE.with(|certificate| certificate.borrow().clone())
+    }
+
+    pub fn instruction_counter() -> u64 {
+        INSTRUCTIONS.with(|instructions| instructions.get())
+    }
+
+    pub fn set_time(now: u64) {
+        TIME.with(|time| time.set(now))
+    }
+
+    pub fn advance_time(by: u64) {
+        TIME.with(|time| time.set(time.get() + by))
+    }
+
+    pub fn set_caller(principal: Principal) {
+        CALLER.with(|caller| caller.set(principal))
+    }
+
+    pub fn certified_data() -> Vec<u8> {
+        CERTIFIED_DATA.with(|certified| certified.borrow().clone())
+    }
+
+    pub fn set_data_certificate(certificate: Option<Vec<u8>>) {
+        CERTIFICATE.with(|current| *current.borrow_mut() = certificate)
+    }
+
+    pub fn set_instruction_counter(count: u64) {
+        INSTRUCTIONS.with(|instructions| instructions.set(count))
+    }
+
+    // Drives a future that never actually suspends, which holds for every
+    // async path once inter-canister calls are replaced by stand-ins.
+    pub fn block_on<F: Future>(future: F) -> F::Output {
+        fn noop_raw_waker() -> RawWaker {
+            fn clone(_: *const ()) -> RawWaker {
+                noop_raw_waker()
+            }
+            fn noop(_: *const ()) {}
+            static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+        let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+        let mut context = Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        match Pin::as_mut(&mut future).poll(&mut context) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("future suspended outside a canister"),
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use crate::module::env;
+use ic_cdk::export::candid::CandidType;
+use ic_cdk::export::Principal;
+use ic_ledger_types::{
+    AccountIdentifier, Memo, Subaccount, Timestamp, Tokens, TransferError, DEFAULT_FEE,
+    DEFAULT_SUBACCOUNT,
+};
+use sha2::{Digest, Sha256};
+
+#[cfg(not(test))]
+use remote::{account_balance, call_transfer};
+#[cfg(test)]
+use stand_in::{account_balance, call_transfer};
+
+// Mirrors the ICP ledger's `TransferArgs`, with the destination as a raw blob
+// so payouts can go to any account identifier, not only to principals.
+#[derive(CandidType)]
+struct TransferArgs {
+    memo: Memo,
+    amount: Tokens,
+    fee: Tokens,
+    from_subaccount: Option<Subaccount>,
+    to: Vec<u8>,
+    created_at_time: Option<Timestamp>,
+}
+
+// The ICP ledger deduplicates transfers created within the last 24 hours and
+// rejects older ones as too old.
+const DEDUP_WINDOW: u64 = 24 * 60 * 60 * 1_000_000_000;
+// Leeway for the time a call spends in flight before the ledger sees it.
+const DEDUP_MARGIN: u64 = 10 * 60 * 1_000_000_000;
+
+pub fn fee() -> u64 {
+    DEFAULT_FEE.e8s()
+}
+
+// Whether a transfer created at `created_at_time` can still be sent as is,
+// relying on the ledger to deduplicate it.
+pub fn deduplicates(created_at_time: u64, now: u64) -> bool {
+    created_at_time.saturating_add(DEDUP_WINDOW) > now.saturating_add(DEDUP_MARGIN)
+}
+
+pub fn default_account(principal: &Principal) -> String {
+    AccountIdentifier::new(principal, &DEFAULT_SUBACCOUNT).to_string()
+}
+
+// A subaccount of this canister dedicated to one purpose, e.g. a single sale.
+pub fn derive_subaccount(domain: &[u8], parts: &[&[u8]]) -> Subaccount {
+    let mut hasher = Sha256::new();
+    hasher.update([domain.len() as u8]);
+    hasher.update(domain);
+    parts.iter().for_each(|part| hasher.update(part));
+    Subaccount(hasher.finalize().into())
+}
+
+pub fn canister_account(subaccount: &Subaccount) -> String {
+    AccountIdentifier::new(&env::id(), subaccount).to_string()
+}
+
+pub fn decode_account(account: &str) -> Option<Vec<u8>> {
+    if account.len() != 64 {
+        return None;
+    }
+    (0..account.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&account[i..i + 2], 16).ok())
+        .collect()
+}
+
+pub async fn balance_of(subaccount: &Subaccount) -> Result<u64, String> {
+    account_balance(AccountIdentifier::new(&env::id(), subaccount)).await
+}
+
+// Sends `amount` e8s (before the ledger fee is taken) from one of this
+// canister's subaccounts to the hex account identifier `to`. The ledger
+// deduplicates on `memo` and `created_at_time`, so retrying with the same pair
+// returns the block of the transfer that already went through.
+pub async fn transfer(
+    from_subaccount: Subaccount,
+    to: &str,
+    amount: u64,
+    memo: u64,
+    created_at_time: u64,
+) -> Result<u64, String> {
+    let to = decode_account(to).ok_or_else(|| format!("invalid account identifier {}", to))?;
+    let args = TransferArgs {
+        memo: Memo(memo),
+        amount: Tokens::from_e8s(amount - fee()),
+        fee: DEFAULT_FEE,
+        from_subaccount: Some(from_subaccount),
+        to,
+        created_at_time: Some(Timestamp {
+            timestamp_nanos: created_at_time,
+        }),
+    };
+    match call_transfer(args).await? {
+        Ok(block_index) => Ok(block_index),
+        Err(TransferError::TxDuplicate { duplicate_of }) => Ok(duplicate_of),
+        Err(err) => Err(format!("transfer rejected: {:?}", err)),
+    }
+}
+
+#[cfg(not(test))]
+mod remote {
+    use super::TransferArgs;
+    use crate::module::ledger;
+    use ic_cdk::export::Principal;
+    use ic_ledger_types::{
+        AccountBalanceArgs, AccountIdentifier, TransferResult, MAINNET_LEDGER_CANISTER_ID,
+    };
+
+    // The ledger canister payments are checked against. Defaults to the mainnet
+    // ICP ledger and can be pointed at a local stand-in through `InitArgs`.
+    fn ledger_canister() -> Principal {
+        ledger::with(|ledger| {
+            ledger
+                .metadata
+                .icp_ledger
+                .unwrap_or(MAINNET_LEDGER_CANISTER_ID)
+        })
+    }
+
+    pub async fn account_balance(account: AccountIdentifier) -> Result<u64, String> {
+        ic_ledger_types::account_balance(ledger_canister(), AccountBalanceArgs { account })
+            .await
+            .map(|tokens| tokens.e8s())
+            .map_err(|(code, msg)| format!("account_balance failed: {:?} {}", code, msg))
+    }
+
+    pub(super) async fn call_transfer(args: TransferArgs) -> Result<TransferResult, String> {
+        let (result,): (TransferResult,) = ic_cdk::call(ledger_canister(), "transfer", (args,))
+            .await
+            .map_err(|(code, msg)| format!("transfer failed: {:?} {}", code, msg))?;
+        Ok(result)
+    }
+}
+
+// An in-memory ledger for unit tests. It keeps balances per account, applies
+// the fee and deduplicates on the same fields as the ICP ledger.
+#[cfg(test)]
+pub mod stand_in {
+    use super::{fee, TransferArgs, DEDUP_WINDOW};
+    use crate::module::env;
+    use ic_ledger_types::{
+        AccountIdentifier, Tokens, TransferError, TransferResult, DEFAULT_SUBACCOUNT,
+    };
+    use std::cell::{Cell, RefCell};
+    use std::collections::HashMap;
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct Transfer {
+        pub from: String,
+        pub to: String,
+        pub amount: u64,
+        pub memo: u64,
+        pub created_at_time: Option<u64>,
+    }
+
+    thread_local! {
+        static BALANCES: RefCell<HashMap<String, u64>> = RefCell::new(HashMap::new());
+        static BLOCKS: RefCell<Vec<Transfer>> = const { RefCell::new(vec![]) };
+        static UNAVAILABLE: Cell<bool> = const { Cell::new(false) };
+        static LOSE_NEXT_REPLY: Cell<bool> = const { Cell::new(false) };
+        static REJECT_NEXT_TRANSFER: Cell<bool> = const { Cell::new(false) };
+    }
+
+    pub fn deposit(account: &str, amount: u64) {
+        BALANCES.with(|balances| *balances.borrow_mut().entry(account.into()).or_default() += amount)
+    }
+
+    pub fn balance(account: &str) -> u64 {
+        BALANCES.with(|balances| balances.borrow().get(account).copied().unwrap_or(0))
+    }
+
+    pub fn transfers() -> Vec<Transfer> {
+        BLOCKS.with(|blocks| blocks.borrow().clone())
+    }
+
+    // Rejects every call, as if the ledger could not be reached.
+    pub fn set_unavailable(unavailable: bool) {
+        UNAVAILABLE.with(|flag| flag.set(unavailable))
+    }
+
+    // Applies the next transfer but reports a failure, as if the reply was lost.
+    pub fn lose_next_reply() {
+        LOSE_NEXT_REPLY.with(|flag| flag.set(true))
+    }
+
+    // Refuses the next transfer without applying it.
+    pub fn reject_next_transfer() {
+        REJECT_NEXT_TRANSFER.with(|flag| flag.set(true))
+    }
+
+    fn unavailable() -> Result<(), String> {
+        if UNAVAILABLE.with(|flag| flag.get()) {
+            return Err("stand-in ledger is unavailable".into());
+        }
+        Ok(())
+    }
+
+    pub async fn account_balance(account: AccountIdentifier) -> Result<u64, String> {
+        unavailable()?;
+        Ok(balance(&account.to_string()))
+    }
+
+    pub(super) async fn call_transfer(args: TransferArgs) -> Result<TransferResult, String> {
+        unavailable()?;
+        if REJECT_NEXT_TRANSFER.with(|flag| flag.replace(false)) {
+            return Err("transfer failed: rejected".into());
+        }
+        let from = AccountIdentifier::new(
+            &env::id(),
+            &args.from_subaccount.unwrap_or(DEFAULT_SUBACCOUNT),
+        )
+        .to_string();
+        let to = args.to.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+        let transfer = Transfer {
+            from,
+            to,
+            amount: args.amount.e8s(),
+            memo: args.memo.0,
+            created_at_time: args
+                .created_at_time
+                .map(|created_at_time| created_at_time.timestamp_nanos),
+        };
+
+        if transfer
+            .created_at_time
+            .map_or(false, |created_at_time| created_at_time + DEDUP_WINDOW < env::time())
+        {
+            return Ok(Err(TransferError::TxTooOld {
+                allowed_window_nanos: DEDUP_WINDOW,
+            }));
+        }
+        let duplicate_of = BLOCKS.with(|blocks| {
+            blocks
+                .borrow()
+                .iter()
+                .position(|block| transfer.created_at_time.is_some() && *block == transfer)
+        });
+        if let Some(duplicate_of) = duplicate_of {
+            return Ok(Err(TransferError::TxDuplicate {
+                duplicate_of: duplicate_of as u64,
+            }));
+        }
+        let debit = transfer.amount + fee();
+        let available = balance(&transfer.from);
+        if available < debit {
+            return Ok(Err(TransferError::InsufficientFunds {
+                balance: Tokens::from_e8s(available),
+            }));
+        }
+
+        BALANCES.with(|balances| {
+            let mut balances = balances.borrow_mut();
+            *balances.entry(transfer.from.clone()).or_default() -= debit;
+            *balances.entry(transfer.to.clone()).or_default() += transfer.amount;
+        });
+        let index = BLOCKS.with(|blocks| {
+            let mut blocks = blocks.borrow_mut();
+            blocks.push(transfer);
+            blocks.len() as u64 - 1
+        });
+        if LOSE_NEXT_REPLY.with(|flag| flag.replace(false)) {
+            return Err("transfer failed: reply lost".into());
+        }
+        Ok(Ok(index))
+    }
+}
@@ -0,0 +1,518 @@
+use crate::module::certification;
+use crate::module::env::time;
+use crate::module::ledger::{self, Ledger};
+use crate::module::rbac;
+use crate::module::types::{
+    ArchiveInfo, ArchivedBlocks, BlockWithId, DataCertificate, GetArchivesArgs, GetBlocksArgs,
+    GetBlocksCallback, GetBlocksResult, NftError, Role, SupportedBlockType, Token_ID, Value,
+};
+use ic_cdk::export::candid::{Func, Nat};
+use ic_cdk::export::Principal;
+use ic_certified_map::{fork, labeled, Hash, HashTree};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cell::Cell;
+
+#[cfg(not(test))]
+use remote::call_append_blocks;
+#[cfg(test)]
+use stand_in::call_append_blocks;
+
+// Most blocks returned by one `icrc3_get_blocks` call.
+pub const MAX_BLOCKS_PER_RESPONSE: u64 = 100;
+// Blocks kept in the canister. The log lives on the heap and is saved on every
+// upgrade, so the heartbeat moves older blocks to the block archive and
+// `icrc3_get_blocks` sends clients there for them. Without an archive nothing
+// is dropped.
+pub const MAX_RETAINED_BLOCKS: usize = 20_000;
+// Most blocks moved to the archive in one call.
+const ARCHIVE_BATCH: usize = 1_000;
+// An archiving call still marked as running after this long is assumed to have
+// trapped in its callback, and archiving may start again.
+const ARCHIVE_TIMEOUT: u64 = 10 * 60 * 1_000_000_000;
+
+thread_local! {
+    static ARCHIVING_SINCE: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+const BLOCK_TYPES: [&str; 8] = [
+    "7mint",
+    "7burn",
+    "7xfer",
+    "37approve",
+    "37approve_coll",
+    "37revoke",
+    "37revoke_coll",
+    "37xfer",
+];
+
+// The representation-independent hash of ICRC-3.
+pub fn hash_value(value: &Value) -> Hash {
+    let mut hasher = Sha256::new();
+    match value {
+        Value::Nat(nat) => {
+            let mut bytes = vec![];
+            nat.encode(&mut bytes).expect("failed to encode nat");
+            hasher.update(bytes);
+        }
+        Value::Int(int) => {
+            let mut bytes = vec![];
+            int.encode(&mut bytes).expect("failed to encode int");
+            hasher.update(bytes);
+        }
+        Value::Text(text) => hasher.update(text.as_bytes()),
+        Value::Blob(blob) => hasher.update(blob),
+        Value::Array(values) => values
+            .iter()
+            .for_each(|value| hasher.update(hash_value(value))),
+        Value::Map(entries) => {
+            let mut pairs = entries
+                .iter()
+                .map(|(key, value)| {
+                    let mut pair = Sha256::digest(key.as_bytes()).to_vec();
+                    pair.extend_from_slice(&hash_value(value));
+                    pair
+                })
+                .collect::<Vec<_>>();
+            pairs.sort();
+            pairs.iter().for_each(|pair| hasher.update(pair));
+        }
+    }
+    hasher.finalize().into()
+}
+
+pub fn account(principal: Principal) -> Value {
+    Value::Array(vec![Value::Blob(principal.as_slice().to_vec())])
+}
+
+pub fn token(token_identifier: &Token_ID) -> Value {
+    Value::Nat(token_identifier.clone())
+}
+
+// Appends a block of type `btype` chained to the previous one, certifies the
+// new tip together with the ownership trees and returns the block's index.
+pub fn append(ledger: &mut Ledger, btype: &str, tx: Vec<(&str, Value)>) -> Nat {
+    let mut block = vec![
+        ("btype".to_string(), Value::Text(btype.to_string())),
+        ("ts".to_string(), Value::Nat(Nat::from(time()))),
+        (
+            "tx".to_string(),
+            Value::Map(
+                tx.into_iter()
+                    .map(|(key, value)| (key.to_string(), value))
+                    .collect(),
+            ),
+        ),
+    ];
+    if let Some(phash) = &ledger.last_block_hash {
+        block.push(("phash".to_string(), Value::Blob(phash.clone())));
+    }
+    let block = Value::Map(block);
+    ledger.last_block_hash = Some(hash_value(&block).to_vec());
+    ledger.blocks.push_back(block);
+    certification::certify(ledger);
+    Nat::from(log_length(ledger) - 1)
+}
+
+// Number of blocks ever appended, including archived ones.
+fn log_length(ledger: &Ledger) -> u64 {
+    ledger.first_block_index + ledger.blocks.len() as u64
+}
+
+fn last_block_index(ledger: &Ledger) -> Option<Vec<u8>> {
+    let index = log_length(ledger).checked_sub(1)?;
+    let mut bytes = vec![];
+    Nat::from(index)
+        .encode(&mut bytes)
+        .expect("failed to encode nat");
+    Some(bytes)
+}
+
+// The `last_block_hash` and `last_block_index` labels of the certified tree.
+pub fn tip(ledger: &Ledger) -> Option<HashTree<'_>> {
+    let hash = ledger.last_block_hash.as_ref()?;
+    let index = last_block_index(ledger)?;
+    Some(fork(
+        labeled(b"last_block_hash", HashTree::Leaf(Cow::Borrowed(hash))),
+        labeled(b"last_block_index", HashTree::Leaf(Cow::Owned(index))),
+    ))
+}
+
+// Sets the canister older blocks are moved to. It must accept
+// `append_blocks : (nat, vec Value) -> ()`, keeping the blocks from the given
+// index on and ignoring those it already holds, and serve `icrc3_get_blocks`
+// for them. Once blocks were moved the archive cannot change, as clients are
+// sent there for them.
+pub fn set_archive(canister: Principal) -> Result<(), NftError> {
+    rbac::ensure_role(Role::Admin)?;
+    ledger::with_mut(|ledger| {
+        if ledger.first_block_index > 0 && ledger.block_archive != Some(canister) {
+            return Err(NftError::ArchiveInUse);
+        }
+        ledger.block_archive = Some(canister);
+        Ok(())
+    })
+}
+
+pub fn get_archives(args: GetArchivesArgs) -> Vec<ArchiveInfo> {
+    ledger::with(|ledger| match ledger.block_archive {
+        Some(canister)
+            if ledger.first_block_index > 0
+                && args.from.map_or(true, |from| from < canister) =>
+        {
+            vec![ArchiveInfo {
+                canister_id: canister,
+                start: Nat::from(0u64),
+                end: Nat::from(ledger.first_block_index - 1),
+            }]
+        }
+        _ => vec![],
+    })
+}
+
+fn is_archiving(now: u64) -> bool {
+    ARCHIVING_SINCE.with(|since| {
+        since
+            .get()
+            .map_or(false, |started_at| started_at.saturating_add(ARCHIVE_TIMEOUT) > now)
+    })
+}
+
+// Called from the heartbeat; starts moving blocks once more than
+// `MAX_RETAINED_BLOCKS` are kept.
+pub fn archive_due() {
+    let due = ledger::with(|ledger| {
+        ledger.block_archive.is_some() && ledger.blocks.len() > MAX_RETAINED_BLOCKS
+    });
+    if due && !is_archiving(time()) {
+        ic_cdk::spawn(async {
+            let _ = archive_oldest().await;
+        });
+    }
+}
+
+// Sends the oldest blocks beyond the cap to the archive and drops them once it
+// has confirmed them. Returns how many blocks were moved.
+async fn archive_oldest() -> Result<u64, String> {
+    let batch = ledger::with(|ledger| {
+        let archive = ledger.block_archive?;
+        let count = ledger
+            .blocks
+            .len()
+            .saturating_sub(MAX_RETAINED_BLOCKS)
+            .min(ARCHIVE_BATCH);
+        if count == 0 {
+            return None;
+        }
+        let blocks = ledger.blocks.iter().take(count).cloned().collect::<Vec<_>>();
+        Some((archive, ledger.first_block_index, blocks))
+    });
+    let (archive, start, blocks) = match batch {
+        Some(batch) => batch,
+        None => return Ok(0),
+    };
+    let count = blocks.len();
+
+    ARCHIVING_SINCE.with(|since| since.set(Some(time())));
+    let result = call_append_blocks(archive, start, blocks).await;
+    ARCHIVING_SINCE.with(|since| since.set(None));
+    result?;
+
+    ledger::with_mut(|ledger| {
+        // The same blocks may have been moved by a run that timed out.
+        if ledger.first_block_index == start {
+            ledger.blocks.drain(..count);
+            ledger.first_block_index += count as u64;
+        }
+    });
+    Ok(count as u64)
+}
+
+pub fn get_blocks(args: Vec<GetBlocksArgs>) -> GetBlocksResult {
+    ledger::with(|ledger| {
+        let log_length = log_length(ledger);
+        let mut remaining = MAX_BLOCKS_PER_RESPONSE;
+        let mut blocks = vec![];
+        let mut archived_blocks = vec![];
+        for arg in args {
+            let start = u64::try_from(&arg.start.0).unwrap_or(u64::MAX);
+            let length = u64::try_from(&arg.length.0).unwrap_or(u64::MAX);
+            let requested_end = start.saturating_add(length).min(log_length);
+            if let Some(archive) = ledger.block_archive {
+                let archived_end = requested_end.min(ledger.first_block_index);
+                if start < archived_end {
+                    archived_blocks.push(ArchivedBlocks {
+                        args: vec![GetBlocksArgs {
+                            start: Nat::from(start),
+                            length: Nat::from(archived_end - start),
+                        }],
+                        callback: GetBlocksCallback(Func {
+                            principal: archive,
+                            method: "icrc3_get_blocks".into(),
+                        }),
+                    });
+                }
+            }
+            // Archived blocks are left to the archive rather than counted
+            // against the limit.
+            let start = start.max(ledger.first_block_index);
+            let end = requested_end.min(start.saturating_add(remaining));
+            for id in start..end {
+                blocks.push(BlockWithId {
+                    id: Nat::from(id),
+                    block: ledger.blocks[(id - ledger.first_block_index) as usize].clone(),
+                });
+            }
+            remaining -= end.saturating_sub(start);
+        }
+        GetBlocksResult {
+            log_length: Nat::from(log_length),
+            blocks,
+            archived_blocks,
+        }
+    })
+}
+
+pub fn get_tip_certificate() -> Option<DataCertificate> {
+    ledger::with(certification::tip_certificate)
+}
+
+pub fn supported_block_types() -> Vec<SupportedBlockType> {
+    BLOCK_TYPES
+        .iter()
+        .map(|block_type| SupportedBlockType {
+            block_type: block_type.to_string(),
+            url: if block_type.starts_with("37") {
+                "https://github.com/dfinity/ICRC/tree/main/ICRCs/ICRC-37".into()
+            } else {
+                "https://github.com/dfinity/ICRC/tree/main/ICRCs/ICRC-7".into()
+            },
+        })
+        .collect()
+}
+
+#[cfg(not(test))]
+mod remote {
+    use crate::module::types::Value;
+    use ic_cdk::export::candid::Nat;
+    use ic_cdk::export::Principal;
+
+    pub async fn call_append_blocks(
+        archive: Principal,
+        start: u64,
+        blocks: Vec<Value>,
+    ) -> Result<(), String> {
+        ic_cdk::call::<_, ()>(archive, "append_blocks", (Nat::from(start), blocks))
+            .await
+            .map_err(|(code, msg)| format!("append_blocks failed: {:?} {}", code, msg))
+    }
+}
+
+// An in-memory archive for unit tests.
+#[cfg(test)]
+pub mod stand_in {
+    use crate::module::types::Value;
+    use ic_cdk::export::Principal;
+    use std::cell::{Cell, RefCell};
+
+    thread_local! {
+        static BLOCKS: RefCell<Vec<Value>> = const { RefCell::new(vec![]) };
+        static UNAVAILABLE: Cell<bool> = const { Cell::new(false) };
+    }
+
+    pub fn blocks() -> Vec<Value> {
+        BLOCKS.with(|blocks| blocks.borrow().clone())
+    }
+
+    // Rejects every call, as if the archive could not be reached.
+    pub fn set_unavailable(unavailable: bool) {
+        UNAVAILABLE.with(|flag| flag.set(unavailable))
+    }
+
+    pub async fn call_append_blocks(
+        _archive: Principal,
+        start: u64,
+        appended: Vec<Value>,
+    ) -> Result<(), String> {
+        if UNAVAILABLE.with(|flag| flag.get()) {
+            return Err("stand-in archive is unavailable".into());
+        }
+        BLOCKS.with(|blocks| {
+            let mut blocks = blocks.borrow_mut();
+            if start > blocks.len() as u64 {
+                return Err(format!("expected block {}, got {}", blocks.len(), start));
+            }
+            let held = blocks.len() - start as usize;
+            blocks.extend(appended.into_iter().skip(held));
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::env;
+    use crate::module::testing::{self, principal};
+
+    fn archive() -> Principal {
+        principal(8)
+    }
+
+    fn hex(hash: Hash) -> String {
+        hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn append_blocks(count: usize) {
+        ledger::with_mut(|ledger| {
+            for _ in 0..count {
+                append(ledger, "7mint", vec![("tid", Value::Nat(Nat::from(1u64)))]);
+            }
+        });
+    }
+
+    fn block_ids(start: u64, length: u64) -> Vec<u64> {
+        get_blocks(vec![GetBlocksArgs {
+            start: start.into(),
+            length: length.into(),
+        }])
+        .blocks
+        .into_iter()
+        .map(|block| u64::try_from(&block.id.0).unwrap())
+        .collect()
+    }
+
+    #[test]
+    fn values_hash_as_the_standard_specifies() {
+        assert_eq!(
+            hex(hash_value(&Value::Nat(Nat::from(42u64)))),
+            "684888c0ebb17f374298b65ee2807526c066094c701bcc7ebbe1c1095f494fc1"
+        );
+        assert_eq!(
+            hex(hash_value(&Value::Text("Hello, World!".into()))),
+            "dffd6021bb2bd5b0af676290809ec3a53191dd81c7f70a4b28688a362182986f"
+        );
+    }
+
+    #[test]
+    fn blocks_are_chained_to_their_predecessor() {
+        testing::setup();
+        append_blocks(2);
+
+        ledger::with(|ledger| {
+            let first = hash_value(&ledger.blocks[0]).to_vec();
+            match &ledger.blocks[1] {
+                Value::Map(entries) => assert!(entries
+                    .iter()
+                    .any(|(key, value)| key == "phash" && *value == Value::Blob(first.clone()))),
+                _ => panic!("block is not a map"),
+            }
+            assert_eq!(
+                ledger.last_block_hash,
+                Some(hash_value(&ledger.blocks[1]).to_vec())
+            );
+        });
+    }
+
+    #[test]
+    fn get_blocks_pages_through_the_log() {
+        testing::setup();
+        append_blocks(5);
+
+        assert_eq!(block_ids(1, 3), vec![1, 2, 3]);
+        assert_eq!(block_ids(3, 10), vec![3, 4]);
+        assert!(block_ids(9, 1).is_empty());
+        assert_eq!(get_blocks(vec![]).log_length, Nat::from(5u64));
+    }
+
+    #[test]
+    fn blocks_are_kept_until_an_archive_takes_them() {
+        testing::setup();
+        append_blocks(MAX_RETAINED_BLOCKS + 3);
+
+        assert_eq!(env::block_on(archive_oldest()), Ok(0));
+        assert_eq!(block_ids(0, 2), vec![0, 1]);
+        assert!(get_blocks(vec![]).archived_blocks.is_empty());
+
+        env::set_caller(testing::custodian());
+        set_archive(archive()).unwrap();
+        stand_in::set_unavailable(true);
+        assert!(env::block_on(archive_oldest()).is_err());
+        assert_eq!(block_ids(0, 2), vec![0, 1]);
+    }
+
+    #[test]
+    fn archived_blocks_are_fetched_from_the_archive() {
+        testing::setup();
+        env::set_caller(testing::custodian());
+        set_archive(archive()).unwrap();
+        append_blocks(MAX_RETAINED_BLOCKS + 3);
+        let first = ledger::with(|ledger| ledger.blocks[0].clone());
+
+        assert_eq!(env::block_on(archive_oldest()), Ok(3));
+        ledger::with(|ledger| {
+            assert_eq!(ledger.blocks.len(), MAX_RETAINED_BLOCKS);
+            assert_eq!(ledger.first_block_index, 3);
+        });
+        assert_eq!(stand_in::blocks().len(), 3);
+        assert_eq!(hash_value(&stand_in::blocks()[0]), hash_value(&first));
+
+        let log_length = (MAX_RETAINED_BLOCKS + 3) as u64;
+        let result = get_blocks(vec![GetBlocksArgs {
+            start: 1u64.into(),
+            length: 4u64.into(),
+        }]);
+        assert_eq!(result.log_length, Nat::from(log_length));
+        assert_eq!(block_ids(1, 4), vec![3, 4]);
+        assert_eq!(result.archived_blocks.len(), 1);
+        let archived = &result.archived_blocks[0];
+        assert_eq!(archived.args[0].start, Nat::from(1u64));
+        assert_eq!(archived.args[0].length, Nat::from(2u64));
+        assert_eq!(archived.callback.0.principal, archive());
+        assert_eq!(archived.callback.0.method, "icrc3_get_blocks");
+
+        let archives = get_archives(GetArchivesArgs { from: None });
+        assert_eq!(archives.len(), 1);
+        assert_eq!(archives[0].end, Nat::from(2u64));
+
+        let next = ledger::with_mut(|ledger| append(ledger, "7burn", vec![]));
+        assert_eq!(next, Nat::from(log_length));
+    }
+
+    #[test]
+    fn the_archive_cannot_change_once_it_holds_blocks() {
+        testing::setup();
+        env::set_caller(principal(1));
+        assert!(matches!(
+            set_archive(archive()),
+            Err(NftError::MissingRole(Role::Admin))
+        ));
+
+        env::set_caller(testing::custodian());
+        set_archive(principal(2)).unwrap();
+        set_archive(archive()).unwrap();
+        append_blocks(MAX_RETAINED_BLOCKS + 1);
+        env::block_on(archive_oldest()).unwrap();
+
+        assert!(matches!(
+            set_archive(principal(2)),
+            Err(NftError::ArchiveInUse)
+        ));
+        set_archive(archive()).unwrap();
+    }
+
+    #[test]
+    fn block_types_link_to_their_standard() {
+        for block_type in supported_block_types() {
+            let standard = if block_type.block_type.starts_with("37") {
+                "ICRC-37"
+            } else {
+                "ICRC-7"
+            };
+            assert_eq!(
+                block_type.url,
+                format!("https://github.com/dfinity/ICRC/tree/main/ICRCs/{}", standard)
+            );
+        }
+    }
+}
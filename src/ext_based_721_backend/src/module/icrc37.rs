@@ -0,0 +1,681 @@
+use crate::module::cap::insert_sync;
+use crate::module::dedup::{self, TimeError};
+use crate::module::dip721;
+use crate::module::env::{self, time};
+use crate::module::icrc3;
+use crate::module::icrc7::{
+    DEFAULT_TAKE_VALUE, MAX_QUERY_BATCH_SIZE, MAX_TAKE_VALUE, MAX_UPDATE_BATCH_SIZE,
+};
+use crate::module::ledger::{self, Ledger};
+use crate::module::types::{
+    Account, ApprovalInfo, ApproveCollectionArg, ApproveCollectionError, ApproveCollectionResult,
+    ApproveTokenArg, ApproveTokenError, ApproveTokenResult, CollectionApproval, IsApprovedArg,
+    NftError, Operation, RevokeCollectionApprovalArg, RevokeCollectionApprovalError,
+    RevokeCollectionApprovalResult, RevokeTokenApprovalArg, RevokeTokenApprovalError,
+    RevokeTokenApprovalResult, TokenApproval, Token_ID, TransferFromArg, TransferFromError,
+    TransferFromResult, Value,
+};
+use cap_sdk::{DetailValue, IndefiniteEvent};
+use ic_cdk::export::candid::Nat;
+use ic_cdk::export::Principal;
+
+fn is_default(subaccount: &Option<Vec<u8>>) -> bool {
+    Account {
+        owner: Principal::anonymous(),
+        subaccount: subaccount.clone(),
+    }
+    .is_default()
+}
+
+fn take_of(take: Option<Nat>) -> usize {
+    take.and_then(|take| usize::try_from(&take.0).ok())
+        .unwrap_or(DEFAULT_TAKE_VALUE)
+        .min(MAX_TAKE_VALUE)
+}
+
+fn batch_error(max: usize) -> (Nat, String) {
+    (Nat::from(0u64), format!("at most {} entries per call", max))
+}
+
+fn describe(err: NftError) -> (Nat, String) {
+    (Nat::from(err.code()), err.message())
+}
+
+// A token is approved for `spender` when it is the token's operator and any
+// ICRC-37 expiry has not passed, or when the owner approved `spender` for the
+// whole collection.
+pub fn is_approved_for(ledger: &Ledger, spender: &Principal, token_identifier: &Token_ID) -> bool {
+    let now = time();
+    let token_metadata = match ledger.token_metadata(token_identifier) {
+        Ok(token_metadata) => token_metadata,
+        Err(_) => return false,
+    };
+    let owner = match token_metadata.owner {
+        Some(owner) => owner,
+        None => return false,
+    };
+    let token_approval = token_metadata.operator == Some(*spender)
+        && *spender != owner
+        && ledger
+            .approvals
+            .get(token_identifier)
+            .map_or(true, |approval| approval.is_active(now));
+    let collection_approval = ledger
+        .collection_approvals
+        .get(&owner)
+        .and_then(|approvals| approvals.get(spender))
+        .map_or(false, |approval| approval.is_active(now));
+    token_approval || collection_approval
+}
+
+fn approve_one(caller: Principal, arg: ApproveTokenArg) -> ApproveTokenResult {
+    let info = &arg.approval_info;
+    if let Err(err) = dedup::check_time(info.created_at_time) {
+        return Err(match err {
+            TimeError::TooOld => ApproveTokenError::TooOld,
+            TimeError::CreatedInFuture { ledger_time } => {
+                ApproveTokenError::CreatedInFuture { ledger_time }
+            }
+        });
+    }
+    if !is_default(&info.from_subaccount) {
+        return Err(ApproveTokenError::Unauthorized);
+    }
+    if !info.spender.is_default() || info.spender.owner == caller {
+        return Err(ApproveTokenError::InvalidSpender);
+    }
+    if info.expires_at.map_or(false, |expires_at| expires_at <= time()) {
+        let (error_code, message) = (Nat::from(1u64), "approval already expired".to_string());
+        return Err(ApproveTokenError::GenericError { error_code, message });
+    }
+
+    let key = dedup::key(&caller, "icrc37_approve_tokens", &arg);
+    ledger::with_mut(|ledger| {
+        if let Some(block_index) = dedup::find(ledger, &key) {
+            return Ok(block_index);
+        }
+        match ledger.owner_of(&arg.token_id) {
+            Ok(Some(owner)) if owner == caller => {}
+            Ok(Some(_)) => return Err(ApproveTokenError::Unauthorized),
+            _ => return Err(ApproveTokenError::NonExistingTokenId),
+        }
+        let spender = info.spender.owner;
+        let block_index =
+            dip721::approve_token(ledger, caller, spender, &arg.token_id, Some(info.clone()))
+                .map_err(|err| {
+                    let (error_code, message) = describe(err);
+                    ApproveTokenError::GenericError { error_code, message }
+                })?;
+
+        let mut details = vec![
+            ("operator".into(), DetailValue::from(spender)),
+            (
+                "token_identifier".into(),
+                DetailValue::from(arg.token_id.to_string()),
+            ),
+        ];
+        if let Some(expires_at) = info.expires_at {
+            details.push(("expires_at".into(), DetailValue::U64(expires_at)));
+        }
+        insert_sync(IndefiniteEvent {
+            caller,
+            operation: "approve".into(),
+            details,
+        });
+
+        ledger.inc_tx();
+        dedup::record(ledger, key, info.created_at_time, &block_index);
+        Ok(block_index)
+    })
+}
+
+pub fn approve_tokens(args: Vec<ApproveTokenArg>) -> Vec<Option<ApproveTokenResult>> {
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        let (error_code, message) = batch_error(MAX_UPDATE_BATCH_SIZE);
+        return vec![Some(Err(ApproveTokenError::GenericBatchError { error_code, message }))];
+    }
+    let caller = env::caller();
+    args.into_iter()
+        .map(|arg| Some(approve_one(caller, arg)))
+        .collect()
+}
+
+fn approve_collection_one(caller: Principal, arg: ApproveCollectionArg) -> ApproveCollectionResult {
+    let info = &arg.approval_info;
+    if let Err(err) = dedup::check_time(info.created_at_time) {
+        return Err(match err {
+            TimeError::TooOld => ApproveCollectionError::TooOld,
+            TimeError::CreatedInFuture { ledger_time } => {
+                ApproveCollectionError::CreatedInFuture { ledger_time }
+            }
+        });
+    }
+    if !is_default(&info.from_subaccount)
+        || !info.spender.is_default()
+        || info.spender.owner == caller
+    {
+        return Err(ApproveCollectionError::InvalidSpender);
+    }
+    if info.expires_at.map_or(false, |expires_at| expires_at <= time()) {
+        let (error_code, message) = (Nat::from(1u64), "approval already expired".to_string());
+        return Err(ApproveCollectionError::GenericError { error_code, message });
+    }
+
+    let key = dedup::key(&caller, "icrc37_approve_collection", &arg);
+    ledger::with_mut(|ledger| {
+        if let Some(block_index) = dedup::find(ledger, &key) {
+            return Ok(block_index);
+        }
+        if ledger.is_paused(&Operation::Approve) {
+            let (error_code, message) = describe(NftError::OperationPaused(Operation::Approve));
+            return Err(ApproveCollectionError::GenericError { error_code, message });
+        }
+        let spender = info.spender.owner;
+        ledger
+            .collection_approvals
+            .entry(caller)
+            .or_default()
+            .insert(spender, info.clone());
+        let mut tx = vec![
+            ("from", icrc3::account(caller)),
+            ("spender", icrc3::account(spender)),
+        ];
+        if let Some(expires_at) = info.expires_at {
+            tx.push(("exp", Value::Nat(Nat::from(expires_at))));
+        }
+        let block_index = icrc3::append(ledger, "37approve_coll", tx);
+
+        insert_sync(IndefiniteEvent {
+            caller,
+            operation: "approveCollection".into(),
+            details: vec![("operator".into(), DetailValue::from(spender))],
+        });
+
+        ledger.inc_tx();
+        dedup::record(ledger, key, info.created_at_time, &block_index);
+        Ok(block_index)
+    })
+}
+
+pub fn approve_collection(args: Vec<ApproveCollectionArg>) -> Vec<Option<ApproveCollectionResult>> {
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        let (error_code, message) = batch_error(MAX_UPDATE_BATCH_SIZE);
+        return vec![Some(Err(ApproveCollectionError::GenericBatchError {
+            error_code,
+            message,
+        }))];
+    }
+    let caller = env::caller();
+    args.into_iter()
+        .map(|arg| Some(approve_collection_one(caller, arg)))
+        .collect()
+}
+
+fn revoke_token_one(caller: Principal, arg: RevokeTokenApprovalArg) -> RevokeTokenApprovalResult {
+    if let Some(Err(err)) = arg.created_at_time.map(dedup::check_time) {
+        return Err(match err {
+            TimeError::TooOld => RevokeTokenApprovalError::TooOld,
+            TimeError::CreatedInFuture { ledger_time } => {
+                RevokeTokenApprovalError::CreatedInFuture { ledger_time }
+            }
+        });
+    }
+    if !is_default(&arg.from_subaccount) {
+        return Err(RevokeTokenApprovalError::Unauthorized);
+    }
+
+    let key = dedup::key(&caller, "icrc37_revoke_token_approvals", &arg);
+    ledger::with_mut(|ledger| {
+        if let Some(block_index) = dedup::find(ledger, &key) {
+            return Ok(block_index);
+        }
+        match ledger.owner_of(&arg.token_id) {
+            Ok(Some(owner)) if owner == caller => {}
+            Ok(Some(_)) => return Err(RevokeTokenApprovalError::Unauthorized),
+            _ => return Err(RevokeTokenApprovalError::NonExistingTokenId),
+        }
+        let operator = match ledger.operator_of(&arg.token_id) {
+            Ok(Some(operator)) if operator != caller => operator,
+            _ => return Err(RevokeTokenApprovalError::ApprovalDoesNotExist),
+        };
+        if arg
+            .spender
+            .as_ref()
+            .map_or(false, |spender| !spender.is_default() || spender.owner != operator)
+        {
+            return Err(RevokeTokenApprovalError::ApprovalDoesNotExist);
+        }
+        ledger.update_operator_cache(&arg.token_id, Some(operator), None);
+        ledger.approve(caller, &arg.token_id, None);
+        ledger.approvals.remove(&arg.token_id);
+        let block_index = icrc3::append(
+            ledger,
+            "37revoke",
+            vec![
+                ("tid", icrc3::token(&arg.token_id)),
+                ("from", icrc3::account(caller)),
+                ("spender", icrc3::account(operator)),
+            ],
+        );
+
+        insert_sync(IndefiniteEvent {
+            caller,
+            operation: "revokeApproval".into(),
+            details: vec![
+                ("operator".into(), DetailValue::from(operator)),
+                (
+                    "token_identifier".into(),
+                    DetailValue::from(arg.token_id.to_string()),
+                ),
+            ],
+        });
+
+        ledger.inc_tx();
+        if let Some(created_at_time) = arg.created_at_time {
+            dedup::record(ledger, key, created_at_time, &block_index);
+        }
+        Ok(block_index)
+    })
+}
+
+pub fn revoke_token_approvals(
+    args: Vec<RevokeTokenApprovalArg>,
+) -> Vec<Option<RevokeTokenApprovalResult>> {
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        let (error_code, message) = batch_error(MAX_UPDATE_BATCH_SIZE);
+        return vec![Some(Err(RevokeTokenApprovalError::GenericBatchError {
+            error_code,
+            message,
+        }))];
+    }
+    let caller = env::caller();
+    args.into_iter()
+        .map(|arg| Some(revoke_token_one(caller, arg)))
+        .collect()
+}
+
+fn revoke_collection_one(
+    caller: Principal,
+    arg: RevokeCollectionApprovalArg,
+) -> RevokeCollectionApprovalResult {
+    if let Some(Err(err)) = arg.created_at_time.map(dedup::check_time) {
+        return Err(match err {
+            TimeError::TooOld => RevokeCollectionApprovalError::TooOld,
+            TimeError::CreatedInFuture { ledger_time } => {
+                RevokeCollectionApprovalError::CreatedInFuture { ledger_time }
+            }
+        });
+    }
+    if !is_default(&arg.from_subaccount) {
+        return Err(RevokeCollectionApprovalError::ApprovalDoesNotExist);
+    }
+
+    let key = dedup::key(&caller, "icrc37_revoke_collection_approvals", &arg);
+    ledger::with_mut(|ledger| {
+        if let Some(block_index) = dedup::find(ledger, &key) {
+            return Ok(block_index);
+        }
+        let approvals = ledger
+            .collection_approvals
+            .get_mut(&caller)
+            .ok_or(RevokeCollectionApprovalError::ApprovalDoesNotExist)?;
+        let revoked = match &arg.spender {
+            Some(spender) => approvals
+                .remove(&spender.owner)
+                .map(|_| vec![spender.owner])
+                .unwrap_or_default(),
+            None => approvals.drain().map(|(spender, _)| spender).collect(),
+        };
+        if approvals.is_empty() {
+            ledger.collection_approvals.remove(&caller);
+        }
+        if revoked.is_empty() {
+            return Err(RevokeCollectionApprovalError::ApprovalDoesNotExist);
+        }
+        let mut tx = vec![("from", icrc3::account(caller))];
+        if let Some(spender) = &arg.spender {
+            tx.push(("spender", icrc3::account(spender.owner)));
+        }
+        let block_index = icrc3::append(ledger, "37revoke_coll", tx);
+
+        insert_sync(IndefiniteEvent {
+            caller,
+            operation: "revokeCollectionApproval".into(),
+            details: vec![(
+                "operators".into(),
+                DetailValue::Vec(revoked.into_iter().map(DetailValue::from).collect()),
+            )],
+        });
+
+        ledger.inc_tx();
+        if let Some(created_at_time) = arg.created_at_time {
+            dedup::record(ledger, key, created_at_time, &block_index);
+        }
+        Ok(block_index)
+    })
+}
+
+pub fn revoke_collection_approvals(
+    args: Vec<RevokeCollectionApprovalArg>,
+) -> Vec<Option<RevokeCollectionApprovalResult>> {
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        let (error_code, message) = batch_error(MAX_UPDATE_BATCH_SIZE);
+        return vec![Some(Err(RevokeCollectionApprovalError::GenericBatchError {
+            error_code,
+            message,
+        }))];
+    }
+    let caller = env::caller();
+    args.into_iter()
+        .map(|arg| Some(revoke_collection_one(caller, arg)))
+        .collect()
+}
+
+pub fn is_approved(args: Vec<IsApprovedArg>) -> Vec<bool> {
+    ledger::with(|ledger| {
+        args.into_iter()
+            .take(MAX_QUERY_BATCH_SIZE)
+            .map(|arg| {
+                arg.spender.is_default()
+                    && is_default(&arg.from_subaccount)
+                    && is_approved_for(ledger, &arg.spender.owner, &arg.token_id)
+            })
+            .collect()
+    })
+}
+
+// A token has at most one spender, its DIP721 operator; approvals made
+// through `approve` have no ICRC-37 details and are reported without expiry.
+pub fn get_token_approvals(
+    token_id: Nat,
+    prev: Option<TokenApproval>,
+    take: Option<Nat>,
+) -> Vec<TokenApproval> {
+    if prev.is_some() || take_of(take) == 0 {
+        return vec![];
+    }
+    ledger::with(|ledger| {
+        let token_metadata = match ledger.token_metadata(&token_id) {
+            Ok(token_metadata) => token_metadata,
+            Err(_) => return vec![],
+        };
+        match (token_metadata.owner, token_metadata.operator) {
+            (Some(owner), Some(operator)) if owner != operator => {}
+            _ => return vec![],
+        }
+        let approval_info = match ledger.approvals.get(&token_id) {
+            Some(approval) if !approval.is_active(time()) => return vec![],
+            Some(approval) => approval.clone(),
+            None => ApprovalInfo {
+                spender: Account {
+                    owner: token_metadata.operator.unwrap(),
+                    subaccount: None,
+                },
+                from_subaccount: None,
+                expires_at: None,
+                memo: None,
+                created_at_time: token_metadata.approved_at.unwrap_or(0),
+            },
+        };
+        vec![TokenApproval {
+            token_id: token_id.clone(),
+            approval_info,
+        }]
+    })
+}
+
+// Collection approvals of `owner`, ordered by spender and paged after `prev`.
+pub fn get_collection_approvals(
+    owner: Account,
+    prev: Option<CollectionApproval>,
+    take: Option<Nat>,
+) -> Vec<CollectionApproval> {
+    if !owner.is_default() {
+        return vec![];
+    }
+    let now = time();
+    ledger::with(|ledger| {
+        let mut approvals = ledger
+            .collection_approvals
+            .get(&owner.owner)
+            .map(|approvals| {
+                approvals
+                    .values()
+                    .filter(|approval| approval.is_active(now))
+                    .cloned()
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        approvals.sort_by_key(|approval| approval.spender.owner);
+        approvals
+            .into_iter()
+            .filter(|approval| {
+                prev.as_ref()
+                    .map_or(true, |prev| approval.spender.owner > prev.spender.owner)
+            })
+            .take(take_of(take))
+            .collect()
+    })
+}
+
+fn transfer_from_one(caller: Principal, arg: TransferFromArg) -> TransferFromResult {
+    if let Some(Err(err)) = arg.created_at_time.map(dedup::check_time) {
+        return Err(match err {
+            TimeError::TooOld => TransferFromError::TooOld,
+            TimeError::CreatedInFuture { ledger_time } => {
+                TransferFromError::CreatedInFuture { ledger_time }
+            }
+        });
+    }
+    if !is_default(&arg.spender_subaccount) || !arg.from.is_default() {
+        return Err(TransferFromError::Unauthorized);
+    }
+    if !arg.to.is_default()
+        || arg.to.owner == Principal::anonymous()
+        || arg.to.owner == arg.from.owner
+    {
+        return Err(TransferFromError::InvalidRecipient);
+    }
+
+    let key = dedup::key(&caller, "icrc37_transfer_from", &arg);
+    ledger::with_mut(|ledger| {
+        if let Some(duplicate_of) = dedup::find(ledger, &key) {
+            return Err(TransferFromError::Duplicate { duplicate_of });
+        }
+        match ledger.owner_of(&arg.token_id) {
+            Ok(Some(owner)) if owner == arg.from.owner => {}
+            Ok(Some(_)) => return Err(TransferFromError::Unauthorized),
+            _ => return Err(TransferFromError::NonExistingTokenId),
+        }
+        if caller != arg.from.owner && !is_approved_for(ledger, &caller, &arg.token_id) {
+            return Err(TransferFromError::Unauthorized);
+        }
+        let block_index =
+            dip721::transfer_token(ledger, caller, &arg.token_id, arg.to.owner).map_err(|err| {
+                let (error_code, message) = describe(err);
+                TransferFromError::GenericError { error_code, message }
+            })?;
+
+        let mut details = vec![
+            ("owner".into(), DetailValue::from(arg.from.owner)),
+            ("to".into(), DetailValue::from(arg.to.owner)),
+            (
+                "token_identifier".into(),
+                DetailValue::from(arg.token_id.to_string()),
+            ),
+        ];
+        if let Some(memo) = arg.memo.clone() {
+            details.push(("memo".into(), DetailValue::Slice(memo)));
+        }
+        insert_sync(IndefiniteEvent {
+            caller,
+            operation: "transferFrom".into(),
+            details,
+        });
+
+        ledger.inc_tx();
+        if let Some(created_at_time) = arg.created_at_time {
+            dedup::record(ledger, key, created_at_time, &block_index);
+        }
+        Ok(block_index)
+    })
+}
+
+pub fn transfer_from(args: Vec<TransferFromArg>) -> Vec<Option<TransferFromResult>> {
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        let (error_code, message) = batch_error(MAX_UPDATE_BATCH_SIZE);
+        return vec![Some(Err(TransferFromError::GenericBatchError { error_code, message }))];
+    }
+    let caller = env::caller();
+    args.into_iter()
+        .map(|arg| Some(transfer_from_one(caller, arg)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::testing::{self, block_type, owner_of, principal};
+
+    fn account(owner: Principal) -> Account {
+        Account {
+            owner,
+            subaccount: None,
+        }
+    }
+
+    fn approval(spender: Principal, expires_at: Option<u64>) -> ApprovalInfo {
+        ApprovalInfo {
+            spender: account(spender),
+            from_subaccount: None,
+            expires_at,
+            memo: None,
+            created_at_time: time(),
+        }
+    }
+
+    fn approve_as(owner: Principal, token_id: &Token_ID, spender: Principal) -> ApproveTokenResult {
+        env::set_caller(owner);
+        approve_tokens(vec![ApproveTokenArg {
+            token_id: token_id.clone(),
+            approval_info: approval(spender, None),
+        }])
+        .pop()
+        .unwrap()
+        .unwrap()
+    }
+
+    fn transfer_from_as(
+        spender: Principal,
+        from: Principal,
+        token_id: &Token_ID,
+        to: Principal,
+    ) -> TransferFromResult {
+        env::set_caller(spender);
+        transfer_from(vec![TransferFromArg {
+            spender_subaccount: None,
+            from: account(from),
+            to: account(to),
+            token_id: token_id.clone(),
+            memo: None,
+            created_at_time: None,
+        }])
+        .pop()
+        .unwrap()
+        .unwrap()
+    }
+
+    fn approved(spender: Principal, token_id: &Token_ID) -> bool {
+        is_approved(vec![IsApprovedArg {
+            spender: account(spender),
+            from_subaccount: None,
+            token_id: token_id.clone(),
+        }])[0]
+    }
+
+    #[test]
+    fn approvals_and_transfers_return_the_index_of_their_block() {
+        testing::setup();
+        let (owner, spender) = (principal(1), principal(2));
+        let token = testing::mint(owner);
+
+        assert_eq!(approve_as(owner, &token, spender).unwrap(), Nat::from(1u64));
+        assert_eq!(block_type(1).as_deref(), Some("37approve"));
+
+        let block_index = transfer_from_as(spender, owner, &token, principal(3)).unwrap();
+        assert_eq!(block_index, Nat::from(2u64));
+        assert_eq!(block_type(2).as_deref(), Some("37xfer"));
+        assert_eq!(owner_of(&token), Some(principal(3)));
+    }
+
+    #[test]
+    fn unapproved_spenders_cannot_transfer() {
+        testing::setup();
+        let token = testing::mint(principal(1));
+
+        assert!(matches!(
+            transfer_from_as(principal(2), principal(1), &token, principal(3)),
+            Err(TransferFromError::Unauthorized)
+        ));
+        assert_eq!(owner_of(&token), Some(principal(1)));
+    }
+
+    #[test]
+    fn token_approvals_expire() {
+        testing::setup();
+        let (owner, spender) = (principal(1), principal(2));
+        let token = testing::mint(owner);
+        env::set_caller(owner);
+        approve_tokens(vec![ApproveTokenArg {
+            token_id: token.clone(),
+            approval_info: approval(spender, Some(time() + 10)),
+        }])
+        .pop()
+        .unwrap()
+        .unwrap()
+        .unwrap();
+        assert!(approved(spender, &token));
+
+        env::advance_time(10);
+        assert!(!approved(spender, &token));
+        assert!(matches!(
+            transfer_from_as(spender, owner, &token, principal(3)),
+            Err(TransferFromError::Unauthorized)
+        ));
+    }
+
+    #[test]
+    fn collection_approvals_cover_every_token_until_revoked() {
+        testing::setup();
+        let (owner, spender) = (principal(1), principal(2));
+        let first = testing::mint(owner);
+        let second = testing::mint(owner);
+        env::set_caller(owner);
+
+        let approved_at = approve_collection(vec![ApproveCollectionArg {
+            approval_info: approval(spender, None),
+        }])
+        .pop()
+        .unwrap()
+        .unwrap()
+        .unwrap();
+        assert_eq!(approved_at, Nat::from(2u64));
+        assert_eq!(block_type(2).as_deref(), Some("37approve_coll"));
+        assert!(approved(spender, &first) && approved(spender, &second));
+
+        let revoked_at = revoke_collection_approvals(vec![RevokeCollectionApprovalArg {
+            spender: Some(account(spender)),
+            from_subaccount: None,
+            memo: None,
+            created_at_time: None,
+        }])
+        .pop()
+        .unwrap()
+        .unwrap()
+        .unwrap();
+        assert_eq!(revoked_at, Nat::from(3u64));
+        assert_eq!(block_type(3).as_deref(), Some("37revoke_coll"));
+        assert!(!approved(spender, &first) && !approved(spender, &second));
+    }
+}
+
@@ -0,0 +1,324 @@
+use crate::module::cap::insert_sync;
+use crate::module::dedup::{self, TimeError};
+use crate::module::dip721;
+use crate::module::env;
+use crate::module::ledger;
+use crate::module::reveal;
+use crate::module::types::{
+    Account, Icrc7TransferArg, Icrc7TransferError, Icrc7TransferResult, NftError,
+    SupportedStandard, Token_ID, Value,
+};
+use cap_sdk::{DetailValue, IndefiniteEvent};
+use ic_cdk::export::candid::Nat;
+use ic_cdk::export::Principal;
+
+pub const MAX_QUERY_BATCH_SIZE: usize = 100;
+pub const MAX_UPDATE_BATCH_SIZE: usize = 20;
+pub const DEFAULT_TAKE_VALUE: usize = 100;
+pub const MAX_TAKE_VALUE: usize = 1_000;
+pub const MAX_MEMO_SIZE: usize = 32;
+
+fn nat(value: usize) -> Value {
+    Value::Nat(Nat::from(value as u64))
+}
+
+pub fn collection_metadata() -> Vec<(String, Value)> {
+    ledger::with(|ledger| {
+        let metadata = &ledger.metadata;
+        let mut entries = vec![];
+        if let Some(name) = &metadata.name {
+            entries.push(("icrc7:name".into(), Value::Text(name.clone())));
+        }
+        if let Some(symbol) = &metadata.symbol {
+            entries.push(("icrc7:symbol".into(), Value::Text(symbol.clone())));
+        }
+        if let Some(logo) = &metadata.logo {
+            entries.push(("icrc7:logo".into(), Value::Text(logo.clone())));
+        }
+        entries.push(("icrc7:total_supply".into(), nat(ledger.tokens_count())));
+        entries.push(("icrc7:max_query_batch_size".into(), nat(MAX_QUERY_BATCH_SIZE)));
+        entries.push(("icrc7:max_update_batch_size".into(), nat(MAX_UPDATE_BATCH_SIZE)));
+        entries.push(("icrc7:default_take_value".into(), nat(DEFAULT_TAKE_VALUE)));
+        entries.push(("icrc7:max_take_value".into(), nat(MAX_TAKE_VALUE)));
+        entries.push(("icrc7:max_memo_size".into(), nat(MAX_MEMO_SIZE)));
+        entries
+    })
+}
+
+pub fn supported_standards() -> Vec<SupportedStandard> {
+    vec![
+        SupportedStandard {
+            name: "ICRC-7".into(),
+            url: "https://github.com/dfinity/ICRC/tree/main/ICRCs/ICRC-7".into(),
+        },
+        SupportedStandard {
+            name: "ICRC-3".into(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-3".into(),
+        },
+        SupportedStandard {
+            name: "ICRC-37".into(),
+            url: "https://github.com/dfinity/ICRC/tree/main/ICRCs/ICRC-37".into(),
+        },
+    ]
+}
+
+// Batched queries answer at most `MAX_QUERY_BATCH_SIZE` entries.
+fn query_batch<T>(items: Vec<T>) -> impl Iterator<Item = T> {
+    items.into_iter().take(MAX_QUERY_BATCH_SIZE)
+}
+
+pub fn token_metadata(token_ids: Vec<Token_ID>) -> Vec<Option<Vec<(String, Value)>>> {
+    ledger::with(|ledger| {
+        query_batch(token_ids)
+            .map(|token_id| {
+                ledger.token_metadata(&token_id).ok().map(|token_metadata| {
+                    reveal::visible_properties(token_metadata)
+                        .into_iter()
+                        .map(|(key, value)| (key, Value::from(value)))
+                        .collect()
+                })
+            })
+            .collect()
+    })
+}
+
+pub fn owner_of(token_ids: Vec<Token_ID>) -> Vec<Option<Account>> {
+    ledger::with(|ledger| {
+        query_batch(token_ids)
+            .map(|token_id| {
+                ledger
+                    .owner_of(&token_id)
+                    .ok()
+                    .flatten()
+                    .map(|owner| Account {
+                        owner,
+                        subaccount: None,
+                    })
+            })
+            .collect()
+    })
+}
+
+pub fn balance_of(accounts: Vec<Account>) -> Vec<Nat> {
+    ledger::with(|ledger| {
+        query_batch(accounts)
+            .map(|account| {
+                if !account.is_default() {
+                    return Nat::from(0u64);
+                }
+                let count = ledger
+                    .owner_token_identifiers(&account.owner)
+                    .map_or(0, |tokens| tokens.len());
+                Nat::from(count as u64)
+            })
+            .collect()
+    })
+}
+
+// Ascending token ids after `prev`, at most `take` of them.
+fn page<'a>(
+    tokens: impl Iterator<Item = &'a Token_ID>,
+    prev: Option<Nat>,
+    take: Option<Nat>,
+) -> Vec<Nat> {
+    let take = take
+        .and_then(|take| usize::try_from(&take.0).ok())
+        .unwrap_or(DEFAULT_TAKE_VALUE)
+        .min(MAX_TAKE_VALUE);
+    let mut tokens = tokens
+        .filter(|token| prev.as_ref().map_or(true, |prev| *token > prev))
+        .cloned()
+        .collect::<Vec<_>>();
+    tokens.sort();
+    tokens.truncate(take);
+    tokens
+}
+
+pub fn tokens(prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    ledger::with(|ledger| {
+        page(
+            ledger
+                .tokens
+                .iter()
+                .filter(|(_, token_metadata)| !token_metadata.is_burned)
+                .map(|(token_id, _)| token_id),
+            prev,
+            take,
+        )
+    })
+}
+
+pub fn tokens_of(account: Account, prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    if !account.is_default() {
+        return vec![];
+    }
+    ledger::with(|ledger| match ledger.owner_token_identifiers(&account.owner) {
+        Ok(tokens) => page(tokens.iter(), prev, take),
+        Err(_) => vec![],
+    })
+}
+
+fn generic_error(err: NftError) -> Icrc7TransferError {
+    Icrc7TransferError::GenericError {
+        error_code: Nat::from(err.code()),
+        message: err.message(),
+    }
+}
+
+fn transfer_one(caller: Principal, arg: Icrc7TransferArg) -> Icrc7TransferResult {
+    if let Some(Err(err)) = arg.created_at_time.map(dedup::check_time) {
+        return Err(match err {
+            TimeError::TooOld => Icrc7TransferError::TooOld,
+            TimeError::CreatedInFuture { ledger_time } => {
+                Icrc7TransferError::CreatedInFuture { ledger_time }
+            }
+        });
+    }
+    let from = Account {
+        owner: caller,
+        subaccount: arg.from_subaccount.clone(),
+    };
+    if !from.is_default() {
+        return Err(Icrc7TransferError::Unauthorized);
+    }
+    if !arg.to.is_default() || arg.to.owner == Principal::anonymous() || arg.to.owner == caller {
+        return Err(Icrc7TransferError::InvalidRecipient);
+    }
+    if arg.memo.as_ref().map_or(false, |memo| memo.len() > MAX_MEMO_SIZE) {
+        return Err(Icrc7TransferError::GenericError {
+            error_code: Nat::from(1u64),
+            message: "memo too long".into(),
+        });
+    }
+
+    let key = dedup::key(&caller, "icrc7_transfer", &arg);
+    ledger::with_mut(|ledger| {
+        if let Some(duplicate_of) = dedup::find(ledger, &key) {
+            return Err(Icrc7TransferError::Duplicate { duplicate_of });
+        }
+        match ledger.owner_of(&arg.token_id) {
+            Ok(Some(owner)) if owner == caller => {}
+            Ok(Some(_)) => return Err(Icrc7TransferError::Unauthorized),
+            _ => return Err(Icrc7TransferError::NonExistingTokenId),
+        }
+        let block_index = dip721::transfer_token(ledger, caller, &arg.token_id, arg.to.owner)
+            .map_err(generic_error)?;
+
+        let mut details = vec![
+            ("owner".into(), DetailValue::from(caller)),
+            ("to".into(), DetailValue::from(arg.to.owner)),
+            (
+                "token_identifier".into(),
+                DetailValue::from(arg.token_id.to_string()),
+            ),
+        ];
+        if let Some(memo) = arg.memo.clone() {
+            details.push(("memo".into(), DetailValue::Slice(memo)));
+        }
+        insert_sync(IndefiniteEvent {
+            caller,
+            operation: "transfer".into(),
+            details,
+        });
+
+        ledger.inc_tx();
+        if let Some(created_at_time) = arg.created_at_time {
+            dedup::record(ledger, key, created_at_time, &block_index);
+        }
+        Ok(block_index)
+    })
+}
+
+// Transfers are applied independently, so one failing entry does not undo
+// the others.
+pub fn transfer(args: Vec<Icrc7TransferArg>) -> Vec<Option<Icrc7TransferResult>> {
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        return vec![Some(Err(Icrc7TransferError::GenericBatchError {
+            error_code: Nat::from(0u64),
+            message: format!("at most {} transfers per call", MAX_UPDATE_BATCH_SIZE),
+        }))];
+    }
+    let caller = env::caller();
+    args.into_iter()
+        .map(|arg| Some(transfer_one(caller, arg)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::testing::{self, block_type, owner_of, principal};
+
+    fn transfer_arg(token_id: &Token_ID, to: Principal) -> Icrc7TransferArg {
+        Icrc7TransferArg {
+            from_subaccount: None,
+            to: Account {
+                owner: to,
+                subaccount: None,
+            },
+            token_id: token_id.clone(),
+            memo: None,
+            created_at_time: Some(env::time()),
+        }
+    }
+
+    fn transfer_as(caller: Principal, arg: Icrc7TransferArg) -> Icrc7TransferResult {
+        env::set_caller(caller);
+        transfer(vec![arg]).pop().unwrap().unwrap()
+    }
+
+    #[test]
+    fn transfers_return_the_index_of_their_block() {
+        testing::setup();
+        let token = testing::mint(principal(1));
+        testing::mint(principal(1));
+        testing::mint(principal(1));
+
+        let block_index = transfer_as(principal(1), transfer_arg(&token, principal(2))).unwrap();
+
+        assert_eq!(block_index, Nat::from(3u64));
+        assert_eq!(block_type(3).as_deref(), Some("7xfer"));
+        assert_eq!(owner_of(&token), Some(principal(2)));
+    }
+
+    #[test]
+    fn duplicates_point_at_the_original_block() {
+        testing::setup();
+        let token = testing::mint(principal(1));
+        let arg = transfer_arg(&token, principal(2));
+
+        let block_index = transfer_as(principal(1), arg.clone()).unwrap();
+        match transfer_as(principal(1), arg) {
+            Err(Icrc7TransferError::Duplicate { duplicate_of }) => {
+                assert_eq!(duplicate_of, block_index)
+            }
+            other => panic!("expected a duplicate, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn only_the_owner_can_transfer() {
+        testing::setup();
+        let token = testing::mint(principal(1));
+
+        assert!(matches!(
+            transfer_as(principal(2), transfer_arg(&token, principal(3))),
+            Err(Icrc7TransferError::Unauthorized)
+        ));
+        assert_eq!(owner_of(&token), Some(principal(1)));
+    }
+
+    #[test]
+    fn paused_transfers_fail_without_a_block() {
+        testing::setup();
+        let token = testing::mint(principal(1));
+        ledger::with_mut(|ledger| ledger.set_paused(crate::module::types::Operation::Transfer, true));
+
+        assert!(matches!(
+            transfer_as(principal(1), transfer_arg(&token, principal(2))),
+            Err(Icrc7TransferError::GenericError { .. })
+        ));
+        assert_eq!(block_type(1), None);
+    }
+}
+
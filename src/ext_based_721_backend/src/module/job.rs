@@ -0,0 +1,581 @@
+use crate::module::cap::insert_sync;
+use crate::module::dip721;
+use crate::module::env::{self, time};
+use crate::module::ledger;
+use crate::module::mint_phase;
+use crate::module::rbac;
+use crate::module::types::{
+    AirdropEntry, GeneralValue, Job, JobItem, JobKind, JobOutcome, JobProgress, MintRequest,
+    NftError, Operation, Role, Token_ID, TransferRequestV1, TransferRequestV2, User,
+};
+use crate::prop;
+use cap_sdk::{DetailValue, IndefiniteEvent};
+use ic_cdk::export::Principal;
+use std::collections::HashSet;
+
+// Work stops once a message has used this many instructions, well below the
+// per-message limit; the rest carries over to the next heartbeat.
+pub const INSTRUCTION_BUDGET: u64 = 3_000_000_000;
+// The most items a single job may queue.
+pub const MAX_JOB_ITEMS: usize = 10_000;
+// Completed jobs stay queryable this long before they are dropped.
+pub const JOB_RETENTION: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+
+fn principal_of(user: &User, index: u64) -> Result<Principal, NftError> {
+    match user {
+        User::principal(principal) if *principal != Principal::anonymous() => Ok(*principal),
+        _ => Err(NftError::InvalidRecipient(index)),
+    }
+}
+
+fn check_quantity(quantity: usize) -> Result<(), NftError> {
+    if quantity == 0 || quantity > MAX_JOB_ITEMS {
+        return Err(NftError::InvalidQuantity);
+    }
+    Ok(())
+}
+
+// Checks that the caller may move every token now, so a job cannot queue
+// transfers its submitter has no authority over. Whether the tokens may change
+// hands is left to when each item runs.
+fn check_transfers(from: Principal, to: Principal, tokens: &[Token_ID]) -> Result<(), NftError> {
+    check_quantity(tokens.len())?;
+    let caller = env::caller();
+    let mut seen = HashSet::new();
+    ledger::with(|ledger| {
+        tokens.iter().try_for_each(|token| {
+            if !seen.insert(token) {
+                return Err(NftError::DuplicateToken(token.clone()));
+            }
+            dip721::check_transfer_authority(ledger, caller, from, to, token)
+        })
+    })
+}
+
+fn submit(kind: JobKind, items: Vec<JobItem>) -> u64 {
+    let caller = env::caller();
+    let total = items.len() as u64;
+    let id = ledger::with_mut(|ledger| {
+        let id = ledger.job_count;
+        ledger.job_count += 1;
+        ledger.jobs.insert(
+            id,
+            Job {
+                id,
+                kind,
+                submitted_by: caller,
+                submitted_at: time(),
+                items,
+                outcomes: vec![],
+                completed_at: None,
+            },
+        );
+        id
+    });
+
+    insert_sync(IndefiniteEvent {
+        caller,
+        operation: "submitJob".into(),
+        details: vec![
+            ("job".into(), DetailValue::U64(id)),
+            ("kind".into(), DetailValue::from(format!("{:?}", kind))),
+            ("items".into(), DetailValue::U64(total)),
+        ],
+    });
+
+    id
+}
+
+// Queued counterpart of `batch_mint`. The phase quota is taken when the job is
+// submitted, like the synchronous endpoint does, and handed back for every item
+// that fails to mint.
+pub fn submit_batch_mint(mint_request: MintRequest, num: u32) -> Result<u64, NftError> {
+    rbac::ensure_role(Role::Minter)?;
+    let to = principal_of(&mint_request.to, 0)?;
+    check_quantity(num as usize)?;
+    if prop::properties_of(&mint_request.class).is_empty() {
+        return Err(NftError::ClassNotFound(mint_request.class));
+    }
+    mint_phase::consume(&mint_request.to, num)?;
+
+    let items = (0..num)
+        .map(|_| JobItem::Mint {
+            to,
+            class: mint_request.class.clone(),
+            metadata: None,
+        })
+        .collect();
+    Ok(submit(JobKind::BatchMint, items))
+}
+
+// Mints each entry's class to its recipient, one job item per entry. Every
+// entry is checked before anything is queued, and nothing is queued while
+// minting is paused. Airdrops are minter distributions and do not count
+// against mint phases.
+pub fn submit_airdrop(entries: Vec<AirdropEntry>) -> Result<u64, NftError> {
+    rbac::ensure_role(Role::Minter)?;
+    if ledger::with(|ledger| ledger.is_paused(&Operation::Mint)) {
+        return Err(NftError::OperationPaused(Operation::Mint));
+    }
+    if entries.is_empty() {
+        return Err(NftError::InvalidRecipient(0));
+    }
+    check_quantity(entries.len())?;
+    let items = entries
+        .into_iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            // Tokens are owned by principals, so account identifiers cannot receive them.
+            let to = principal_of(&entry.to, index as u64)?;
+            if prop::properties_of(&entry.class).is_empty() {
+                return Err(NftError::ClassNotFound(entry.class));
+            }
+            Ok(JobItem::Mint {
+                to,
+                class: entry.class,
+                metadata: entry.metadata,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(submit(JobKind::Airdrop, items))
+}
+
+// Queued counterpart of `batch_transfer_v1`: picks `num` transferable tokens of
+// `class` owned by `from` when the job is submitted.
+pub fn submit_batch_transfer_v1(request: TransferRequestV1) -> Result<u64, NftError> {
+    let from = principal_of(&request.from, 0)?;
+    let to = principal_of(&request.to, 0)?;
+    let class = GeneralValue::TextContent(request.class.clone());
+    let tokens = ledger::with(|ledger| {
+        ledger.owner_token_identifiers(&from).map(|tokens| {
+            tokens
+                .iter()
+                .filter(|token| {
+                    ledger.token_metadata(token).map_or(false, |metadata| {
+                        !metadata.is_burned
+                            && !dip721::is_soulbound(metadata)
+                            && metadata
+                                .properties
+                                .iter()
+                                .any(|(key, value)| key == "class" && *value == class)
+                    })
+                })
+                .cloned()
+                .collect::<Vec<_>>()
+        })
+    })?;
+    let num = usize::try_from(request.num).unwrap_or(usize::MAX);
+    check_quantity(num)?;
+    if tokens.len() < num {
+        return Err(NftError::InsufficientInputs(request.class));
+    }
+    let tokens = tokens.into_iter().take(num).collect::<Vec<_>>();
+    check_transfers(from, to, &tokens)?;
+
+    let items = tokens
+        .into_iter()
+        .map(|token| JobItem::Transfer { from, to, token })
+        .collect();
+    Ok(submit(JobKind::BatchTransferV1, items))
+}
+
+pub fn submit_batch_transfer_v2(request: TransferRequestV2) -> Result<u64, NftError> {
+    let from = principal_of(&request.from, 0)?;
+    let to = principal_of(&request.to, 0)?;
+    check_transfers(from, to, &request.token_list)?;
+
+    let items = request
+        .token_list
+        .into_iter()
+        .map(|token| JobItem::Transfer { from, to, token })
+        .collect();
+    Ok(submit(JobKind::BatchTransferV2, items))
+}
+
+fn operation_of(item: &JobItem) -> Operation {
+    match item {
+        JobItem::Mint { .. } => Operation::Mint,
+        JobItem::Transfer { .. } => Operation::Transfer,
+    }
+}
+
+fn run(submitted_by: Principal, item: JobItem) -> JobOutcome {
+    let result = match item {
+        JobItem::Mint {
+            to,
+            class,
+            metadata,
+        } => {
+            let mut properties = prop::properties_of(&class);
+            if let Some(metadata) = metadata {
+                properties.push(("metadata".into(), GeneralValue::BlobContent(metadata)));
+            }
+            dip721::mint_next(submitted_by, to, properties).map(|(_, tx_id)| tx_id)
+        }
+        JobItem::Transfer { from, to, token } => {
+            dip721::transfer_from_by(submitted_by, from, to, token)
+        }
+    };
+    match result {
+        Ok(tx_id) => JobOutcome::Done(tx_id),
+        Err(err) => JobOutcome::Failed(err.to_string()),
+    }
+}
+
+// Drops jobs that completed more than `JOB_RETENTION` ago.
+fn prune_completed(now: u64) {
+    ledger::with_mut(|ledger| {
+        ledger.jobs.retain(|_, job| {
+            job.completed_at.map_or(true, |completed_at| {
+                completed_at.saturating_add(JOB_RETENTION) > now
+            })
+        })
+    });
+}
+
+// Called from the heartbeat: works through unfinished jobs oldest first until
+// the instruction budget is spent. A job whose next item is paused waits.
+pub fn process_queued() {
+    prune_completed(time());
+    let mut pending = ledger::with(|ledger| {
+        ledger
+            .jobs
+            .values()
+            .filter(|job| job.completed_at.is_none())
+            .map(|job| job.id)
+            .collect::<Vec<_>>()
+    });
+    pending.sort_unstable();
+
+    for id in pending {
+        loop {
+            if env::instruction_counter() >= INSTRUCTION_BUDGET {
+                return;
+            }
+            let next = ledger::with(|ledger| {
+                let job = ledger.jobs.get(&id)?;
+                let item = job.items.get(job.outcomes.len())?.clone();
+                if ledger.is_paused(&operation_of(&item)) {
+                    return None;
+                }
+                Some((job.submitted_by, job.kind, item))
+            });
+            let (submitted_by, kind, item) = match next {
+                Some(next) => next,
+                None => break,
+            };
+
+            let minted_to = match (&kind, &item) {
+                (JobKind::BatchMint, JobItem::Mint { to, .. }) => Some(*to),
+                _ => None,
+            };
+            let outcome = run(submitted_by, item);
+            if let (Some(to), JobOutcome::Failed(_)) = (minted_to, &outcome) {
+                mint_phase::release(&User::principal(to), 1);
+            }
+            ledger::with_mut(|ledger| {
+                if let Some(job) = ledger.jobs.get_mut(&id) {
+                    job.outcomes.push(outcome);
+                    if job.outcomes.len() == job.items.len() {
+                        job.completed_at = Some(time());
+                    }
+                }
+            });
+        }
+    }
+}
+
+pub fn job_progress(id: u64) -> Option<JobProgress> {
+    ledger::with(|ledger| {
+        ledger.jobs.get(&id).map(|job| JobProgress {
+            id: job.id,
+            kind: job.kind,
+            submitted_by: job.submitted_by,
+            submitted_at: job.submitted_at,
+            total: job.items.len() as u64,
+            processed: job.outcomes.len() as u64,
+            failed: job
+                .outcomes
+                .iter()
+                .filter(|outcome| matches!(outcome, JobOutcome::Failed(_)))
+                .count() as u64,
+            completed_at: job.completed_at,
+        })
+    })
+}
+
+// Per-item outcomes in submission order, a page at a time.
+pub fn job_outcomes(id: u64, offset: u64, limit: u64) -> Vec<JobOutcome> {
+    ledger::with(|ledger| {
+        ledger
+            .jobs
+            .get(&id)
+            .map(|job| {
+                job.outcomes
+                    .iter()
+                    .skip(offset as usize)
+                    .take(limit as usize)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::testing::{self, owner_of, principal};
+    use crate::module::types::{MintPhase, MintPhaseKind};
+    use ic_cdk::export::candid::Nat;
+
+    const DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+    fn mint_request(to: Principal) -> MintRequest {
+        MintRequest {
+            to: User::principal(to),
+            metadata: None,
+            class: "H".into(),
+            created_at_time: None,
+        }
+    }
+
+    fn transfer_v2(from: Principal, to: Principal, token_list: Vec<Token_ID>) -> TransferRequestV2 {
+        TransferRequestV2 {
+            amount: Nat::from(1u32),
+            from: User::principal(from),
+            memo: vec![],
+            notify: false,
+            subaccount: None,
+            to: User::principal(to),
+            token_list,
+            created_at_time: None,
+        }
+    }
+
+    fn entry(to: User, class: &str) -> AirdropEntry {
+        AirdropEntry {
+            to,
+            class: class.into(),
+            metadata: None,
+        }
+    }
+
+    fn job_count() -> u64 {
+        ledger::with(|ledger| ledger.jobs.len() as u64)
+    }
+
+    #[test]
+    fn airdrops_are_minted_by_the_heartbeat() {
+        testing::setup();
+        let next = dip721::tid_info();
+        let mut with_metadata = entry(User::principal(principal(2)), "Li");
+        with_metadata.metadata = Some(vec![1, 2, 3]);
+        let id = submit_airdrop(vec![
+            entry(User::principal(principal(1)), "H"),
+            with_metadata,
+        ])
+        .unwrap();
+        assert!(matches!(job_progress(id).unwrap().kind, JobKind::Airdrop));
+        assert_eq!(job_progress(id).unwrap().processed, 0);
+
+        process_queued();
+
+        let progress = job_progress(id).unwrap();
+        assert_eq!(progress.processed, 2);
+        assert_eq!(progress.failed, 0);
+        assert!(progress.completed_at.is_some());
+        assert_eq!(owner_of(&Nat::from(next)), Some(principal(1)));
+        let second = Nat::from(next + 1);
+        assert_eq!(owner_of(&second), Some(principal(2)));
+        let properties =
+            ledger::with(|ledger| ledger.token_metadata(&second).unwrap().properties.clone());
+        assert!(properties.contains(&("metadata".into(), GeneralValue::BlobContent(vec![1, 2, 3]))));
+    }
+
+    #[test]
+    fn airdrops_are_refused_while_minting_is_paused() {
+        testing::setup();
+        ledger::with_mut(|ledger| ledger.set_paused(Operation::Mint, true));
+
+        assert!(matches!(
+            submit_airdrop(vec![entry(User::principal(principal(1)), "H")]),
+            Err(NftError::OperationPaused(Operation::Mint))
+        ));
+        assert_eq!(job_count(), 0);
+    }
+
+    #[test]
+    fn airdrops_check_every_entry_before_queueing() {
+        testing::setup();
+
+        assert!(matches!(
+            submit_airdrop(vec![]),
+            Err(NftError::InvalidRecipient(0))
+        ));
+        assert!(matches!(
+            submit_airdrop(vec![
+                entry(User::principal(principal(1)), "H"),
+                entry(User::address("00".repeat(32)), "H"),
+            ]),
+            Err(NftError::InvalidRecipient(1))
+        ));
+        assert!(matches!(
+            submit_airdrop(vec![entry(User::principal(principal(1)), "Xx")]),
+            Err(NftError::ClassNotFound(_))
+        ));
+        assert_eq!(job_count(), 0);
+    }
+
+    #[test]
+    fn airdrops_need_the_minter_role() {
+        testing::setup();
+        env::set_caller(principal(1));
+
+        assert!(matches!(
+            submit_airdrop(vec![entry(User::principal(principal(1)), "H")]),
+            Err(NftError::MissingRole(Role::Minter))
+        ));
+    }
+
+    #[test]
+    fn queued_mints_wait_while_minting_is_paused() {
+        testing::setup();
+        let id = submit_airdrop(vec![entry(User::principal(principal(1)), "H")]).unwrap();
+        ledger::with_mut(|ledger| ledger.set_paused(Operation::Mint, true));
+
+        process_queued();
+        assert_eq!(job_progress(id).unwrap().processed, 0);
+
+        ledger::with_mut(|ledger| ledger.set_paused(Operation::Mint, false));
+        process_queued();
+        assert_eq!(job_progress(id).unwrap().processed, 1);
+    }
+
+    #[test]
+    fn queued_jobs_wait_once_the_instruction_budget_is_spent() {
+        testing::setup();
+        let id = submit_airdrop(vec![entry(User::principal(principal(1)), "H")]).unwrap();
+        env::set_instruction_counter(INSTRUCTION_BUDGET);
+
+        process_queued();
+        assert_eq!(job_progress(id).unwrap().processed, 0);
+
+        env::set_instruction_counter(0);
+        process_queued();
+        assert_eq!(job_progress(id).unwrap().processed, 1);
+    }
+
+    #[test]
+    fn batch_mint_jobs_need_a_bounded_positive_quantity() {
+        testing::setup();
+
+        assert!(matches!(
+            submit_batch_mint(mint_request(principal(1)), 0),
+            Err(NftError::InvalidQuantity)
+        ));
+        assert!(matches!(
+            submit_batch_mint(mint_request(principal(1)), MAX_JOB_ITEMS as u32 + 1),
+            Err(NftError::InvalidQuantity)
+        ));
+        assert_eq!(job_count(), 0);
+    }
+
+    #[test]
+    fn failed_batch_mint_items_give_their_quota_back() {
+        testing::setup();
+        mint_phase::set_mint_phases(vec![MintPhase {
+            name: "public".into(),
+            kind: MintPhaseKind::Public,
+            start_at: time(),
+            end_at: time() + DAY,
+            wallet_cap: Some(2),
+        }])
+        .unwrap();
+        let buyer = User::principal(principal(1));
+        let id = submit_batch_mint(mint_request(principal(1)), 2).unwrap();
+        assert_eq!(mint_phase::available(&buyer).unwrap(), Some(0));
+
+        // Take the identifier the first item will be given, so its mint fails.
+        let next = Nat::from(dip721::tid_info());
+        ledger::with_mut(|ledger| {
+            let custodian = testing::custodian();
+            dip721::mint_token(
+                ledger,
+                custodian,
+                custodian,
+                &next,
+                prop::properties_of("H"),
+            )
+        })
+        .unwrap();
+        process_queued();
+
+        let progress = job_progress(id).unwrap();
+        assert_eq!(progress.processed, 2);
+        assert!(progress.failed > 0);
+        assert_eq!(
+            mint_phase::available(&buyer).unwrap(),
+            Some(progress.failed as u32)
+        );
+    }
+
+    #[test]
+    fn transfer_jobs_check_the_callers_authority_when_submitted() {
+        testing::setup();
+        let (owner, operator, to) = (principal(1), principal(2), principal(3));
+        let token = testing::mint(owner);
+
+        env::set_caller(operator);
+        assert!(matches!(
+            submit_batch_transfer_v2(transfer_v2(owner, to, vec![token.clone()])),
+            Err(NftError::UnauthorizedOperator)
+        ));
+        assert!(matches!(
+            submit_batch_transfer_v1(TransferRequestV1 {
+                amount: Nat::from(1u32),
+                from: User::principal(owner),
+                memo: vec![],
+                notify: false,
+                subaccount: None,
+                to: User::principal(to),
+                class: "H".into(),
+                num: 1,
+                created_at_time: None,
+            }),
+            Err(NftError::UnauthorizedOperator)
+        ));
+        assert_eq!(job_count(), 0);
+
+        env::set_caller(owner);
+        dip721::dip721_approve(operator, token.clone()).unwrap();
+        env::set_caller(operator);
+        assert!(matches!(
+            submit_batch_transfer_v2(transfer_v2(owner, to, vec![])),
+            Err(NftError::InvalidQuantity)
+        ));
+        assert!(matches!(
+            submit_batch_transfer_v2(transfer_v2(owner, to, vec![token.clone(), token.clone()])),
+            Err(NftError::DuplicateToken(_))
+        ));
+        submit_batch_transfer_v2(transfer_v2(owner, to, vec![token.clone()])).unwrap();
+        process_queued();
+        assert_eq!(owner_of(&token), Some(to));
+    }
+
+    #[test]
+    fn completed_jobs_are_dropped_after_the_retention_period() {
+        testing::setup();
+        let id = submit_airdrop(vec![entry(User::principal(principal(1)), "H")]).unwrap();
+        process_queued();
+        assert!(job_progress(id).unwrap().completed_at.is_some());
+
+        env::advance_time(JOB_RETENTION - 1);
+        process_queued();
+        assert!(job_progress(id).is_some());
+
+        env::advance_time(1);
+        process_queued();
+        assert!(job_progress(id).is_none());
+    }
+}
@@ -1,11 +1,11 @@
 use std::cell::RefCell;
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use cap_sdk::{handshake};
 
-use ic_cdk::api::{time};
 use ic_cdk::export::candid::{CandidType, Deserialize, Nat};
 use ic_cdk::export::Principal;
 
+use crate::module::env::time;
 use crate::module::types::*;
 
 thread_local! {
@@ -27,6 +27,29 @@ pub struct Ledger {
     pub tokens: HashMap<Token_ID, TokenMetaData>,
     pub owners: HashMap<Principal, HashSet<Token_ID>>,
     pub operators: HashMap<Principal, HashSet<Token_ID>>,
+    pub locks: HashMap<Token_ID, TokenLock>,
+    pub escrows: HashMap<Token_ID, TokenLock>,
+    pub offers: HashMap<u64, SwapOffer>,
+    pub offer_count: u64,
+    pub listings: HashMap<Token_ID, Listing>,
+    pub sales: HashMap<Token_ID, Sale>,
+    pub sale_count: u64,
+    pub failed_payouts: Vec<Payout>,
+    pub auctions: HashMap<u64, Auction>,
+    pub auction_count: u64,
+    pub jobs: HashMap<u64, Job>,
+    pub job_count: u64,
+    pub approvals: HashMap<Token_ID, ApprovalInfo>,
+    pub collection_approvals: HashMap<Principal, HashMap<Principal, ApprovalInfo>>,
+    // Candid-encoded results of recent requests, keyed by `dedup::key`.
+    pub recent_transactions: HashMap<Vec<u8>, (u64, Vec<u8>)>,
+    // The most recent ICRC-3 blocks; `first_block_index` is the index of
+    // `blocks[0]`, i.e. how many older blocks have been archived.
+    pub blocks: VecDeque<Value>,
+    pub first_block_index: u64,
+    pub last_block_hash: Option<Vec<u8>>,
+    // Where blocks before `first_block_index` went; see `icrc3::set_archive`.
+    pub block_archive: Option<Principal>,
     pub tx_count: Nat,
 }
 
@@ -38,6 +61,7 @@ impl Ledger {
             metadata.name = args.name;
             metadata.logo = args.logo;
             metadata.symbol = args.symbol;
+            metadata.icp_ledger = args.icp_ledger;
             if let Some(custodians) = args.custodians {
                 for custodian in custodians {
                      metadata.custodians.insert(custodian);
@@ -60,6 +84,48 @@ impl Ledger {
         &mut self.metadata
     }
 
+    pub fn is_custodian(&self, principal: &Principal) -> bool {
+        self.metadata.custodians.contains(principal)
+    }
+
+    pub fn has_role(&self, principal: &Principal, role: &Role) -> bool {
+        self.is_custodian(principal)
+            || self
+                .metadata
+                .roles
+                .get(principal)
+                .map_or(false, |roles| roles.contains(role))
+    }
+
+    pub fn grant_role(&mut self, principal: Principal, role: Role) {
+        self.metadata
+            .roles
+            .entry(principal)
+            .or_default()
+            .insert(role);
+    }
+
+    pub fn revoke_role(&mut self, principal: &Principal, role: &Role) {
+        if let Some(roles) = self.metadata.roles.get_mut(principal) {
+            roles.remove(role);
+            if roles.is_empty() {
+                self.metadata.roles.remove(principal);
+            }
+        }
+    }
+
+    pub fn is_paused(&self, operation: &Operation) -> bool {
+        self.metadata.paused.contains(operation)
+    }
+
+    pub fn set_paused(&mut self, operation: Operation, paused: bool) {
+        if paused {
+            self.metadata.paused.insert(operation);
+        } else {
+            self.metadata.paused.remove(&operation);
+        }
+    }
+
     pub fn tokens_count(&self) -> usize {
         self.tokens.len()
     }
@@ -124,7 +190,7 @@ impl Ledger {
         if let Some(new_owner) = new_owner {
             self.owners
                 .entry(new_owner)
-                .or_insert_with(HashSet::new)
+                .or_default()
                 .insert(token_identifier.clone());
         }
     }
@@ -165,7 +231,7 @@ impl Ledger {
         if let Some(new_operator) = new_operator {
             self.operators
                 .entry(new_operator)
-                .or_insert_with(HashSet::new)
+                .or_default()
                 .insert(token_identifier.clone());
         }
     }
@@ -199,6 +265,12 @@ impl Ledger {
         token_metadata.transferred_by = Some(transferred_by);
         token_metadata.transferred_at = Some(time());
         token_metadata.operator = None;
+        token_metadata.user = None;
+        token_metadata.user_expires = None;
+        self.approvals.remove(token_identifier);
+        self.locks.remove(token_identifier);
+        self.escrows.remove(token_identifier);
+        self.listings.remove(token_identifier);
     }
 
     pub fn burn(&mut self,
@@ -213,8 +285,86 @@ impl Ledger {
             token_metadata.is_burned = true;
             token_metadata.burned_by = Some(burned_by);
             token_metadata.burned_at = Some(time());
+            token_metadata.user = None;
+            token_metadata.user_expires = None;
+            self.approvals.remove(token_identifier);
+            self.locks.remove(token_identifier);
+            self.escrows.remove(token_identifier);
+            self.listings.remove(token_identifier);
         }
 
+    pub fn set_user(
+        &mut self,
+        token_identifier: &Token_ID,
+        user: Option<Principal>,
+        expires: Option<u64>,
+    ) {
+        let token_metadata = self
+            .tokens
+            .get_mut(token_identifier)
+            .expect("could not find token metadata");
+        token_metadata.user = user;
+        token_metadata.user_expires = expires;
+    }
+
+    pub fn set_soulbound(&mut self, token_identifier: &Token_ID, soulbound: bool) {
+        let token_metadata = self
+            .tokens
+            .get_mut(token_identifier)
+            .expect("could not find token metadata");
+        token_metadata.soulbound = soulbound;
+    }
+
+    // Game locks, placed and lifted by game operators.
+    pub fn active_lock(&self, token_identifier: &Token_ID) -> Option<&TokenLock> {
+        self.locks
+            .get(token_identifier)
+            .filter(|lock| lock.is_active(time()))
+    }
+
+    // Escrow taken by swaps, sales and auctions. It is kept apart from game
+    // locks so a game operator can neither replace nor lift it.
+    pub fn active_escrow(&self, token_identifier: &Token_ID) -> Option<&TokenLock> {
+        self.escrows
+            .get(token_identifier)
+            .filter(|escrow| escrow.is_active(time()))
+    }
+
+    pub fn is_locked(&self, token_identifier: &Token_ID) -> bool {
+        self.active_lock(token_identifier).is_some()
+            || self.active_escrow(token_identifier).is_some()
+    }
+
+    pub fn lock(&mut self, token_identifier: Token_ID, lock: TokenLock) {
+        self.locks.insert(token_identifier, lock);
+    }
+
+    pub fn unlock(&mut self, token_identifier: &Token_ID) -> Option<TokenLock> {
+        self.locks.remove(token_identifier)
+    }
+
+    // A buyer may have paid into the sale's subaccount, so the token stays put
+    // until the sale is settled or refunded.
+    pub fn has_pending_sale(&self, token_identifier: &Token_ID) -> bool {
+        self.sales.contains_key(token_identifier)
+    }
+
+    pub fn escrow(&mut self, token_identifier: Token_ID, escrow: TokenLock) {
+        self.escrows.insert(token_identifier, escrow);
+    }
+
+    // Lifts the escrow only if it was taken for `reason`.
+    pub fn release_escrow(&mut self, token_identifier: &Token_ID, reason: &str) -> Option<TokenLock> {
+        if self
+            .escrows
+            .get(token_identifier)
+            .map_or(false, |escrow| escrow.reason == reason)
+        {
+            return self.escrows.remove(token_identifier);
+        }
+        None
+    }
+
     pub fn inc_tx(&mut self) -> Nat {
         self.tx_count += 1;
         self.tx_count.clone()
@@ -0,0 +1,194 @@
+use crate::module::cap::insert_sync;
+use crate::module::env::{self, time};
+use crate::module::ledger;
+use crate::module::types::{NftError, Role, TokenLock, Token_ID};
+use cap_sdk::{DetailValue, IndefiniteEvent};
+use ic_cdk::export::Principal;
+
+pub fn lock(token_identifier: Token_ID, reason: String, expires_at: u64) -> Result<(), NftError> {
+    ledger::with_mut(|ledger| {
+        let caller = env::caller();
+        if !ledger.has_role(&caller, &Role::GameOperator) {
+            return Err(NftError::MissingRole(Role::GameOperator));
+        }
+        let now = time();
+        if expires_at <= now {
+            return Err(NftError::InvalidExpiry);
+        }
+        let owner = match ledger.owner_of(&token_identifier)? {
+            Some(owner) => owner,
+            None => return Err(NftError::OwnerNotFound),
+        };
+        if ledger.active_escrow(&token_identifier).is_some() {
+            return Err(NftError::TokenLocked);
+        }
+        // A buyer may already have paid; the token must stay free to settle.
+        if ledger.has_pending_sale(&token_identifier) {
+            return Err(NftError::SalePending);
+        }
+        ledger.lock(
+            token_identifier.clone(),
+            TokenLock {
+                owner,
+                locked_by: caller,
+                reason: reason.clone(),
+                locked_at: now,
+                expires_at,
+            },
+        );
+
+        insert_sync(IndefiniteEvent {
+            caller,
+            operation: "lock".into(),
+            details: vec![
+                (
+                    "token_identifier".into(),
+                    DetailValue::from(token_identifier.to_string()),
+                ),
+                ("owner".into(), DetailValue::from(owner)),
+                ("reason".into(), DetailValue::from(reason)),
+                ("expires_at".into(), DetailValue::U64(expires_at)),
+            ],
+        });
+
+        Ok(())
+    })
+}
+
+pub fn unlock(token_identifier: Token_ID) -> Result<(), NftError> {
+    ledger::with_mut(|ledger| {
+        let caller = env::caller();
+        if !ledger.has_role(&caller, &Role::GameOperator) {
+            return Err(NftError::MissingRole(Role::GameOperator));
+        }
+        ledger.token_metadata(&token_identifier)?;
+        if ledger.unlock(&token_identifier).is_none() {
+            return Ok(());
+        }
+
+        insert_sync(IndefiniteEvent {
+            caller,
+            operation: "unlock".into(),
+            details: vec![(
+                "token_identifier".into(),
+                DetailValue::from(token_identifier.to_string()),
+            )],
+        });
+
+        Ok(())
+    })
+}
+
+// The lock holding the token in place, whether a game lock or an escrow.
+pub fn lock_of(token_identifier: Token_ID) -> Option<TokenLock> {
+    ledger::with(|ledger| {
+        ledger
+            .active_lock(&token_identifier)
+            .or_else(|| ledger.active_escrow(&token_identifier))
+            .cloned()
+    })
+}
+
+// Locks are keyed by token, so a player's locked items are those of their
+// tokens that still carry an active lock or escrow.
+pub fn locked_tokens_of(owner: Principal) -> Vec<(Token_ID, TokenLock)> {
+    ledger::with(|ledger| match ledger.owner_token_identifiers(&owner) {
+        Ok(tokens) => tokens
+            .iter()
+            .filter_map(|token_identifier| {
+                ledger
+                    .active_lock(token_identifier)
+                    .or_else(|| ledger.active_escrow(token_identifier))
+                    .map(|lock| (token_identifier.clone(), lock.clone()))
+            })
+            .collect(),
+        Err(_) => vec![],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::env;
+    use crate::module::testing::{self, principal};
+
+    const HOUR: u64 = 60 * 60 * 1_000_000_000;
+
+    fn game_operator() -> Principal {
+        let operator = principal(7);
+        testing::grant(operator, Role::GameOperator);
+        env::set_caller(operator);
+        operator
+    }
+
+    fn escrow(token_identifier: &Token_ID) {
+        ledger::with_mut(|ledger| {
+            ledger.escrow(
+                token_identifier.clone(),
+                TokenLock {
+                    owner: principal(1),
+                    locked_by: env::id(),
+                    reason: "swap offer 0".into(),
+                    locked_at: time(),
+                    expires_at: time() + HOUR,
+                },
+            )
+        });
+    }
+
+    #[test]
+    fn game_operators_lock_and_unlock() {
+        testing::setup();
+        let token_identifier = testing::mint(principal(1));
+        let operator = game_operator();
+
+        lock(token_identifier.clone(), "quest".into(), time() + HOUR).unwrap();
+        let held = lock_of(token_identifier.clone()).unwrap();
+        assert_eq!(held.locked_by, operator);
+        assert_eq!(locked_tokens_of(principal(1)).len(), 1);
+
+        unlock(token_identifier.clone()).unwrap();
+        assert!(lock_of(token_identifier).is_none());
+    }
+
+    #[test]
+    fn locking_requires_the_game_operator_role() {
+        testing::setup();
+        let token_identifier = testing::mint(principal(1));
+        env::set_caller(principal(1));
+
+        assert!(matches!(
+            lock(token_identifier, "quest".into(), time() + HOUR),
+            Err(NftError::MissingRole(Role::GameOperator))
+        ));
+    }
+
+    #[test]
+    fn locks_expire() {
+        testing::setup();
+        let token_identifier = testing::mint(principal(1));
+        game_operator();
+
+        lock(token_identifier.clone(), "quest".into(), time() + HOUR).unwrap();
+        env::advance_time(HOUR);
+        assert!(lock_of(token_identifier.clone()).is_none());
+        assert!(!ledger::with(|ledger| ledger.is_locked(&token_identifier)));
+    }
+
+    #[test]
+    fn game_operators_cannot_touch_escrow() {
+        testing::setup();
+        let token_identifier = testing::mint(principal(1));
+        escrow(&token_identifier);
+        game_operator();
+
+        assert!(matches!(
+            lock(token_identifier.clone(), "quest".into(), time() + HOUR),
+            Err(NftError::TokenLocked)
+        ));
+        unlock(token_identifier.clone()).unwrap();
+        let held = lock_of(token_identifier.clone()).unwrap();
+        assert_eq!(held.reason, "swap offer 0");
+        assert!(ledger::with(|ledger| ledger.is_locked(&token_identifier)));
+    }
+}
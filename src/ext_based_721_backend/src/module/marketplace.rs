@@ -0,0 +1,779 @@
+use crate::module::cap::insert_sync;
+use crate::module::dip721;
+use crate::module::env::{self, time};
+use crate::module::icp_ledger;
+use crate::module::ledger::{self, Ledger};
+use crate::module::rbac;
+use crate::module::royalty;
+use crate::module::token_identifier::{self, TokenIdentifier};
+use crate::module::types::{
+    CommonError, ListRequest, Listing, NftError, Operation, Payout, Role, Royalty, Sale,
+    TokenLock, Token_ID,
+};
+use cap_sdk::{DetailValue, IndefiniteEvent};
+use ic_cdk::export::candid::{Int, Nat};
+use ic_ledger_types::Subaccount;
+use std::collections::{HashMap, HashSet};
+
+// How long a buyer has to pay after locking a listing, in nanoseconds.
+const LOCK_DURATION: u64 = 5 * 60 * 1_000_000_000;
+const SALE_LOCK_REASON: &str = "sale";
+const SALE_MEMO: u64 = 0x5341_4c45;
+
+fn other(msg: &str) -> CommonError {
+    CommonError::Other(msg.into())
+}
+
+pub fn decode(token: &TokenIdentifier) -> Result<Token_ID, CommonError> {
+    let canister = token_identifier::CanisterId(env::id());
+    if !token_identifier::is_valid_token_id(token, &canister) {
+        return Err(CommonError::InvalidToken(token.clone()));
+    }
+    Ok(Nat::from(token_identifier::get_token_index(token).get_value()))
+}
+
+pub fn to_subaccount(bytes: &[u8]) -> Subaccount {
+    let mut subaccount = [0u8; 32];
+    subaccount.copy_from_slice(bytes);
+    Subaccount(subaccount)
+}
+
+fn is_sale_locked(ledger: &Ledger, token_identifier: &Token_ID) -> bool {
+    ledger
+        .active_escrow(token_identifier)
+        .map_or(false, |lock| lock.reason == SALE_LOCK_REASON)
+}
+
+// The listing as EXT clients expect it, with `locked` set while a buyer holds it.
+pub fn listing_of(ledger: &Ledger, token_identifier: &Token_ID) -> Option<Listing> {
+    ledger.listings.get(token_identifier).map(|listing| Listing {
+        locked: ledger
+            .active_escrow(token_identifier)
+            .filter(|lock| lock.reason == SALE_LOCK_REASON)
+            .map(|lock| Int::from(lock.expires_at)),
+        price: listing.price,
+        seller: listing.seller,
+    })
+}
+
+pub fn set_marketplace_fee(fee: Option<Royalty>) -> Result<(), NftError> {
+    rbac::ensure_role(Role::Admin)?;
+    if fee.as_ref().map_or(false, |fee| !fee.is_valid()) {
+        return Err(NftError::InvalidRoyalty);
+    }
+    let details = match &fee {
+        Some(fee) => vec![
+            ("recipient".into(), DetailValue::from(fee.recipient.clone())),
+            ("basis_points".into(), DetailValue::U64(fee.basis_points as u64)),
+        ],
+        None => vec![],
+    };
+    ledger::with_mut(|ledger| ledger.metadata_mut().marketplace_fee = fee);
+
+    insert_sync(IndefiniteEvent {
+        caller: env::caller(),
+        operation: "setMarketplaceFee".into(),
+        details,
+    });
+
+    Ok(())
+}
+
+pub fn list(request: ListRequest) -> Result<(), CommonError> {
+    let token_identifier = decode(&request.token)?;
+    ledger::with_mut(|ledger| {
+        let caller = env::caller();
+        let token = ledger
+            .token_metadata(&token_identifier)
+            .map_err(|_| CommonError::InvalidToken(request.token.clone()))?;
+        if token.owner != Some(caller) {
+            return Err(other("Unauthorized"));
+        }
+        if is_sale_locked(ledger, &token_identifier) {
+            return Err(other("Listing is locked"));
+        }
+        let operation = match request.price {
+            Some(price) => {
+                if ledger.is_paused(&Operation::Transfer) {
+                    return Err(other("Transfers are paused"));
+                }
+                if dip721::is_soulbound(token) {
+                    return Err(other("Token is not transferable"));
+                }
+                if ledger.is_locked(&token_identifier) {
+                    return Err(other("Token is locked"));
+                }
+                ledger.listings.insert(
+                    token_identifier.clone(),
+                    Listing {
+                        locked: None,
+                        price,
+                        seller: caller,
+                    },
+                );
+                "list"
+            }
+            None => {
+                ledger.listings.remove(&token_identifier);
+                "delist"
+            }
+        };
+
+        let mut details = vec![(
+            "token_identifier".into(),
+            DetailValue::from(token_identifier.to_string()),
+        )];
+        if let Some(price) = request.price {
+            details.push(("price".into(), DetailValue::U64(price)));
+        }
+        insert_sync(IndefiniteEvent {
+            caller,
+            operation: operation.into(),
+            details,
+        });
+
+        Ok(())
+    })
+}
+
+// Reserves a listing for the caller and returns the account to pay into. A
+// previous buyer whose lock has expired is settled first if they did pay, and
+// refunded otherwise.
+pub async fn lock(
+    token: TokenIdentifier,
+    price: u64,
+    address: String,
+) -> Result<String, CommonError> {
+    let token_identifier = decode(&token)?;
+    let caller = env::caller();
+    if address != icp_ledger::default_account(&caller) {
+        return Err(other("Tokens can only be sent to the caller's default account"));
+    }
+
+    let previous = ledger::with(|ledger| {
+        if is_sale_locked(ledger, &token_identifier) {
+            return Err(other("Listing is locked"));
+        }
+        Ok(ledger.sales.get(&token_identifier).cloned())
+    })?;
+    if let Some(previous) = previous {
+        if settle_sale(&token_identifier, previous).await? {
+            return Err(other("Listing has been sold"));
+        }
+    }
+
+    ledger::with_mut(|ledger| {
+        let listing = ledger
+            .listings
+            .get(&token_identifier)
+            .cloned()
+            .ok_or_else(|| other("No listing"))?;
+        if listing.price != price {
+            return Err(other("Price has changed"));
+        }
+        if listing.seller == caller {
+            return Err(other("Cannot buy your own listing"));
+        }
+        if ledger.is_locked(&token_identifier) {
+            return Err(other("Listing is locked"));
+        }
+
+        let now = time();
+        let nonce = ledger.sale_count;
+        ledger.sale_count += 1;
+        let subaccount = icp_ledger::derive_subaccount(
+            b"sale",
+            &[
+                token_identifier.to_string().as_bytes(),
+                &nonce.to_be_bytes(),
+            ],
+        );
+        ledger.sales.insert(
+            token_identifier.clone(),
+            Sale {
+                buyer: caller,
+                seller: listing.seller,
+                price,
+                subaccount: subaccount.0.to_vec(),
+                expires_at: now + LOCK_DURATION,
+            },
+        );
+        ledger.escrow(
+            token_identifier.clone(),
+            TokenLock {
+                owner: listing.seller,
+                locked_by: env::id(),
+                reason: SALE_LOCK_REASON.into(),
+                locked_at: now,
+                expires_at: now + LOCK_DURATION,
+            },
+        );
+
+        Ok(icp_ledger::canister_account(&subaccount))
+    })
+}
+
+pub async fn settle(token: TokenIdentifier) -> Result<(), CommonError> {
+    let token_identifier = decode(&token)?;
+    let sale = ledger::with(|ledger| ledger.sales.get(&token_identifier).cloned())
+        .ok_or_else(|| other("Nothing to settle"))?;
+    if settle_sale(&token_identifier, sale).await? {
+        Ok(())
+    } else {
+        Err(other("Insufficient funds sent"))
+    }
+}
+
+// Completes `sale` if its subaccount holds the price. Returns whether the token
+// changed hands. An unpaid sale whose lock has expired is dropped and whatever
+// was sent is refunded to the buyer, as is a paid sale whose token can no
+// longer be transferred, e.g. because transfers were paused in the meantime.
+// Until one of these happens the token cannot be moved, so a buyer's payment
+// is never left without its token.
+async fn settle_sale(token_identifier: &Token_ID, sale: Sale) -> Result<bool, CommonError> {
+    let subaccount = to_subaccount(&sale.subaccount);
+    let balance = icp_ledger::balance_of(&subaccount)
+        .await
+        .map_err(CommonError::Other)?;
+
+    let current = ledger::with(|ledger| ledger.sales.get(token_identifier).cloned());
+    if current.map_or(true, |current| current.subaccount != sale.subaccount) {
+        return Err(other("Sale has changed"));
+    }
+
+    if balance < sale.price {
+        if sale.expires_at <= time() {
+            ledger::with_mut(|ledger| ledger.sales.remove(token_identifier));
+            refund(&sale, balance).await;
+        }
+        return Ok(false);
+    }
+
+    let transferred = ledger::with_mut(|ledger| {
+        let canister = env::id();
+        ledger.sales.remove(token_identifier);
+        ledger.release_escrow(token_identifier, SALE_LOCK_REASON);
+        if let Err(err) = dip721::transfer_token(ledger, canister, token_identifier, sale.buyer) {
+            // The listing cannot be honoured as long as the token is stuck.
+            ledger.listings.remove(token_identifier);
+            return Err(err);
+        }
+
+        insert_sync(IndefiniteEvent {
+            caller: env::caller(),
+            operation: "sale".into(),
+            details: vec![
+                (
+                    "token_identifier".into(),
+                    DetailValue::from(token_identifier.to_string()),
+                ),
+                ("seller".into(), DetailValue::from(sale.seller)),
+                ("buyer".into(), DetailValue::from(sale.buyer)),
+                ("price".into(), DetailValue::U64(sale.price)),
+            ],
+        });
+        ledger.inc_tx();
+        Ok(())
+    });
+    if let Err(err) = transferred {
+        refund(&sale, balance).await;
+        return Err(other(&format!(
+            "Token can no longer be transferred ({}); the payment was refunded",
+            err.message()
+        )));
+    }
+
+    disburse(token_identifier, &sale, balance).await;
+
+    Ok(true)
+}
+
+// Returns everything a sale's subaccount holds to the buyer.
+async fn refund(sale: &Sale, balance: u64) {
+    if balance > icp_ledger::fee() {
+        payout(Payout {
+            subaccount: sale.subaccount.clone(),
+            to: icp_ledger::default_account(&sale.buyer),
+            amount: balance,
+            memo: SALE_MEMO,
+            created_at_time: time(),
+            error: String::new(),
+        })
+        .await;
+    }
+}
+
+// Pays out a completed sale from its subaccount.
+pub async fn disburse(token_identifier: &Token_ID, sale: &Sale, balance: u64) {
+    let royalty = royalty::royalty_info(token_identifier, sale.price).ok().flatten();
+    let fee = ledger::with(|ledger| ledger.metadata.marketplace_fee.clone());
+    for payout_item in distribution(sale, balance, royalty, fee) {
+        payout(payout_item).await;
+    }
+}
+
+// Splits a sale's balance into royalty, marketplace fee, the seller's share and
+// a refund of anything sent above the price. Each payout gets its own memo so
+// two payouts of the same amount to the same account are not mistaken for
+// duplicates by the ledger.
+fn distribution(
+    sale: &Sale,
+    balance: u64,
+    royalty: Option<(String, u64)>,
+    fee: Option<Royalty>,
+) -> Vec<Payout> {
+    let created_at_time = time();
+    let payout_to = |to: String, amount: u64| Payout {
+        subaccount: sale.subaccount.clone(),
+        to,
+        amount,
+        memo: SALE_MEMO,
+        created_at_time,
+        error: String::new(),
+    };
+
+    let mut payouts = Vec::new();
+    let mut remaining = sale.price;
+    if let Some((recipient, amount)) = royalty {
+        remaining -= amount;
+        payouts.push(payout_to(recipient, amount));
+    }
+    if let Some(fee) = fee {
+        let amount = fee.amount(sale.price).min(remaining);
+        remaining -= amount;
+        payouts.push(payout_to(fee.recipient, amount));
+    }
+    payouts.push(payout_to(icp_ledger::default_account(&sale.seller), remaining));
+    if balance > sale.price {
+        payouts.push(payout_to(
+            icp_ledger::default_account(&sale.buyer),
+            balance - sale.price,
+        ));
+    }
+
+    payouts
+        .into_iter()
+        .filter(|payout| payout.amount > icp_ledger::fee())
+        .enumerate()
+        .map(|(index, payout)| Payout {
+            memo: SALE_MEMO + index as u64,
+            ..payout
+        })
+        .collect()
+}
+
+// Failed payouts are kept so an admin can retry them with the same memo and
+// creation time.
+pub async fn payout(mut payout: Payout) {
+    let result = icp_ledger::transfer(
+        to_subaccount(&payout.subaccount),
+        &payout.to,
+        payout.amount,
+        payout.memo,
+        payout.created_at_time,
+    )
+    .await;
+    if let Err(err) = result {
+        payout.error = err;
+        ledger::with_mut(|ledger| ledger.failed_payouts.push(payout));
+    }
+}
+
+pub fn failed_payouts() -> Vec<Payout> {
+    ledger::with(|ledger| ledger.failed_payouts.clone())
+}
+
+// What the failed payouts waiting to be retried still owe out of `subaccount`.
+pub fn pending_payouts_from(subaccount: &[u8]) -> u64 {
+    ledger::with(|ledger| {
+        ledger
+            .failed_payouts
+            .iter()
+            .filter(|payout| payout.subaccount == subaccount)
+            .map(|payout| payout.amount)
+            .sum()
+    })
+}
+
+// Retries every failed payout. Inside the ledger's deduplication window a retry
+// keeps its creation time, so a payout whose reply was lost is not sent twice.
+// Past the window the ledger would reject that time as too old; the payout is
+// then sent with a fresh one, but only while its subaccount still holds every
+// payout that failed from it. Otherwise an earlier attempt may have gone
+// through after all and the payout stays parked for an admin to look into.
+pub async fn retry_failed_payouts() -> Result<u64, NftError> {
+    rbac::ensure_role(Role::Admin)?;
+    let payouts = ledger::with_mut(|ledger| std::mem::take(&mut ledger.failed_payouts));
+    let count = payouts.len() as u64;
+
+    let now = time();
+    let mut owed = HashMap::<Vec<u8>, u64>::new();
+    for payout_item in payouts.iter() {
+        *owed.entry(payout_item.subaccount.clone()).or_default() += payout_item.amount;
+    }
+    let mut covered = HashSet::new();
+    for payout_item in payouts.iter() {
+        if icp_ledger::deduplicates(payout_item.created_at_time, now)
+            || covered.contains(&payout_item.subaccount)
+        {
+            continue;
+        }
+        let balance = icp_ledger::balance_of(&to_subaccount(&payout_item.subaccount)).await;
+        if balance.map_or(false, |balance| balance >= owed[&payout_item.subaccount]) {
+            covered.insert(payout_item.subaccount.clone());
+        }
+    }
+
+    for mut payout_item in payouts {
+        if !icp_ledger::deduplicates(payout_item.created_at_time, now) {
+            if !covered.contains(&payout_item.subaccount) {
+                payout_item.error =
+                    "past the ledger's deduplication window and no longer covered by the subaccount"
+                        .into();
+                ledger::with_mut(|ledger| ledger.failed_payouts.push(payout_item));
+                continue;
+            }
+            payout_item.created_at_time = time();
+        }
+        payout(payout_item).await;
+    }
+    Ok(count)
+}
+
+pub fn listings() -> Vec<(Token_ID, Listing)> {
+    ledger::with(|ledger| {
+        ledger
+            .listings
+            .keys()
+            .filter_map(|token_identifier| {
+                listing_of(ledger, token_identifier).map(|listing| (token_identifier.clone(), listing))
+            })
+            .collect()
+    })
+}
+
+pub fn details(token: TokenIdentifier) -> Result<(String, Option<Listing>), CommonError> {
+    let token_identifier = decode(&token)?;
+    ledger::with(|ledger| {
+        let owner = ledger
+            .owner_of(&token_identifier)
+            .ok()
+            .flatten()
+            .ok_or_else(|| CommonError::InvalidToken(token.clone()))?;
+        Ok((
+            icp_ledger::default_account(&owner),
+            listing_of(ledger, &token_identifier),
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::icp_ledger::stand_in;
+    use crate::module::lock as game;
+    use crate::module::testing::{self, owner_of, principal};
+    use crate::prop;
+    use ic_cdk::export::Principal;
+
+    const PRICE: u64 = 1_000_000_000;
+    const DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+    fn account(principal: &Principal) -> String {
+        icp_ledger::default_account(principal)
+    }
+
+    fn listed(seller: Principal) -> (Token_ID, TokenIdentifier) {
+        let token_identifier = testing::mint(seller);
+        let token = prop::tokens(&token_identifier);
+        env::set_caller(seller);
+        list(ListRequest {
+            token: token.clone(),
+            from_subaccount: None,
+            price: Some(PRICE),
+        })
+        .unwrap();
+        (token_identifier, token)
+    }
+
+    // Locks the listing for `buyer` and returns the address to pay into.
+    fn lock_as(buyer: Principal, token: &TokenIdentifier) -> String {
+        env::set_caller(buyer);
+        env::block_on(lock(token.clone(), PRICE, account(&buyer))).unwrap()
+    }
+
+    fn transfer_away(seller: Principal, token_identifier: &Token_ID) -> Result<(), NftError> {
+        ledger::with_mut(|ledger| {
+            dip721::transfer_token(ledger, seller, token_identifier, principal(9)).map(|_| ())
+        })
+    }
+
+    #[test]
+    fn list_lock_and_settle() {
+        testing::setup();
+        let (seller, buyer) = (principal(1), principal(2));
+        let (token_identifier, token) = listed(seller);
+
+        let address = lock_as(buyer, &token);
+        let listing = ledger::with(|ledger| listing_of(ledger, &token_identifier)).unwrap();
+        assert!(listing.locked.is_some());
+        assert!(matches!(
+            transfer_away(seller, &token_identifier),
+            Err(NftError::TokenLocked)
+        ));
+
+        stand_in::deposit(&address, PRICE);
+        env::block_on(settle(token)).unwrap();
+
+        assert_eq!(owner_of(&token_identifier), Some(buyer));
+        assert_eq!(stand_in::balance(&account(&seller)), PRICE - icp_ledger::fee());
+        assert_eq!(stand_in::balance(&address), 0);
+        ledger::with(|ledger| {
+            assert!(!ledger.listings.contains_key(&token_identifier));
+            assert!(!ledger.has_pending_sale(&token_identifier));
+            assert!(!ledger.is_locked(&token_identifier));
+        });
+    }
+
+    #[test]
+    fn settling_an_underpaid_sale_fails() {
+        testing::setup();
+        let (seller, buyer) = (principal(1), principal(2));
+        let (token_identifier, token) = listed(seller);
+
+        let address = lock_as(buyer, &token);
+        stand_in::deposit(&address, PRICE - 1);
+
+        assert!(env::block_on(settle(token)).is_err());
+        assert_eq!(owner_of(&token_identifier), Some(seller));
+        assert!(ledger::with(|ledger| ledger.has_pending_sale(&token_identifier)));
+    }
+
+    #[test]
+    fn an_expired_unpaid_lock_is_refunded_and_frees_the_token() {
+        testing::setup();
+        let (seller, buyer) = (principal(1), principal(2));
+        let (token_identifier, token) = listed(seller);
+
+        let address = lock_as(buyer, &token);
+        stand_in::deposit(&address, PRICE / 2);
+        env::advance_time(LOCK_DURATION);
+
+        // The partial payment has to be returned before the token may move.
+        assert!(matches!(
+            transfer_away(seller, &token_identifier),
+            Err(NftError::SalePending)
+        ));
+        assert!(env::block_on(settle(token.clone())).is_err());
+        assert_eq!(
+            stand_in::balance(&account(&buyer)),
+            PRICE / 2 - icp_ledger::fee()
+        );
+        assert!(!ledger::with(|ledger| ledger.has_pending_sale(&token_identifier)));
+
+        // The listing is still up for the next buyer.
+        lock_as(principal(3), &token);
+    }
+
+    #[test]
+    fn a_paid_sale_survives_its_lock_expiring() {
+        testing::setup();
+        let (seller, buyer) = (principal(1), principal(2));
+        let (token_identifier, token) = listed(seller);
+
+        let address = lock_as(buyer, &token);
+        stand_in::deposit(&address, PRICE);
+        env::advance_time(LOCK_DURATION);
+
+        assert!(matches!(
+            transfer_away(seller, &token_identifier),
+            Err(NftError::SalePending)
+        ));
+        env::set_caller(principal(7));
+        env::block_on(settle(token)).unwrap();
+        assert_eq!(owner_of(&token_identifier), Some(buyer));
+    }
+
+    #[test]
+    fn a_paid_sale_whose_token_cannot_move_is_refunded() {
+        testing::setup();
+        let (seller, buyer) = (principal(1), principal(2));
+        let (token_identifier, token) = listed(seller);
+
+        let address = lock_as(buyer, &token);
+        stand_in::deposit(&address, PRICE);
+        ledger::with_mut(|ledger| ledger.set_paused(Operation::Transfer, true));
+
+        assert!(env::block_on(settle(token)).is_err());
+        assert_eq!(owner_of(&token_identifier), Some(seller));
+        assert_eq!(stand_in::balance(&account(&buyer)), PRICE - icp_ledger::fee());
+        assert_eq!(stand_in::balance(&address), 0);
+        ledger::with(|ledger| {
+            assert!(!ledger.listings.contains_key(&token_identifier));
+            assert!(!ledger.has_pending_sale(&token_identifier));
+            assert!(!ledger.is_locked(&token_identifier));
+        });
+    }
+
+    #[test]
+    fn game_locks_wait_for_a_pending_sale() {
+        testing::setup();
+        let (seller, buyer) = (principal(1), principal(2));
+        let (token_identifier, token) = listed(seller);
+        let address = lock_as(buyer, &token);
+        stand_in::deposit(&address, PRICE);
+        env::advance_time(LOCK_DURATION);
+
+        let operator = principal(7);
+        testing::grant(operator, Role::GameOperator);
+        env::set_caller(operator);
+        assert!(matches!(
+            game::lock(token_identifier.clone(), "quest".into(), time() + LOCK_DURATION),
+            Err(NftError::SalePending)
+        ));
+
+        env::block_on(settle(token)).unwrap();
+        assert_eq!(owner_of(&token_identifier), Some(buyer));
+    }
+
+    #[test]
+    fn payouts_split_royalty_fee_seller_and_overpayment() {
+        testing::setup();
+        let (seller, buyer) = (principal(1), principal(2));
+        let (artist, house) = (account(&principal(5)), account(&principal(6)));
+        ledger::with_mut(|ledger| {
+            ledger.metadata_mut().royalty = Some(Royalty {
+                recipient: artist.clone(),
+                basis_points: 500,
+            });
+            ledger.metadata_mut().marketplace_fee = Some(Royalty {
+                recipient: house.clone(),
+                basis_points: 250,
+            });
+        });
+        let (token_identifier, token) = listed(seller);
+
+        let address = lock_as(buyer, &token);
+        let overpayment = 10_000_000;
+        stand_in::deposit(&address, PRICE + overpayment);
+        env::block_on(settle(token)).unwrap();
+
+        let fee = icp_ledger::fee();
+        let royalty = PRICE * 500 / 10_000;
+        let house_fee = PRICE * 250 / 10_000;
+        assert_eq!(stand_in::balance(&artist), royalty - fee);
+        assert_eq!(stand_in::balance(&house), house_fee - fee);
+        assert_eq!(
+            stand_in::balance(&account(&seller)),
+            PRICE - royalty - house_fee - fee
+        );
+        assert_eq!(stand_in::balance(&account(&buyer)), overpayment - fee);
+        assert_eq!(stand_in::balance(&address), 0);
+        assert_eq!(owner_of(&token_identifier), Some(buyer));
+
+        let memos = stand_in::transfers()
+            .iter()
+            .map(|transfer| transfer.memo)
+            .collect::<Vec<_>>();
+        assert_eq!(memos, vec![SALE_MEMO, SALE_MEMO + 1, SALE_MEMO + 2, SALE_MEMO + 3]);
+    }
+
+    #[test]
+    fn the_fee_only_takes_what_the_royalty_leaves() {
+        let sale = Sale {
+            buyer: principal(2),
+            seller: principal(1),
+            price: PRICE,
+            subaccount: vec![0; 32],
+            expires_at: 0,
+        };
+        let fee = Some(Royalty {
+            recipient: account(&principal(6)),
+            basis_points: 10_000,
+        });
+        let payouts = distribution(&sale, PRICE, Some((account(&principal(5)), PRICE)), fee);
+
+        assert_eq!(payouts.len(), 1);
+        assert_eq!(payouts[0].to, account(&principal(5)));
+        assert_eq!(payouts[0].amount, PRICE);
+    }
+
+    #[test]
+    fn retrying_a_payout_whose_reply_was_lost_does_not_pay_twice() {
+        testing::setup();
+        let seller = principal(1);
+        let subaccount = icp_ledger::derive_subaccount(b"test", &[]);
+        stand_in::deposit(&icp_ledger::canister_account(&subaccount), 2 * PRICE);
+
+        stand_in::lose_next_reply();
+        env::block_on(payout(Payout {
+            subaccount: subaccount.0.to_vec(),
+            to: account(&seller),
+            amount: PRICE,
+            memo: SALE_MEMO,
+            created_at_time: time(),
+            error: String::new(),
+        }));
+        assert_eq!(failed_payouts().len(), 1);
+
+        env::set_caller(testing::custodian());
+        env::block_on(retry_failed_payouts()).unwrap();
+        assert!(failed_payouts().is_empty());
+        assert_eq!(stand_in::transfers().len(), 1);
+        assert_eq!(stand_in::balance(&account(&seller)), PRICE - icp_ledger::fee());
+    }
+
+    #[test]
+    fn a_payout_past_the_deduplication_window_is_retried_with_a_fresh_time() {
+        testing::setup();
+        let seller = principal(1);
+        let subaccount = icp_ledger::derive_subaccount(b"test", &[]);
+        let from = icp_ledger::canister_account(&subaccount);
+        stand_in::deposit(&from, PRICE);
+
+        stand_in::set_unavailable(true);
+        env::block_on(payout(Payout {
+            subaccount: subaccount.0.to_vec(),
+            to: account(&seller),
+            amount: PRICE,
+            memo: SALE_MEMO,
+            created_at_time: time(),
+            error: String::new(),
+        }));
+        stand_in::set_unavailable(false);
+        env::advance_time(2 * DAY);
+
+        env::set_caller(testing::custodian());
+        env::block_on(retry_failed_payouts()).unwrap();
+        assert!(failed_payouts().is_empty());
+        assert_eq!(stand_in::transfers()[0].created_at_time, Some(time()));
+        assert_eq!(stand_in::balance(&account(&seller)), PRICE - icp_ledger::fee());
+        assert_eq!(stand_in::balance(&from), 0);
+    }
+
+    #[test]
+    fn a_stale_payout_the_subaccount_no_longer_covers_stays_parked() {
+        testing::setup();
+        let seller = principal(1);
+        let subaccount = icp_ledger::derive_subaccount(b"test", &[]);
+        stand_in::deposit(&icp_ledger::canister_account(&subaccount), PRICE);
+
+        // The first attempt went through but its reply never arrived.
+        stand_in::lose_next_reply();
+        env::block_on(payout(Payout {
+            subaccount: subaccount.0.to_vec(),
+            to: account(&seller),
+            amount: PRICE,
+            memo: SALE_MEMO,
+            created_at_time: time(),
+            error: String::new(),
+        }));
+        env::advance_time(2 * DAY);
+
+        env::set_caller(testing::custodian());
+        env::block_on(retry_failed_payouts()).unwrap();
+        assert_eq!(failed_payouts().len(), 1);
+        assert_eq!(stand_in::transfers().len(), 1);
+        assert_eq!(stand_in::balance(&account(&seller)), PRICE - icp_ledger::fee());
+    }
+}
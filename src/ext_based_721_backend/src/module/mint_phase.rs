@@ -0,0 +1,354 @@
+use crate::module::cap::insert_sync;
+use crate::module::env::{self, time};
+use crate::module::icp_ledger;
+use crate::module::rbac;
+use crate::module::types::{
+    AccountIdentifier, MintPhase, MintPhaseKind, MintPhases, NftError, Role, User,
+};
+use cap_sdk::{DetailValue, IndefiniteEvent};
+use ic_cdk::export::Principal;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+thread_local! {
+    static PHASES: RefCell<MintPhases> = RefCell::new(MintPhases::default());
+}
+
+pub fn with<T, F: FnOnce(&MintPhases) -> T>(f: F) -> T {
+    PHASES.with(|phases| f(&phases.borrow()))
+}
+
+pub fn with_mut<T, F: FnOnce(&mut MintPhases) -> T>(f: F) -> T {
+    PHASES.with(|phases| f(&mut phases.borrow_mut()))
+}
+
+pub fn phases_info() -> MintPhases {
+    with(|phases| phases.clone())
+}
+
+pub fn restore_phases_info(phases_info: MintPhases) {
+    with_mut(|phases| *phases = phases_info);
+}
+
+// Quotas are tracked per account identifier, so a principal and its default
+// account share one quota.
+fn wallet_of(user: &User) -> AccountIdentifier {
+    match user {
+        User::principal(principal) => icp_ledger::default_account(principal),
+        User::address(account) => account.to_lowercase(),
+    }
+}
+
+fn parse_wallet(wallet: &str) -> Option<AccountIdentifier> {
+    if icp_ledger::decode_account(wallet).is_some() {
+        return Some(wallet.to_lowercase());
+    }
+    Principal::from_text(wallet)
+        .ok()
+        .map(|principal| icp_ledger::default_account(&principal))
+}
+
+impl MintPhases {
+    pub fn active(&self, now: u64) -> Option<&MintPhase> {
+        self.phases
+            .iter()
+            .find(|phase| phase.start_at <= now && now < phase.end_at)
+    }
+
+    // How many more tokens `wallet` may receive in `phase`; `None` means no cap.
+    pub fn remaining(&self, phase: &MintPhase, wallet: &AccountIdentifier) -> Option<u32> {
+        let quota = match phase.kind {
+            MintPhaseKind::Allowlist => Some(
+                self.allowlists
+                    .get(&phase.name)
+                    .and_then(|allowlist| allowlist.get(wallet))
+                    .copied()
+                    .unwrap_or(0),
+            ),
+            MintPhaseKind::Public => phase.wallet_cap,
+        }?;
+        let minted = self
+            .minted
+            .get(&phase.name)
+            .and_then(|minted| minted.get(wallet))
+            .copied()
+            .unwrap_or(0);
+        Some(quota.saturating_sub(minted))
+    }
+}
+
+// Replaces the phase schedule. Phases must not overlap; allowlists and mint
+// counts of phases that keep their name are preserved.
+pub fn set_mint_phases(mut phases: Vec<MintPhase>) -> Result<(), NftError> {
+    rbac::ensure_role(Role::Admin)?;
+    phases.sort_by_key(|phase| phase.start_at);
+    if phases.iter().any(|phase| phase.start_at >= phase.end_at)
+        || phases.windows(2).any(|pair| pair[0].end_at > pair[1].start_at)
+        || phases
+            .iter()
+            .enumerate()
+            .any(|(i, phase)| phases[..i].iter().any(|other| other.name == phase.name))
+    {
+        return Err(NftError::InvalidMintPhase);
+    }
+
+    let names = phases
+        .iter()
+        .map(|phase| DetailValue::from(phase.name.clone()))
+        .collect::<Vec<_>>();
+    with_mut(|state| {
+        state
+            .allowlists
+            .retain(|name, _| phases.iter().any(|phase| phase.name == *name));
+        state
+            .minted
+            .retain(|name, _| phases.iter().any(|phase| phase.name == *name));
+        state.phases = phases;
+    });
+
+    insert_sync(IndefiniteEvent {
+        caller: env::caller(),
+        operation: "setMintPhases".into(),
+        details: vec![("phases".into(), DetailValue::Vec(names))],
+    });
+
+    Ok(())
+}
+
+// Adds one chunk of an allowlist. Entries are principals or account
+// identifiers; a repeated entry has its quota replaced. Returns the number of
+// wallets on the allowlist.
+pub fn add_allowlist(phase: String, entries: Vec<(String, u32)>) -> Result<u64, NftError> {
+    rbac::ensure_role(Role::Admin)?;
+    let entries = entries
+        .into_iter()
+        .map(|(wallet, quota)| parse_wallet(&wallet).map(|wallet| (wallet, quota)))
+        .collect::<Option<Vec<_>>>()
+        .ok_or(NftError::InvalidMintPhase)?;
+
+    let added = entries.len() as u64;
+    let total = with_mut(|state| {
+        match state.phases.iter().find(|p| p.name == phase) {
+            Some(p) if p.kind == MintPhaseKind::Allowlist => {}
+            Some(_) => return Err(NftError::InvalidMintPhase),
+            None => return Err(NftError::MintPhaseNotFound(phase.clone())),
+        }
+        let allowlist = state.allowlists.entry(phase.clone()).or_default();
+        allowlist.extend(entries);
+        Ok(allowlist.len() as u64)
+    })?;
+
+    insert_sync(IndefiniteEvent {
+        caller: env::caller(),
+        operation: "addAllowlist".into(),
+        details: vec![
+            ("phase".into(), DetailValue::from(phase)),
+            ("entries".into(), DetailValue::U64(added)),
+        ],
+    });
+
+    Ok(total)
+}
+
+pub fn clear_allowlist(phase: String) -> Result<(), NftError> {
+    rbac::ensure_role(Role::Admin)?;
+    with_mut(|state| state.allowlists.remove(&phase));
+
+    insert_sync(IndefiniteEvent {
+        caller: env::caller(),
+        operation: "clearAllowlist".into(),
+        details: vec![("phase".into(), DetailValue::from(phase))],
+    });
+
+    Ok(())
+}
+
+pub fn mint_phases() -> Vec<MintPhase> {
+    with(|state| state.phases.clone())
+}
+
+pub fn active_mint_phase() -> Option<MintPhase> {
+    with(|state| state.active(time()).cloned())
+}
+
+// The caller's remaining quota in every phase.
+pub fn remaining_quota() -> Vec<(String, Option<u32>)> {
+    let wallet = wallet_of(&User::principal(env::caller()));
+    with(|state| {
+        state
+            .phases
+            .iter()
+            .map(|phase| (phase.name.clone(), state.remaining(phase, &wallet)))
+            .collect()
+    })
+}
+
+// How many tokens `to` may still receive right now; `None` means no cap.
+// Minting is unrestricted until a phase schedule is configured, and closed
+// outside its windows.
+pub fn available(to: &User) -> Result<Option<u32>, NftError> {
+    with(|state| {
+        if state.phases.is_empty() {
+            return Ok(None);
+        }
+        let phase = state.active(time()).ok_or(NftError::MintPhaseNotActive)?;
+        Ok(state.remaining(phase, &wallet_of(to)))
+    })
+}
+
+// Counts `quantity` mints to `to` against the active phase.
+pub fn consume(to: &User, quantity: u32) -> Result<(), NftError> {
+    if available(to)?.map_or(false, |remaining| remaining < quantity) {
+        return Err(NftError::QuotaExceeded);
+    }
+    adjust(to, quantity as i64);
+    Ok(())
+}
+
+// Gives back quota taken by `consume` for mints that did not happen.
+pub fn release(to: &User, quantity: u32) {
+    adjust(to, -(quantity as i64));
+}
+
+fn adjust(to: &User, delta: i64) {
+    let wallet = wallet_of(to);
+    with_mut(|state| {
+        let name = match state.active(time()) {
+            Some(phase) => phase.name.clone(),
+            None => return,
+        };
+        let minted: &mut HashMap<AccountIdentifier, u32> = state.minted.entry(name).or_default();
+        let count = minted.entry(wallet).or_insert(0);
+        *count = (*count as i64 + delta).max(0) as u32;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::testing::{self, principal};
+
+    const HOUR: u64 = 60 * 60 * 1_000_000_000;
+
+    fn phase(name: &str, kind: MintPhaseKind, start_at: u64, wallet_cap: Option<u32>) -> MintPhase {
+        MintPhase {
+            name: name.into(),
+            kind,
+            start_at,
+            end_at: start_at + HOUR,
+            wallet_cap,
+        }
+    }
+
+    // An allowlist phase starting now, followed by a public phase.
+    fn schedule() {
+        let now = time();
+        set_mint_phases(vec![
+            phase("public", MintPhaseKind::Public, now + HOUR, Some(2)),
+            phase("allowlist", MintPhaseKind::Allowlist, now, None),
+        ])
+        .unwrap();
+    }
+
+    fn buyer(n: u8) -> User {
+        User::principal(principal(n))
+    }
+
+    #[test]
+    fn minting_is_unrestricted_without_phases() {
+        testing::setup();
+        assert_eq!(available(&buyer(1)).unwrap(), None);
+        consume(&buyer(1), 1_000).unwrap();
+    }
+
+    #[test]
+    fn overlapping_or_empty_phases_are_rejected() {
+        testing::setup();
+        let now = time();
+
+        assert!(matches!(
+            set_mint_phases(vec![
+                phase("a", MintPhaseKind::Public, now, None),
+                phase("b", MintPhaseKind::Public, now + HOUR / 2, None),
+            ]),
+            Err(NftError::InvalidMintPhase)
+        ));
+        let mut empty = phase("a", MintPhaseKind::Public, now, None);
+        empty.end_at = now;
+        assert!(matches!(
+            set_mint_phases(vec![empty]),
+            Err(NftError::InvalidMintPhase)
+        ));
+        assert!(mint_phases().is_empty());
+    }
+
+    #[test]
+    fn allowlisted_wallets_mint_their_quota() {
+        testing::setup();
+        schedule();
+        assert_eq!(active_mint_phase().unwrap().name, "allowlist");
+        add_allowlist("allowlist".into(), vec![(principal(1).to_text(), 2)]).unwrap();
+
+        assert_eq!(available(&buyer(1)).unwrap(), Some(2));
+        assert_eq!(available(&buyer(2)).unwrap(), Some(0));
+        consume(&buyer(1), 2).unwrap();
+        assert!(matches!(consume(&buyer(1), 1), Err(NftError::QuotaExceeded)));
+
+        release(&buyer(1), 1);
+        consume(&buyer(1), 1).unwrap();
+    }
+
+    #[test]
+    fn a_principal_and_its_account_share_a_quota() {
+        testing::setup();
+        schedule();
+        let account = icp_ledger::default_account(&principal(1));
+        add_allowlist("allowlist".into(), vec![(account.clone(), 1)]).unwrap();
+
+        consume(&User::address(account.to_uppercase()), 1).unwrap();
+        assert_eq!(available(&buyer(1)).unwrap(), Some(0));
+    }
+
+    #[test]
+    fn public_phases_apply_the_wallet_cap() {
+        testing::setup();
+        schedule();
+        env::advance_time(HOUR);
+
+        assert_eq!(active_mint_phase().unwrap().name, "public");
+        consume(&buyer(3), 2).unwrap();
+        assert!(matches!(consume(&buyer(3), 1), Err(NftError::QuotaExceeded)));
+    }
+
+    #[test]
+    fn minting_closes_between_and_after_phases() {
+        testing::setup();
+        schedule();
+        env::advance_time(2 * HOUR);
+
+        assert!(active_mint_phase().is_none());
+        assert!(matches!(
+            consume(&buyer(1), 1),
+            Err(NftError::MintPhaseNotActive)
+        ));
+    }
+
+    #[test]
+    fn allowlists_belong_to_allowlist_phases() {
+        testing::setup();
+        schedule();
+
+        assert!(matches!(
+            add_allowlist("public".into(), vec![(principal(1).to_text(), 1)]),
+            Err(NftError::InvalidMintPhase)
+        ));
+        assert!(matches!(
+            add_allowlist("missing".into(), vec![]),
+            Err(NftError::MintPhaseNotFound(_))
+        ));
+        assert!(matches!(
+            add_allowlist("allowlist".into(), vec![("not a wallet".into(), 1)]),
+            Err(NftError::InvalidMintPhase)
+        ));
+    }
+}
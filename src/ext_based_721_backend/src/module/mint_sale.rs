@@ -0,0 +1,427 @@
+use crate::module::cap::insert_sync;
+use crate::module::env::{self, time};
+use crate::module::icp_ledger;
+use crate::module::ledger;
+use crate::module::marketplace;
+use crate::module::mint_phase;
+use crate::module::rbac;
+use crate::module::types::{
+    MintRequest, MintSale, MintSaleRequest, NftError, Operation, Payout, Role, TokenIndex, User,
+};
+use crate::prop;
+use cap_sdk::{DetailValue, IndefiniteEvent};
+use ic_cdk::export::Principal;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+const MINT_MEMO: u64 = 0x4d49_4e54;
+
+// A claim still marked as running after this long is assumed to have trapped
+// in a callback, and the buyer may claim again.
+const CLAIM_TIMEOUT: u64 = 10 * 60 * 1_000_000_000;
+
+thread_local! {
+    static SALES: RefCell<HashMap<String, MintSale>> = RefCell::new(HashMap::new());
+    static CLAIMS: RefCell<HashMap<Principal, u64>> = RefCell::new(HashMap::new());
+}
+
+pub fn with<T, F: FnOnce(&HashMap<String, MintSale>) -> T>(f: F) -> T {
+    SALES.with(|sales| f(&sales.borrow()))
+}
+
+pub fn with_mut<T, F: FnOnce(&mut HashMap<String, MintSale>) -> T>(f: F) -> T {
+    SALES.with(|sales| f(&mut sales.borrow_mut()))
+}
+
+pub fn sales_info() -> Vec<MintSale> {
+    with(|sales| sales.values().cloned().collect())
+}
+
+pub fn restore_sales_info(sales_info: Vec<MintSale>) {
+    with_mut(|sales| {
+        sales_info.into_iter().for_each(|sale| {
+            sales.insert(sale.class.clone(), sale);
+        })
+    });
+}
+
+impl MintSale {
+    pub fn remaining(&self) -> u64 {
+        self.supply.saturating_sub(self.sold)
+    }
+
+    pub fn is_active(&self, now: u64) -> bool {
+        self.start_at <= now && self.end_at.map_or(true, |end_at| now < end_at)
+    }
+}
+
+// Buyers pay into a subaccount of this canister derived from their principal.
+fn payment_subaccount(buyer: &Principal) -> Vec<u8> {
+    icp_ledger::derive_subaccount(b"mint", &[buyer.as_slice()])
+        .0
+        .to_vec()
+}
+
+pub fn payment_address() -> String {
+    let subaccount = payment_subaccount(&env::caller());
+    icp_ledger::canister_account(&marketplace::to_subaccount(&subaccount))
+}
+
+// Opens or reconfigures the paid sale of a class. Tokens already sold keep
+// counting against the new supply.
+pub fn set_mint_sale(request: MintSaleRequest) -> Result<(), NftError> {
+    rbac::ensure_role(Role::Admin)?;
+    if prop::properties_of(&request.class).is_empty() {
+        return Err(NftError::ClassNotFound(request.class));
+    }
+    if request.price <= icp_ledger::fee()
+        || request.end_at.map_or(false, |end_at| end_at <= request.start_at)
+        || icp_ledger::decode_account(&request.recipient).is_none()
+    {
+        return Err(NftError::InvalidMintSale);
+    }
+
+    let sold = mint_sale(&request.class).map_or(0, |sale| sale.sold);
+    let sale = MintSale {
+        class: request.class,
+        price: request.price,
+        supply: request.supply,
+        sold,
+        start_at: request.start_at,
+        end_at: request.end_at,
+        recipient: request.recipient,
+    };
+    let details = vec![
+        ("class".into(), DetailValue::from(sale.class.clone())),
+        ("price".into(), DetailValue::U64(sale.price)),
+        ("supply".into(), DetailValue::U64(sale.supply)),
+    ];
+    with_mut(|sales| sales.insert(sale.class.clone(), sale));
+
+    insert_sync(IndefiniteEvent {
+        caller: env::caller(),
+        operation: "setMintSale".into(),
+        details,
+    });
+
+    Ok(())
+}
+
+pub fn remove_mint_sale(class: String) -> Result<(), NftError> {
+    rbac::ensure_role(Role::Admin)?;
+    if with_mut(|sales| sales.remove(&class)).is_none() {
+        return Err(NftError::MintSaleNotFound(class));
+    }
+
+    insert_sync(IndefiniteEvent {
+        caller: env::caller(),
+        operation: "removeMintSale".into(),
+        details: vec![("class".into(), DetailValue::from(class))],
+    });
+
+    Ok(())
+}
+
+pub fn mint_sale(class: &str) -> Option<MintSale> {
+    with(|sales| sales.get(class).cloned())
+}
+
+pub fn mint_sales() -> Vec<MintSale> {
+    sales_info()
+}
+
+fn active_sale(class: &str) -> Result<MintSale, NftError> {
+    let sale = mint_sale(class).ok_or_else(|| NftError::MintSaleNotFound(class.to_string()))?;
+    if !sale.is_active(time()) {
+        return Err(NftError::MintSaleNotActive);
+    }
+    if ledger::with(|ledger| ledger.is_paused(&Operation::Mint)) {
+        return Err(NftError::OperationPaused(Operation::Mint));
+    }
+    Ok(sale)
+}
+
+async fn refund(buyer: Principal, subaccount: Vec<u8>, amount: u64) {
+    if amount > icp_ledger::fee() {
+        marketplace::payout(Payout {
+            subaccount,
+            to: icp_ledger::default_account(&buyer),
+            amount,
+            memo: MINT_MEMO,
+            created_at_time: time(),
+            error: String::new(),
+        })
+        .await;
+    }
+}
+
+// Claims take the buyer's payment out of their subaccount across several
+// ledger calls, so only one may run per buyer at a time.
+fn begin_claim(buyer: Principal) -> Result<(), NftError> {
+    let now = time();
+    CLAIMS.with(|claims| {
+        let mut claims = claims.borrow_mut();
+        if claims
+            .get(&buyer)
+            .map_or(false, |started_at| started_at.saturating_add(CLAIM_TIMEOUT) > now)
+        {
+            return Err(NftError::ClaimInProgress);
+        }
+        claims.insert(buyer, now);
+        Ok(())
+    })
+}
+
+fn end_claim(buyer: &Principal) {
+    CLAIMS.with(|claims| claims.borrow_mut().remove(buyer));
+}
+
+// Mints as many tokens of `class` as the caller's payment covers, then forwards
+// the price of the tokens actually minted to the sale's recipient and refunds
+// the rest. Mints that fail give their place in the supply and the phase quota
+// back and are refunded with everything else; a forward that fails is kept
+// with the failed payouts for an admin to retry, and what it owes does not
+// count as payment for later claims.
+pub async fn claim(class: String) -> Result<Vec<TokenIndex>, NftError> {
+    let buyer = env::caller();
+    active_sale(&class)?;
+    begin_claim(buyer)?;
+    let result = claim_paid(buyer, class).await;
+    end_claim(&buyer);
+    result
+}
+
+async fn claim_paid(buyer: Principal, class: String) -> Result<Vec<TokenIndex>, NftError> {
+    let subaccount = payment_subaccount(&buyer);
+    let balance = icp_ledger::balance_of(&marketplace::to_subaccount(&subaccount))
+        .await
+        .map_err(NftError::LedgerError)?;
+    // Payouts from the subaccount that failed and wait to be retried are
+    // already spoken for and must not pay for tokens a second time.
+    let balance = balance.saturating_sub(marketplace::pending_payouts_from(&subaccount));
+    let sale = active_sale(&class)?;
+    if balance < sale.price {
+        return Err(NftError::InsufficientFunds);
+    }
+
+    if sale.remaining() == 0 {
+        refund(buyer, subaccount, balance).await;
+        return Err(NftError::SoldOut);
+    }
+    let recipient = User::principal(buyer);
+    let quota = match mint_phase::available(&recipient) {
+        Ok(quota) => quota,
+        Err(err) => {
+            refund(buyer, subaccount, balance).await;
+            return Err(err);
+        }
+    };
+    if quota == Some(0) {
+        refund(buyer, subaccount, balance).await;
+        return Err(NftError::QuotaExceeded);
+    }
+    let quantity = (balance / sale.price)
+        .min(sale.remaining())
+        .min(quota.map_or(u64::MAX, u64::from));
+    let quantity = u32::try_from(quantity).unwrap_or(u32::MAX);
+    if let Err(err) = mint_phase::consume(&recipient, quantity) {
+        refund(buyer, subaccount, balance).await;
+        return Err(err);
+    }
+    adjust_sold(&class, i64::from(quantity));
+
+    let mut minted = vec![];
+    let mut error = None;
+    for _ in 0..quantity {
+        match crate::mint_within_quota(MintRequest {
+            to: recipient.clone(),
+            metadata: None,
+            class: class.clone(),
+            created_at_time: None,
+        }) {
+            Ok(token_index) => minted.push(token_index),
+            Err(err) => {
+                error = Some(err);
+                break;
+            }
+        }
+    }
+    let failed = quantity - minted.len() as u32;
+    if failed > 0 {
+        adjust_sold(&class, -i64::from(failed));
+        mint_phase::release(&recipient, failed);
+    }
+
+    let cost = minted.len() as u64 * sale.price;
+    if cost > 0 {
+        insert_sync(IndefiniteEvent {
+            caller: buyer,
+            operation: "claim".into(),
+            details: vec![
+                ("class".into(), DetailValue::from(class)),
+                ("quantity".into(), DetailValue::U64(minted.len() as u64)),
+                ("price".into(), DetailValue::U64(sale.price)),
+            ],
+        });
+        marketplace::payout(Payout {
+            subaccount: subaccount.clone(),
+            to: sale.recipient,
+            amount: cost,
+            memo: MINT_MEMO,
+            created_at_time: time(),
+            error: String::new(),
+        })
+        .await;
+    }
+    refund(buyer, subaccount, balance - cost).await;
+
+    match error {
+        Some(err) if minted.is_empty() => Err(err),
+        _ => Ok(minted),
+    }
+}
+
+fn adjust_sold(class: &str, delta: i64) {
+    with_mut(|sales| {
+        if let Some(sale) = sales.get_mut(class) {
+            sale.sold = (sale.sold as i64 + delta).max(0) as u64;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::dip721;
+    use crate::module::icp_ledger::stand_in;
+    use crate::module::testing::{self, custodian, principal};
+    use ic_cdk::export::candid::Nat;
+
+    const PRICE: u64 = 100_000_000;
+
+    fn open_sale(supply: u64) {
+        let sale = MintSale {
+            class: "H".into(),
+            price: PRICE,
+            supply,
+            sold: 0,
+            start_at: 0,
+            end_at: None,
+            recipient: icp_ledger::default_account(&principal(9)),
+        };
+        with_mut(|sales| sales.insert(sale.class.clone(), sale));
+    }
+
+    fn pay(buyer: Principal, amount: u64) {
+        env::set_caller(buyer);
+        stand_in::deposit(&payment_address(), amount);
+    }
+
+    fn sold() -> u64 {
+        mint_sale("H").unwrap().sold
+    }
+
+    fn balance(principal: Principal) -> u64 {
+        stand_in::balance(&icp_ledger::default_account(&principal))
+    }
+
+    #[test]
+    fn claims_mint_what_the_payment_covers_and_refund_the_rest() {
+        testing::setup();
+        open_sale(10);
+        let buyer = principal(1);
+        pay(buyer, 2 * PRICE + PRICE / 2);
+
+        let minted = env::block_on(claim("H".into())).unwrap();
+
+        assert_eq!(minted.len(), 2);
+        assert_eq!(sold(), 2);
+        assert_eq!(balance(principal(9)), 2 * PRICE - icp_ledger::fee());
+        assert_eq!(balance(buyer), PRICE / 2 - icp_ledger::fee());
+    }
+
+    #[test]
+    fn claims_stop_at_the_supply() {
+        testing::setup();
+        open_sale(1);
+        let buyer = principal(1);
+        pay(buyer, 3 * PRICE);
+
+        assert_eq!(env::block_on(claim("H".into())).unwrap().len(), 1);
+        assert_eq!(balance(buyer), 2 * PRICE - icp_ledger::fee());
+
+        pay(buyer, PRICE);
+        assert!(matches!(
+            env::block_on(claim("H".into())),
+            Err(NftError::SoldOut)
+        ));
+    }
+
+    #[test]
+    fn failed_mints_are_refunded_and_give_back_the_supply() {
+        testing::setup();
+        open_sale(10);
+        // Take the identifier the claim will be given, so its mint fails.
+        let next = Nat::from(dip721::tid_info());
+        ledger::with_mut(|ledger| {
+            dip721::mint_token(ledger, custodian(), custodian(), &next, prop::properties_of("H"))
+        })
+        .unwrap();
+        let buyer = principal(1);
+        pay(buyer, PRICE);
+
+        assert!(matches!(
+            env::block_on(claim("H".into())),
+            Err(NftError::ExistedNFT)
+        ));
+        assert_eq!(sold(), 0);
+        assert_eq!(balance(principal(9)), 0);
+        assert_eq!(balance(buyer), PRICE - icp_ledger::fee());
+        assert!(marketplace::failed_payouts().is_empty());
+    }
+
+    #[test]
+    fn a_failed_forward_does_not_pay_for_a_second_claim() {
+        testing::setup();
+        open_sale(10);
+        let buyer = principal(1);
+        pay(buyer, 2 * PRICE);
+
+        stand_in::reject_next_transfer();
+        assert_eq!(env::block_on(claim("H".into())).unwrap().len(), 2);
+        assert_eq!(marketplace::failed_payouts().len(), 1);
+
+        // What is left in the subaccount belongs to the parked forward.
+        assert!(matches!(
+            env::block_on(claim("H".into())),
+            Err(NftError::InsufficientFunds)
+        ));
+        pay(buyer, PRICE);
+        assert_eq!(env::block_on(claim("H".into())).unwrap().len(), 1);
+        assert_eq!(sold(), 3);
+
+        env::set_caller(custodian());
+        env::block_on(marketplace::retry_failed_payouts()).unwrap();
+        assert!(marketplace::failed_payouts().is_empty());
+        assert_eq!(balance(principal(9)), 3 * PRICE - 2 * icp_ledger::fee());
+        assert_eq!(balance(buyer), 0);
+    }
+
+    #[test]
+    fn a_buyer_cannot_run_two_claims_at_once() {
+        testing::setup();
+        open_sale(10);
+        let buyer = principal(1);
+        pay(buyer, PRICE);
+        begin_claim(buyer).unwrap();
+
+        assert!(matches!(
+            env::block_on(claim("H".into())),
+            Err(NftError::ClaimInProgress)
+        ));
+
+        // A claim that trapped mid-way stops blocking the buyer eventually.
+        env::advance_time(CLAIM_TIMEOUT);
+        assert_eq!(env::block_on(claim("H".into())).unwrap().len(), 1);
+    }
+}
@@ -1,4 +1,29 @@
+pub mod auction;
+pub mod cap;
+pub mod certification;
+pub mod craft;
+pub mod dedup;
+pub mod diagnostics;
 pub mod dip721;
+pub mod env;
+pub mod icp_ledger;
+pub mod icrc3;
+pub mod icrc37;
+pub mod icrc7;
+pub mod job;
 pub mod ledger;
+pub mod lock;
+pub mod marketplace;
+pub mod mint_phase;
+pub mod mint_sale;
+pub mod mystery_box;
+pub mod random;
+pub mod rbac;
+pub mod reveal;
+pub mod royalty;
+pub mod swap;
+#[cfg(test)]
+pub mod testing;
 pub mod types;
+pub mod upgrade;
 pub mod token_identifier;
\ No newline at end of file
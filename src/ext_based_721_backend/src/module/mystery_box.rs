@@ -0,0 +1,285 @@
+use crate::module::cap::insert_sync;
+use crate::module::dip721;
+use crate::module::env;
+use crate::module::ledger;
+use crate::module::random::{self, RandomSource};
+use crate::module::rbac;
+use crate::module::reveal;
+use crate::module::types::{MysteryBox, NftError, Operation, Role, Token_ID};
+use crate::prop;
+use cap_sdk::{DetailValue, IndefiniteEvent};
+use ic_cdk::export::Principal;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+thread_local! {
+    static BOXES: RefCell<HashMap<String, MysteryBox>> = RefCell::new(HashMap::new());
+}
+
+pub fn with<T, F: FnOnce(&HashMap<String, MysteryBox>) -> T>(f: F) -> T {
+    BOXES.with(|boxes| f(&boxes.borrow()))
+}
+
+pub fn with_mut<T, F: FnOnce(&mut HashMap<String, MysteryBox>) -> T>(f: F) -> T {
+    BOXES.with(|boxes| f(&mut boxes.borrow_mut()))
+}
+
+pub fn boxes_info() -> Vec<MysteryBox> {
+    with(|boxes| boxes.values().cloned().collect())
+}
+
+pub fn restore_boxes_info(boxes_info: Vec<MysteryBox>) {
+    with_mut(|boxes| {
+        boxes_info.into_iter().for_each(|mystery_box| {
+            boxes.insert(mystery_box.class.clone(), mystery_box);
+        })
+    });
+}
+
+pub fn set_mystery_box(mystery_box: MysteryBox) -> Result<(), NftError> {
+    rbac::ensure_role(Role::MetadataEditor)?;
+    if mystery_box.outcomes.is_empty()
+        || mystery_box.outcomes.iter().all(|outcome| outcome.weight == 0)
+    {
+        return Err(NftError::InvalidMysteryBox);
+    }
+    if let Some(class) = std::iter::once(&mystery_box.class)
+        .chain(mystery_box.outcomes.iter().map(|outcome| &outcome.class))
+        .find(|class| prop::properties_of(class).is_empty())
+    {
+        return Err(NftError::ClassNotFound(class.clone()));
+    }
+
+    let class = mystery_box.class.clone();
+    with_mut(|boxes| boxes.insert(class.clone(), mystery_box));
+
+    insert_sync(IndefiniteEvent {
+        caller: env::caller(),
+        operation: "setMysteryBox".into(),
+        details: vec![("class".into(), DetailValue::from(class))],
+    });
+
+    Ok(())
+}
+
+pub fn remove_mystery_box(class: String) -> Result<(), NftError> {
+    rbac::ensure_role(Role::MetadataEditor)?;
+    if with_mut(|boxes| boxes.remove(&class)).is_none() {
+        return Err(NftError::NotAMysteryBox);
+    }
+
+    insert_sync(IndefiniteEvent {
+        caller: env::caller(),
+        operation: "removeMysteryBox".into(),
+        details: vec![("class".into(), DetailValue::from(class))],
+    });
+
+    Ok(())
+}
+
+// Checks that `caller` could open `token_identifier` right now and returns the
+// box configuration of its class.
+fn check_openable(caller: &Principal, token_identifier: &Token_ID) -> Result<MysteryBox, NftError> {
+    let class = ledger::with(|ledger| {
+        for operation in [Operation::Burn, Operation::Mint] {
+            if ledger.is_paused(&operation) {
+                return Err(NftError::OperationPaused(operation));
+            }
+        }
+        if ledger.is_locked(token_identifier) {
+            return Err(NftError::TokenLocked);
+        }
+        let token = ledger.token_metadata(token_identifier)?;
+        if token.owner != Some(*caller) {
+            return Err(NftError::UnauthorizedOwner);
+        }
+        token.class().cloned().ok_or(NftError::NotAMysteryBox)
+    })?;
+
+    with(|boxes| boxes.get(&class).cloned()).ok_or(NftError::NotAMysteryBox)
+}
+
+pub async fn open_box(token_identifier: Token_ID) -> Result<Token_ID, NftError> {
+    open_box_with(&random::source(), token_identifier).await
+}
+
+// Burns the box and mints a token of a class drawn from the box's weighted
+// outcomes. Ownership is checked again after the randomness call returns, as
+// the box may have moved while the message was suspended.
+pub async fn open_box_with<R: RandomSource>(
+    source: &R,
+    token_identifier: Token_ID,
+) -> Result<Token_ID, NftError> {
+    let caller = env::caller();
+    check_openable(&caller, &token_identifier)?;
+
+    let bytes = source
+        .random_bytes()
+        .await
+        .map_err(NftError::RandomnessUnavailable)?;
+
+    let mystery_box = check_openable(&caller, &token_identifier)?;
+    let weights = mystery_box
+        .outcomes
+        .iter()
+        .map(|outcome| outcome.weight)
+        .collect::<Vec<_>>();
+    let class = random::weighted_index(&weights, &bytes)
+        .map(|index| mystery_box.outcomes[index].class.clone())
+        .ok_or(NftError::InvalidMysteryBox)?;
+
+    let properties = prop::properties_of(&class);
+    ledger::with_mut(|ledger| {
+        dip721::burn_token(ledger, caller, &token_identifier)?;
+        let prize = dip721::allocate_token_identifier();
+        if let Err(err) = dip721::mint_token(ledger, caller, caller, &prize, properties) {
+            ic_cdk::trap(&format!("open_box: failed to mint prize {}: {:?}", prize, err));
+        }
+
+        let mut details = vec![
+            (
+                "box".into(),
+                DetailValue::from(token_identifier.to_string()),
+            ),
+            (
+                "token_identifier".into(),
+                DetailValue::from(prize.to_string()),
+            ),
+        ];
+        // A prize still hidden until its reveal keeps its class out of the
+        // public history.
+        if !ledger.token_metadata(&prize).map_or(false, reveal::is_hidden) {
+            details.push(("class".into(), DetailValue::from(class)));
+        }
+        insert_sync(IndefiniteEvent {
+            caller,
+            operation: "openBox".into(),
+            details,
+        });
+        ledger.inc_tx();
+
+        Ok(prize)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::cap;
+    use crate::module::testing::{self, owner_of, principal};
+    use crate::module::types::{BoxOutcome, GeneralValue, RevealConfig};
+
+    fn open_as(owner: Principal, token: Token_ID) -> Result<Token_ID, NftError> {
+        env::set_caller(owner);
+        env::block_on(open_box(token))
+    }
+
+    fn configure_box() {
+        let mystery_box = MysteryBox {
+            class: "He".into(),
+            outcomes: vec![
+                BoxOutcome {
+                    class: "H".into(),
+                    weight: 1,
+                },
+                BoxOutcome {
+                    class: "Li".into(),
+                    weight: 0,
+                },
+            ],
+        };
+        set_mystery_box(mystery_box).unwrap();
+    }
+
+    #[test]
+    fn opening_burns_the_box_and_mints_a_prize() {
+        testing::setup();
+        configure_box();
+        let owner = principal(1);
+        let token = testing::mint_class(owner, "He");
+
+        let prize = open_as(owner, token.clone()).unwrap();
+
+        assert_eq!(owner_of(&token), None);
+        assert_eq!(owner_of(&prize), Some(owner));
+        let class = ledger::with(|ledger| {
+            ledger.token_metadata(&prize).unwrap().class().cloned()
+        });
+        assert_eq!(class.as_deref(), Some("H"));
+    }
+
+    fn open_event_class(owner: Principal, token: Token_ID) -> Option<DetailValue> {
+        open_as(owner, token).unwrap();
+        let event = cap::pending().pop().unwrap();
+        assert_eq!(event.operation, "openBox");
+        event
+            .details
+            .into_iter()
+            .find_map(|(key, value)| (key == "class").then_some(value))
+    }
+
+    #[test]
+    fn the_open_event_records_the_prize_class() {
+        testing::setup();
+        configure_box();
+        let owner = principal(1);
+        let token = testing::mint_class(owner, "He");
+
+        assert!(matches!(
+            open_event_class(owner, token),
+            Some(DetailValue::Text(class)) if class == "H"
+        ));
+    }
+
+    #[test]
+    fn the_open_event_keeps_a_hidden_prize_class_private() {
+        testing::setup();
+        configure_box();
+        let owner = principal(1);
+        let token = testing::mint_class(owner, "He");
+        reveal::with_mut(|reveals| {
+            reveals.insert(
+                Some("H".into()),
+                RevealConfig {
+                    class: Some("H".into()),
+                    placeholder: vec![("class".into(), GeneralValue::TextContent("?".into()))],
+                    seed: vec![1; 32],
+                    seed_hash: vec![2; 32],
+                    configured_at: 0,
+                    revealed_at: None,
+                },
+            )
+        });
+
+        assert!(open_event_class(owner, token).is_none());
+    }
+
+    #[test]
+    fn only_the_owner_can_open_a_box() {
+        testing::setup();
+        configure_box();
+        let token = testing::mint_class(principal(1), "He");
+
+        assert!(matches!(
+            open_as(principal(2), token.clone()),
+            Err(NftError::UnauthorizedOwner)
+        ));
+        assert_eq!(owner_of(&token), Some(principal(1)));
+    }
+
+    #[test]
+    fn boxes_need_an_outcome_with_weight() {
+        testing::setup();
+        let mystery_box = MysteryBox {
+            class: "He".into(),
+            outcomes: vec![BoxOutcome {
+                class: "H".into(),
+                weight: 0,
+            }],
+        };
+        assert!(matches!(
+            set_mystery_box(mystery_box),
+            Err(NftError::InvalidMysteryBox)
+        ));
+    }
+}
@@ -0,0 +1,132 @@
+#[cfg(not(any(test, feature = "deterministic-rand")))]
+use ic_cdk::export::Principal;
+#[cfg(any(test, feature = "deterministic-rand"))]
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+
+pub type RandomBytes = Pin<Box<dyn Future<Output = Result<Vec<u8>, String>>>>;
+
+pub trait RandomSource {
+    fn random_bytes(&self) -> RandomBytes;
+}
+
+// 32 bytes of randomness from the management canister.
+#[cfg(not(any(test, feature = "deterministic-rand")))]
+pub struct RawRand;
+
+#[cfg(not(any(test, feature = "deterministic-rand")))]
+impl RandomSource for RawRand {
+    fn random_bytes(&self) -> RandomBytes {
+        Box::pin(async {
+            let (bytes,): (Vec<u8>,) =
+                ic_cdk::call(Principal::management_canister(), "raw_rand", ())
+                    .await
+                    .map_err(|(code, msg)| format!("raw_rand failed: {:?} {}", code, msg))?;
+            Ok(bytes)
+        })
+    }
+}
+
+#[cfg(any(test, feature = "deterministic-rand"))]
+thread_local! {
+    static SEED: RefCell<u64> = const { RefCell::new(0x9E37_79B9_7F4A_7C15) };
+}
+
+// Reproducible xorshift sequence used instead of `raw_rand` in unit tests and
+// when the canister is built with the `deterministic-rand` feature, so draws
+// can be asserted on.
+#[cfg(any(test, feature = "deterministic-rand"))]
+pub struct Deterministic;
+
+#[cfg(any(test, feature = "deterministic-rand"))]
+impl RandomSource for Deterministic {
+    fn random_bytes(&self) -> RandomBytes {
+        let next = SEED.with(|seed| {
+            let mut x = *seed.borrow();
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            *seed.borrow_mut() = x;
+            x
+        });
+        Box::pin(async move { Ok(next.to_be_bytes().to_vec()) })
+    }
+}
+
+#[cfg(not(any(test, feature = "deterministic-rand")))]
+pub fn source() -> RawRand {
+    RawRand
+}
+
+#[cfg(any(test, feature = "deterministic-rand"))]
+pub fn source() -> Deterministic {
+    Deterministic
+}
+
+// Picks an index into `weights` with probability proportional to its weight.
+pub fn weighted_index(weights: &[u32], bytes: &[u8]) -> Option<usize> {
+    let total: u64 = weights.iter().map(|weight| *weight as u64).sum();
+    if total == 0 || bytes.len() < 8 {
+        return None;
+    }
+    let mut word = [0u8; 8];
+    word.copy_from_slice(&bytes[..8]);
+    let mut roll = u64::from_be_bytes(word) % total;
+    for (index, weight) in weights.iter().enumerate() {
+        let weight = *weight as u64;
+        if roll < weight {
+            return Some(index);
+        }
+        roll -= weight;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::env;
+
+    fn roll(value: u64) -> Vec<u8> {
+        value.to_be_bytes().to_vec()
+    }
+
+    #[test]
+    fn every_roll_lands_in_proportion_to_the_weights() {
+        let weights = [1, 3, 0, 6];
+        let mut hits = [0u32; 4];
+        for value in 0..10 {
+            hits[weighted_index(&weights, &roll(value)).unwrap()] += 1;
+        }
+        assert_eq!(hits, weights);
+    }
+
+    #[test]
+    fn zero_weights_are_never_picked() {
+        let weights = [0, 5, 0];
+        for value in [0, 1, 4, 5, u64::MAX] {
+            assert_eq!(weighted_index(&weights, &roll(value)), Some(1));
+        }
+    }
+
+    #[test]
+    fn nothing_is_picked_without_weight_or_randomness() {
+        assert_eq!(weighted_index(&[], &roll(0)), None);
+        assert_eq!(weighted_index(&[0, 0], &roll(0)), None);
+        assert_eq!(weighted_index(&[1, 2], &[0; 7]), None);
+    }
+
+    #[test]
+    fn deterministic_draws_follow_the_weights() {
+        let weights = [1, 3];
+        let mut hits = [0u32; 2];
+        for _ in 0..4_000 {
+            let bytes = env::block_on(source().random_bytes()).unwrap();
+            hits[weighted_index(&weights, &bytes).unwrap()] += 1;
+        }
+        // Expect about 1000 and 3000.
+        assert!((800..1_200).contains(&hits[0]), "{:?}", hits);
+        assert!((2_800..3_200).contains(&hits[1]), "{:?}", hits);
+    }
+}
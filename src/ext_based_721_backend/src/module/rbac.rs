@@ -0,0 +1,136 @@
+use crate::module::cap::insert_sync;
+use crate::module::env;
+use crate::module::ledger;
+use crate::module::types::{NftError, Role};
+use cap_sdk::{DetailValue, IndefiniteEvent};
+use ic_cdk::export::Principal;
+
+// Custodians implicitly hold every role, so a freshly installed canister keeps
+// working before any role has been granted.
+pub fn ensure_role(role: Role) -> Result<(), NftError> {
+    ledger::with(|ledger| {
+        if ledger.has_role(&env::caller(), &role) {
+            Ok(())
+        } else {
+            Err(NftError::MissingRole(role))
+        }
+    })
+}
+
+pub fn grant_role(principal: Principal, role: Role) -> Result<(), NftError> {
+    ensure_role(Role::Admin)?;
+    ledger::with_mut(|ledger| ledger.grant_role(principal, role));
+
+    insert_sync(IndefiniteEvent {
+        caller: env::caller(),
+        operation: "grantRole".into(),
+        details: vec![
+            ("principal".into(), DetailValue::from(principal)),
+            ("role".into(), DetailValue::from(format!("{:?}", role))),
+        ],
+    });
+
+    Ok(())
+}
+
+pub fn revoke_role(principal: Principal, role: Role) -> Result<(), NftError> {
+    ensure_role(Role::Admin)?;
+    ledger::with_mut(|ledger| ledger.revoke_role(&principal, &role));
+
+    insert_sync(IndefiniteEvent {
+        caller: env::caller(),
+        operation: "revokeRole".into(),
+        details: vec![
+            ("principal".into(), DetailValue::from(principal)),
+            ("role".into(), DetailValue::from(format!("{:?}", role))),
+        ],
+    });
+
+    Ok(())
+}
+
+pub fn roles_of(principal: Principal) -> Vec<Role> {
+    ledger::with(|ledger| {
+        ledger
+            .metadata
+            .roles
+            .get(&principal)
+            .map(|roles| roles.iter().cloned().collect())
+            .unwrap_or_default()
+    })
+}
+
+pub fn role_members() -> Vec<(Principal, Vec<Role>)> {
+    ledger::with(|ledger| {
+        ledger
+            .metadata
+            .roles
+            .iter()
+            .map(|(principal, roles)| (*principal, roles.iter().cloned().collect()))
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::testing::{self, custodian, principal};
+
+    fn has(principal: Principal, role: Role) -> bool {
+        env::set_caller(principal);
+        ensure_role(role).is_ok()
+    }
+
+    #[test]
+    fn custodians_hold_every_role() {
+        testing::setup();
+
+        for role in [Role::Admin, Role::Minter, Role::Pauser, Role::Burner] {
+            assert!(has(custodian(), role));
+        }
+        assert!(matches!(
+            {
+                env::set_caller(principal(1));
+                ensure_role(Role::Minter)
+            },
+            Err(NftError::MissingRole(Role::Minter))
+        ));
+    }
+
+    #[test]
+    fn admins_grant_and_revoke_roles() {
+        testing::setup();
+        let minter = principal(1);
+
+        grant_role(minter, Role::Minter).unwrap();
+        assert!(has(minter, Role::Minter));
+        assert!(!has(minter, Role::Admin));
+        assert_eq!(roles_of(minter), vec![Role::Minter]);
+
+        env::set_caller(custodian());
+        revoke_role(minter, Role::Minter).unwrap();
+        assert!(!has(minter, Role::Minter));
+        assert!(role_members().is_empty());
+    }
+
+    #[test]
+    fn only_admins_manage_roles() {
+        testing::setup();
+        testing::grant(principal(1), Role::Minter);
+        env::set_caller(principal(1));
+
+        assert!(matches!(
+            grant_role(principal(1), Role::Admin),
+            Err(NftError::MissingRole(Role::Admin))
+        ));
+        assert!(matches!(
+            revoke_role(principal(1), Role::Minter),
+            Err(NftError::MissingRole(Role::Admin))
+        ));
+
+        testing::grant(principal(2), Role::Admin);
+        env::set_caller(principal(2));
+        grant_role(principal(3), Role::Pauser).unwrap();
+        assert!(has(principal(3), Role::Pauser));
+    }
+}
@@ -0,0 +1,306 @@
+use crate::module::cap::insert_sync;
+use crate::module::certification;
+use crate::module::env::{self, time};
+use crate::module::ledger;
+use crate::module::random::{self, RandomSource};
+use crate::module::rbac;
+use crate::module::types::{
+    GeneralValue, NftError, RevealConfig, RevealStatus, Role, TokenMetaData, Token_ID,
+};
+use crate::prop;
+use cap_sdk::{DetailValue, IndefiniteEvent};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+thread_local! {
+    static REVEALS: RefCell<HashMap<Option<String>, RevealConfig>> = RefCell::new(HashMap::new());
+}
+
+pub fn with<T, F: FnOnce(&HashMap<Option<String>, RevealConfig>) -> T>(f: F) -> T {
+    REVEALS.with(|reveals| f(&reveals.borrow()))
+}
+
+pub fn with_mut<T, F: FnOnce(&mut HashMap<Option<String>, RevealConfig>) -> T>(f: F) -> T {
+    REVEALS.with(|reveals| f(&mut reveals.borrow_mut()))
+}
+
+pub fn reveals_info() -> Vec<RevealConfig> {
+    with(|reveals| reveals.values().cloned().collect())
+}
+
+pub fn restore_reveals_info(reveals_info: Vec<RevealConfig>) {
+    with_mut(|reveals| {
+        reveals_info.into_iter().for_each(|config| {
+            reveals.insert(config.class.clone(), config);
+        })
+    });
+}
+
+fn sha256(chunks: &[&[u8]]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    chunks.iter().for_each(|chunk| hasher.update(chunk));
+    hasher.finalize().to_vec()
+}
+
+// The commitment published for a hidden token:
+// sha256(seed || decimal token identifier || ":" || class). `Nat`'s `Display`
+// groups digits with underscores, so the inner value is formatted instead.
+pub fn commitment(seed: &[u8], token_identifier: &Token_ID, class: &str) -> Vec<u8> {
+    sha256(&[
+        seed,
+        token_identifier.0.to_string().as_bytes(),
+        b":",
+        class.as_bytes(),
+    ])
+}
+
+// A class-level reveal takes precedence over the collection-wide one.
+fn config_for(class: Option<&String>) -> Option<RevealConfig> {
+    with(|reveals| {
+        class
+            .and_then(|class| reveals.get(&Some(class.clone())))
+            .or_else(|| reveals.get(&None))
+            .cloned()
+    })
+}
+
+// Generates a secret seed with `raw_rand` and starts hiding the metadata of
+// every token of `class` (or of the whole collection when `None`) minted from
+// now on. Only the seed's hash is published until `reveal` is called.
+pub async fn configure(
+    class: Option<String>,
+    placeholder: Vec<(String, GeneralValue)>,
+) -> Result<Vec<u8>, NftError> {
+    rbac::ensure_role(Role::MetadataEditor)?;
+    if let Some(class) = &class {
+        if prop::properties_of(class).is_empty() {
+            return Err(NftError::ClassNotFound(class.clone()));
+        }
+    }
+    if with(|reveals| reveals.contains_key(&class)) {
+        return Err(NftError::RevealAlreadyConfigured);
+    }
+
+    let seed = random::source()
+        .random_bytes()
+        .await
+        .map_err(NftError::RandomnessUnavailable)?;
+    let seed_hash = sha256(&[&seed]);
+
+    if with(|reveals| reveals.contains_key(&class)) {
+        return Err(NftError::RevealAlreadyConfigured);
+    }
+    with_mut(|reveals| {
+        reveals.insert(
+            class.clone(),
+            RevealConfig {
+                class: class.clone(),
+                placeholder,
+                seed,
+                seed_hash: seed_hash.clone(),
+                configured_at: time(),
+                revealed_at: None,
+            },
+        )
+    });
+
+    insert_sync(IndefiniteEvent {
+        caller: env::caller(),
+        operation: "configureReveal".into(),
+        details: vec![
+            ("class".into(), DetailValue::from(class.unwrap_or_default())),
+            ("seed_hash".into(), DetailValue::Slice(seed_hash.clone())),
+        ],
+    });
+
+    Ok(seed_hash)
+}
+
+pub fn reveal(class: Option<String>) -> Result<Vec<u8>, NftError> {
+    rbac::ensure_role(Role::MetadataEditor)?;
+    let seed = with_mut(|reveals| match reveals.get_mut(&class) {
+        Some(config) if config.revealed_at.is_none() => {
+            config.revealed_at = Some(time());
+            Ok(config.seed.clone())
+        }
+        Some(_) => Err(NftError::AlreadyRevealed),
+        None => Err(NftError::RevealNotConfigured),
+    })?;
+    // Revealed tokens now certify their real metadata.
+    ledger::with(certification::rebuild);
+
+    insert_sync(IndefiniteEvent {
+        caller: env::caller(),
+        operation: "reveal".into(),
+        details: vec![
+            ("class".into(), DetailValue::from(class.unwrap_or_default())),
+            ("seed".into(), DetailValue::Slice(seed.clone())),
+        ],
+    });
+
+    Ok(seed)
+}
+
+pub fn status(class: Option<String>) -> Option<RevealStatus> {
+    with(|reveals| {
+        reveals.get(&class).map(|config| RevealStatus {
+            class: config.class.clone(),
+            placeholder: config.placeholder.clone(),
+            seed_hash: config.seed_hash.clone(),
+            configured_at: config.configured_at,
+            revealed_at: config.revealed_at,
+            seed: config.revealed_at.map(|_| config.seed.clone()),
+        })
+    })
+}
+
+// Called at mint time; returns the commitment to store on the token when its
+// class is currently hidden.
+pub fn commit(token_identifier: &Token_ID, class: Option<&String>) -> Option<Vec<u8>> {
+    config_for(class)
+        .filter(|config| config.revealed_at.is_none())
+        .map(|config| {
+            commitment(
+                &config.seed,
+                token_identifier,
+                class.map(|class| class.as_str()).unwrap_or_default(),
+            )
+        })
+}
+
+pub fn is_hidden(token_metadata: &TokenMetaData) -> bool {
+    token_metadata.reveal_commitment.is_some()
+        && config_for(token_metadata.class())
+            .map_or(false, |config| config.revealed_at.is_none())
+}
+
+// The properties clients are allowed to see for a token right now.
+pub fn visible_properties(token_metadata: &TokenMetaData) -> Vec<(String, GeneralValue)> {
+    if is_hidden(token_metadata) {
+        config_for(token_metadata.class())
+            .map(|config| config.placeholder)
+            .unwrap_or_default()
+    } else {
+        token_metadata.properties.clone()
+    }
+}
+
+pub fn reveal_commitment(token_identifier: Token_ID) -> Result<Option<Vec<u8>>, NftError> {
+    ledger::with(|ledger| {
+        ledger
+            .token_metadata(&token_identifier)
+            .map(|token_metadata| token_metadata.reveal_commitment.clone())
+    })
+}
+
+// Recomputes a revealed token's commitment from the published seed.
+pub fn verify(token_identifier: Token_ID) -> Result<bool, NftError> {
+    let token_metadata =
+        ledger::with(|ledger| ledger.token_metadata(&token_identifier).cloned())?;
+    let committed = match &token_metadata.reveal_commitment {
+        Some(committed) => committed,
+        None => return Err(NftError::RevealNotConfigured),
+    };
+    let config = config_for(token_metadata.class()).ok_or(NftError::RevealNotConfigured)?;
+    if config.revealed_at.is_none() {
+        return Err(NftError::NotRevealed);
+    }
+    let class = token_metadata.class().cloned().unwrap_or_default();
+
+    Ok(*committed == commitment(&config.seed, &token_identifier, &class))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::dip721;
+    use crate::module::env::block_on;
+    use crate::module::testing::{self, custodian, principal};
+    use ic_cdk::export::candid::Nat;
+
+    fn placeholder() -> Vec<(String, GeneralValue)> {
+        vec![("class".into(), GeneralValue::TextContent("?".into()))]
+    }
+
+    fn visible(token_identifier: &Token_ID) -> Vec<(String, GeneralValue)> {
+        ledger::with(|ledger| visible_properties(ledger.token_metadata(token_identifier).unwrap()))
+    }
+
+    fn class_of(properties: &[(String, GeneralValue)]) -> Option<String> {
+        properties.iter().find_map(|(key, value)| match value {
+            GeneralValue::TextContent(class) if key == "class" => Some(class.clone()),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn hidden_tokens_show_the_placeholder_until_revealed() {
+        testing::setup();
+        block_on(configure(None, placeholder())).unwrap();
+        let token_identifier = testing::mint(principal(1));
+
+        assert_eq!(class_of(&visible(&token_identifier)), Some("?".into()));
+        assert!(matches!(
+            verify(token_identifier.clone()),
+            Err(NftError::NotRevealed)
+        ));
+
+        reveal(None).unwrap();
+        assert_eq!(class_of(&visible(&token_identifier)), Some("H".into()));
+        assert!(verify(token_identifier).unwrap());
+        assert!(matches!(reveal(None), Err(NftError::AlreadyRevealed)));
+    }
+
+    #[test]
+    fn verification_rejects_a_commitment_mismatch() {
+        testing::setup();
+        block_on(configure(None, placeholder())).unwrap();
+        let token_identifier = testing::mint(principal(1));
+        reveal(None).unwrap();
+
+        ledger::with_mut(|ledger| {
+            let token_metadata = ledger.tokens.get_mut(&token_identifier).unwrap();
+            token_metadata.reveal_commitment = Some(vec![0; 32]);
+        });
+        assert!(!verify(token_identifier).unwrap());
+    }
+
+    #[test]
+    fn commitments_use_the_plain_decimal_identifier() {
+        testing::setup();
+        block_on(configure(None, placeholder())).unwrap();
+        let seed = with(|reveals| reveals[&None].seed.clone());
+        let token_identifier = Nat::from(1_000u32);
+        ledger::with_mut(|ledger| {
+            dip721::mint_token(
+                ledger,
+                custodian(),
+                principal(1),
+                &token_identifier,
+                prop::properties_of("H"),
+            )
+        })
+        .unwrap();
+
+        assert_eq!(
+            reveal_commitment(token_identifier).unwrap(),
+            Some(sha256(&[&seed, b"1000:H"]))
+        );
+    }
+
+    #[test]
+    fn reveals_need_a_configuration_and_the_metadata_editor_role() {
+        testing::setup();
+        assert!(matches!(reveal(None), Err(NftError::RevealNotConfigured)));
+        assert!(matches!(
+            block_on(configure(Some("Xx".into()), placeholder())),
+            Err(NftError::ClassNotFound(_))
+        ));
+        env::set_caller(principal(1));
+        assert!(matches!(
+            block_on(configure(None, placeholder())),
+            Err(NftError::MissingRole(Role::MetadataEditor))
+        ));
+    }
+}
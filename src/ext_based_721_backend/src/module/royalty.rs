@@ -0,0 +1,165 @@
+use crate::module::cap::insert_sync;
+use crate::module::env;
+use crate::module::ledger;
+use crate::module::rbac;
+use crate::module::types::{NftError, Role, Royalty, Token_ID};
+use crate::prop;
+use cap_sdk::{DetailValue, IndefiniteEvent};
+
+fn validate(royalty: &Option<Royalty>) -> Result<(), NftError> {
+    match royalty {
+        Some(royalty) if !royalty.is_valid() => Err(NftError::InvalidRoyalty),
+        _ => Ok(()),
+    }
+}
+
+fn royalty_details(royalty: &Option<Royalty>) -> Vec<(String, DetailValue)> {
+    match royalty {
+        Some(royalty) => vec![
+            ("recipient".into(), DetailValue::from(royalty.recipient.clone())),
+            (
+                "basis_points".into(),
+                DetailValue::U64(royalty.basis_points as u64),
+            ),
+        ],
+        None => vec![],
+    }
+}
+
+pub fn set_collection_royalty(royalty: Option<Royalty>) -> Result<(), NftError> {
+    rbac::ensure_role(Role::MetadataEditor)?;
+    validate(&royalty)?;
+
+    let details = royalty_details(&royalty);
+    ledger::with_mut(|ledger| ledger.metadata_mut().royalty = royalty);
+
+    insert_sync(IndefiniteEvent {
+        caller: env::caller(),
+        operation: "setCollectionRoyalty".into(),
+        details,
+    });
+
+    Ok(())
+}
+
+pub fn set_class_royalty(class: String, royalty: Option<Royalty>) -> Result<(), NftError> {
+    rbac::ensure_role(Role::MetadataEditor)?;
+    validate(&royalty)?;
+
+    let mut details = royalty_details(&royalty);
+    prop::with_mut(|props| {
+        props
+            .iter_mut()
+            .find(|p| *p.class() == class)
+            .map(|p| p.set_royalty(royalty))
+            .ok_or_else(|| NftError::ClassNotFound(class.clone()))
+    })?;
+
+    details.insert(0, ("class".into(), DetailValue::from(class)));
+    insert_sync(IndefiniteEvent {
+        caller: env::caller(),
+        operation: "setClassRoyalty".into(),
+        details,
+    });
+
+    Ok(())
+}
+
+pub fn class_royalty(class: &str) -> Option<Royalty> {
+    prop::with(|props| {
+        props
+            .iter()
+            .find(|p| p.class() == class)
+            .and_then(|p| p.royalty().clone())
+    })
+}
+
+// A class-level royalty takes precedence over the collection-wide one.
+pub fn royalty_of(token_identifier: &Token_ID) -> Result<Option<Royalty>, NftError> {
+    let (class, collection_royalty) = ledger::with(|ledger| {
+        ledger.token_metadata(token_identifier).map(|token| {
+            (token.class().cloned(), ledger.metadata.royalty.clone())
+        })
+    })?;
+
+    Ok(class
+        .and_then(|class| class_royalty(&class))
+        .or(collection_royalty))
+}
+
+pub fn royalty_info(token_identifier: &Token_ID, sale_price: u64) -> Result<Option<(String, u64)>, NftError> {
+    royalty_of(token_identifier).map(|royalty| {
+        royalty.map(|royalty| {
+            let amount = royalty.amount(sale_price);
+            (royalty.recipient, amount)
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::testing::{self, mint_class, principal};
+
+    fn royalty(digit: char, basis_points: u16) -> Royalty {
+        Royalty {
+            recipient: digit.to_string().repeat(64),
+            basis_points,
+        }
+    }
+
+    #[test]
+    fn class_royalty_overrides_the_collection_royalty() {
+        testing::setup();
+        set_collection_royalty(Some(royalty('a', 500))).unwrap();
+        set_class_royalty("H".into(), Some(royalty('b', 1_000))).unwrap();
+        let hydrogen = mint_class(principal(1), "H");
+        let helium = mint_class(principal(1), "He");
+
+        assert_eq!(royalty_of(&hydrogen).unwrap(), Some(royalty('b', 1_000)));
+        assert_eq!(royalty_of(&helium).unwrap(), Some(royalty('a', 500)));
+        assert_eq!(
+            royalty_info(&hydrogen, 2_000).unwrap(),
+            Some(("b".repeat(64), 200))
+        );
+        assert_eq!(
+            royalty_info(&helium, 2_000).unwrap(),
+            Some(("a".repeat(64), 100))
+        );
+    }
+
+    #[test]
+    fn clearing_the_class_royalty_falls_back_to_the_collection() {
+        testing::setup();
+        set_collection_royalty(Some(royalty('a', 500))).unwrap();
+        set_class_royalty("H".into(), Some(royalty('b', 1_000))).unwrap();
+        set_class_royalty("H".into(), None).unwrap();
+        let hydrogen = mint_class(principal(1), "H");
+
+        assert_eq!(royalty_of(&hydrogen).unwrap(), Some(royalty('a', 500)));
+        set_collection_royalty(None).unwrap();
+        assert_eq!(royalty_info(&hydrogen, 2_000).unwrap(), None);
+    }
+
+    #[test]
+    fn invalid_royalties_and_unknown_classes_are_rejected() {
+        testing::setup();
+        assert!(matches!(
+            set_collection_royalty(Some(royalty('a', 10_001))),
+            Err(NftError::InvalidRoyalty)
+        ));
+        assert!(matches!(
+            set_collection_royalty(Some(royalty('z', 500))),
+            Err(NftError::InvalidRoyalty)
+        ));
+        assert!(matches!(
+            set_class_royalty("Xx".into(), Some(royalty('a', 500))),
+            Err(NftError::ClassNotFound(_))
+        ));
+        env::set_caller(principal(1));
+        assert!(matches!(
+            set_collection_royalty(Some(royalty('a', 500))),
+            Err(NftError::MissingRole(Role::MetadataEditor))
+        ));
+    }
+}
@@ -0,0 +1,313 @@
+use crate::module::cap::insert_sync;
+use crate::module::dip721;
+use crate::module::env::{self, time};
+use crate::module::ledger::{self, Ledger};
+use crate::module::types::{NftError, SwapOffer, SwapProposal, TokenLock, Token_ID};
+use cap_sdk::{DetailValue, IndefiniteEvent};
+use ic_cdk::export::Principal;
+use std::collections::HashSet;
+
+fn escrow_reason(id: u64) -> String {
+    format!("swap offer {}", id)
+}
+
+fn tokens_detail(tokens: &[Token_ID]) -> DetailValue {
+    DetailValue::Vec(
+        tokens
+            .iter()
+            .map(|token_identifier| DetailValue::from(token_identifier.to_string()))
+            .collect(),
+    )
+}
+
+fn has_duplicates(tokens: &[Token_ID]) -> bool {
+    tokens.iter().collect::<HashSet<_>>().len() != tokens.len()
+}
+
+// Checks that every token is owned by `owner` and could be transferred right
+// now, ignoring the escrow of offer `id` when given.
+fn check_tokens(
+    ledger: &Ledger,
+    owner: &Principal,
+    tokens: &[Token_ID],
+    escrow: Option<u64>,
+) -> Result<(), NftError> {
+    let escrow = escrow.map(escrow_reason);
+    for token_identifier in tokens.iter() {
+        let token = ledger.token_metadata(token_identifier)?;
+        if token.owner != Some(*owner) {
+            return Err(NftError::UnauthorizedOwner);
+        }
+        dip721::check_transferable(ledger, token_identifier, escrow.as_deref())?;
+    }
+    Ok(())
+}
+
+// Escrows the proposer's tokens in the canister's name until the offer
+// expires, so they cannot be moved while the offer is open.
+pub fn propose(proposal: SwapProposal) -> Result<u64, NftError> {
+    ledger::with_mut(|ledger| {
+        let caller = env::caller();
+        let now = time();
+        if proposal.expires_at <= now {
+            return Err(NftError::InvalidExpiry);
+        }
+        if proposal.counterparty == caller
+            || proposal.offered.is_empty()
+            || proposal.requested.is_empty()
+            || has_duplicates(&proposal.offered)
+            || has_duplicates(&proposal.requested)
+        {
+            return Err(NftError::InvalidOffer);
+        }
+        ledger.offers.retain(|_, offer| offer.expires_at > now);
+        check_tokens(ledger, &caller, &proposal.offered, None)?;
+        check_tokens(ledger, &proposal.counterparty, &proposal.requested, None)?;
+
+        let id = ledger.offer_count;
+        ledger.offer_count += 1;
+        for token_identifier in proposal.offered.iter() {
+            ledger.escrow(
+                token_identifier.clone(),
+                TokenLock {
+                    owner: caller,
+                    locked_by: env::id(),
+                    reason: escrow_reason(id),
+                    locked_at: now,
+                    expires_at: proposal.expires_at,
+                },
+            );
+        }
+        ledger.offers.insert(
+            id,
+            SwapOffer {
+                id,
+                proposer: caller,
+                counterparty: proposal.counterparty,
+                offered: proposal.offered.clone(),
+                requested: proposal.requested.clone(),
+                created_at: now,
+                expires_at: proposal.expires_at,
+            },
+        );
+
+        insert_sync(IndefiniteEvent {
+            caller,
+            operation: "proposeSwap".into(),
+            details: vec![
+                ("offer".into(), DetailValue::U64(id)),
+                ("counterparty".into(), DetailValue::from(proposal.counterparty)),
+                ("offered".into(), tokens_detail(&proposal.offered)),
+                ("requested".into(), tokens_detail(&proposal.requested)),
+                ("expires_at".into(), DetailValue::U64(proposal.expires_at)),
+            ],
+        });
+
+        Ok(id)
+    })
+}
+
+fn release(ledger: &mut Ledger, offer: &SwapOffer) {
+    for token_identifier in offer.offered.iter() {
+        ledger.release_escrow(token_identifier, &escrow_reason(offer.id));
+    }
+}
+
+// Swaps both sides in one message. Every leg is validated before anything is
+// mutated, so a pause, a lock or a changed owner is reported as an error; a
+// transfer failing after that point is a bug and traps so nothing is
+// half-swapped.
+pub fn accept(id: u64) -> Result<(), NftError> {
+    ledger::with_mut(|ledger| {
+        let caller = env::caller();
+        let offer = ledger.offers.get(&id).cloned().ok_or(NftError::OfferNotFound)?;
+        if offer.counterparty != caller {
+            return Err(NftError::UnauthorizedOwner);
+        }
+        if offer.expires_at <= time() {
+            return Err(NftError::OfferExpired);
+        }
+        check_tokens(ledger, &offer.proposer, &offer.offered, Some(id))?;
+        check_tokens(ledger, &offer.counterparty, &offer.requested, None)?;
+
+        release(ledger, &offer);
+        ledger.offers.remove(&id);
+        for (tokens, to) in [
+            (&offer.offered, offer.counterparty),
+            (&offer.requested, offer.proposer),
+        ] {
+            for token_identifier in tokens.iter() {
+                if let Err(err) = dip721::transfer_token(ledger, caller, token_identifier, to) {
+                    ic_cdk::trap(&format!("accept_swap: failed to transfer {}: {:?}", token_identifier, err));
+                }
+            }
+        }
+
+        insert_sync(IndefiniteEvent {
+            caller,
+            operation: "acceptSwap".into(),
+            details: vec![
+                ("offer".into(), DetailValue::U64(id)),
+                ("proposer".into(), DetailValue::from(offer.proposer)),
+                ("counterparty".into(), DetailValue::from(offer.counterparty)),
+                ("offered".into(), tokens_detail(&offer.offered)),
+                ("requested".into(), tokens_detail(&offer.requested)),
+            ],
+        });
+        ledger.inc_tx();
+
+        Ok(())
+    })
+}
+
+pub fn reject(id: u64) -> Result<(), NftError> {
+    close(id, "rejectSwap", |offer| offer.counterparty)
+}
+
+pub fn cancel(id: u64) -> Result<(), NftError> {
+    close(id, "cancelSwap", |offer| offer.proposer)
+}
+
+// Removes an offer without swapping and releases the escrowed tokens.
+fn close(id: u64, operation: &str, party: fn(&SwapOffer) -> Principal) -> Result<(), NftError> {
+    ledger::with_mut(|ledger| {
+        let caller = env::caller();
+        let offer = ledger.offers.get(&id).cloned().ok_or(NftError::OfferNotFound)?;
+        if party(&offer) != caller {
+            return Err(NftError::UnauthorizedOwner);
+        }
+        release(ledger, &offer);
+        ledger.offers.remove(&id);
+
+        insert_sync(IndefiniteEvent {
+            caller,
+            operation: operation.into(),
+            details: vec![("offer".into(), DetailValue::U64(id))],
+        });
+
+        Ok(())
+    })
+}
+
+pub fn offer(id: u64) -> Option<SwapOffer> {
+    ledger::with(|ledger| ledger.offers.get(&id).cloned())
+}
+
+// Open offers made by or to `principal`.
+pub fn offers_of(principal: Principal) -> Vec<SwapOffer> {
+    let now = time();
+    ledger::with(|ledger| {
+        ledger
+            .offers
+            .values()
+            .filter(|offer| offer.expires_at > now)
+            .filter(|offer| offer.proposer == principal || offer.counterparty == principal)
+            .cloned()
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::testing::{self, owner_of, principal};
+    use crate::module::types::{Operation, Role};
+
+    const HOUR: u64 = 60 * 60 * 1_000_000_000;
+
+    // Alice offers one token for one of Bob's and returns the offer id.
+    fn offer_between(alice: Principal, bob: Principal) -> (u64, Token_ID, Token_ID) {
+        let offered = testing::mint(alice);
+        let requested = testing::mint(bob);
+        env::set_caller(alice);
+        let id = propose(SwapProposal {
+            counterparty: bob,
+            offered: vec![offered.clone()],
+            requested: vec![requested.clone()],
+            expires_at: time() + HOUR,
+        })
+        .unwrap();
+        (id, offered, requested)
+    }
+
+    #[test]
+    fn accepting_swaps_both_sides() {
+        testing::setup();
+        let (alice, bob) = (principal(1), principal(2));
+        let (id, offered, requested) = offer_between(alice, bob);
+        assert!(ledger::with(|ledger| ledger.is_locked(&offered)));
+
+        env::set_caller(bob);
+        accept(id).unwrap();
+
+        assert_eq!(owner_of(&offered), Some(bob));
+        assert_eq!(owner_of(&requested), Some(alice));
+        assert!(!ledger::with(|ledger| ledger.is_locked(&offered)));
+        assert!(offer(id).is_none());
+    }
+
+    #[test]
+    fn accepting_while_paused_fails_without_moving_anything() {
+        testing::setup();
+        let (alice, bob) = (principal(1), principal(2));
+        let (id, offered, requested) = offer_between(alice, bob);
+        ledger::with_mut(|ledger| ledger.set_paused(Operation::Transfer, true));
+
+        env::set_caller(bob);
+        assert!(matches!(
+            accept(id),
+            Err(NftError::OperationPaused(Operation::Transfer))
+        ));
+        assert_eq!(owner_of(&offered), Some(alice));
+        assert_eq!(owner_of(&requested), Some(bob));
+        assert!(offer(id).is_some());
+        assert!(ledger::with(|ledger| ledger.is_locked(&offered)));
+    }
+
+    #[test]
+    fn accepting_a_locked_leg_fails_without_moving_anything() {
+        testing::setup();
+        let (alice, bob) = (principal(1), principal(2));
+        let (id, offered, requested) = offer_between(alice, bob);
+        testing::grant(principal(3), Role::GameOperator);
+        env::set_caller(principal(3));
+        crate::module::lock::lock(requested.clone(), "quest".into(), time() + HOUR).unwrap();
+
+        env::set_caller(bob);
+        assert!(matches!(accept(id), Err(NftError::TokenLocked)));
+        assert_eq!(owner_of(&offered), Some(alice));
+        assert_eq!(owner_of(&requested), Some(bob));
+    }
+
+    #[test]
+    fn escrowed_tokens_cannot_be_offered_twice() {
+        testing::setup();
+        let (alice, bob) = (principal(1), principal(2));
+        let (_, offered, requested) = offer_between(alice, bob);
+
+        assert!(matches!(
+            propose(SwapProposal {
+                counterparty: bob,
+                offered: vec![offered],
+                requested: vec![requested],
+                expires_at: time() + HOUR,
+            }),
+            Err(NftError::TokenLocked)
+        ));
+    }
+
+    #[test]
+    fn rejecting_releases_the_escrow() {
+        testing::setup();
+        let (alice, bob) = (principal(1), principal(2));
+        let (id, offered, _) = offer_between(alice, bob);
+
+        env::set_caller(alice);
+        assert!(matches!(reject(id), Err(NftError::UnauthorizedOwner)));
+        env::set_caller(bob);
+        reject(id).unwrap();
+
+        assert!(!ledger::with(|ledger| ledger.is_locked(&offered)));
+        assert!(offer(id).is_none());
+    }
+}
@@ -0,0 +1,67 @@
+// Fixtures shared by the unit tests. Every test runs on its own thread, so the
+// thread-local registries start out empty for each of them.
+use crate::module::dip721;
+use crate::module::env;
+use crate::module::icrc3;
+use crate::module::ledger;
+use crate::module::types::{GetBlocksArgs, Role, Token_ID, Value};
+use crate::prop;
+use ic_cdk::export::Principal;
+
+pub fn principal(n: u8) -> Principal {
+    Principal::from_slice(&[n; 10])
+}
+
+pub fn custodian() -> Principal {
+    principal(100)
+}
+
+// Loads the built-in classes and makes `custodian()` the caller.
+pub fn setup() {
+    prop::init();
+    ledger::with_mut(|ledger| ledger.metadata_mut().custodians.insert(custodian()));
+    env::set_caller(custodian());
+}
+
+pub fn grant(principal: Principal, role: Role) {
+    ledger::with_mut(|ledger| ledger.grant_role(principal, role));
+}
+
+// Mints a token of class `class` straight into the ledger.
+pub fn mint_class(to: Principal, class: &str) -> Token_ID {
+    let token_identifier = dip721::allocate_token_identifier();
+    ledger::with_mut(|ledger| {
+        dip721::mint_token(
+            ledger,
+            custodian(),
+            to,
+            &token_identifier,
+            prop::properties_of(class),
+        )
+    })
+    .unwrap();
+    token_identifier
+}
+
+pub fn mint(to: Principal) -> Token_ID {
+    mint_class(to, "H")
+}
+
+pub fn owner_of(token_identifier: &Token_ID) -> Option<Principal> {
+    ledger::with(|ledger| ledger.owner_of(token_identifier).ok().flatten())
+}
+
+// The `btype` of ICRC-3 block `index`, if the log has such a block.
+pub fn block_type(index: u64) -> Option<String> {
+    let result = icrc3::get_blocks(vec![GetBlocksArgs {
+        start: index.into(),
+        length: 1u64.into(),
+    }]);
+    match &result.blocks.first()?.block {
+        Value::Map(entries) => entries.iter().find_map(|(key, value)| match value {
+            Value::Text(btype) if key == "btype" => Some(btype.clone()),
+            _ => None,
+        }),
+        _ => None,
+    }
+}
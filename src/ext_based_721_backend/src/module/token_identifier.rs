@@ -16,13 +16,6 @@ impl TokenIndex {
         self.0
     }
 }
-#[derive(Debug, CandidType, Clone, Deserialize)]
-pub enum ShikuError {
-    #[allow(non_camel_case_types)]
-    ok(String),
-    #[allow(non_camel_case_types)]
-    err(String),    
-}
 
 #[derive(CandidType, Debug, Clone, Deserialize)]
 pub struct TokenObj {
@@ -61,18 +54,18 @@ pub fn decode_token_id(tid: &TokenIdentifier) -> NFTServiceResult<TokenObj> {
         Ok(principal) => {
             let bytes = principal.as_slice();
             if !bytes.starts_with(&TOKEN_ID_PREFIX) {
-                return Err(CommonError::Other(format!("This is not TokenIdentifier")));
+                return Err(CommonError::Other("This is not TokenIdentifier".to_string()));
             }
             let canister: Vec<u8> = bytes[4..(4 + CANISTER_ID_HASH_LEN_IN_BYTES)].to_vec();
             let mut token_index: [u8; 4] = Default::default();
             token_index.copy_from_slice(&bytes[14..]);
 
-            return Ok(TokenObj {
+            Ok(TokenObj {
                 index: TokenIndex(u32::from_be_bytes(token_index)),
                 canister,
-            });
+            })
         }
-        Err(_) => return Err(CommonError::InvalidToken(tid.to_owned())),
+        Err(_) => Err(CommonError::InvalidToken(tid.to_owned())),
     }
 }
 
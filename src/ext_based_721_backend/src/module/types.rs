@@ -1,8 +1,12 @@
 use crate::token_identifier;
-use ic_cdk::export::candid::{CandidType, Deserialize, Int, Nat};
+use cap_sdk::{DetailValue, IndefiniteEvent};
+use ic_cdk::export::candid::parser::types::FuncMode;
+use ic_cdk::export::candid::types::{Function, Serializer, Type};
+use ic_cdk::export::candid::{CandidType, Deserialize, Func, Int, Nat};
 use ic_cdk::export::Principal;
 use serde::Serialize;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 
 #[derive(CandidType, Deserialize)]
 pub struct InitArgs {
@@ -11,6 +15,7 @@ pub struct InitArgs {
     pub symbol: Option<String>,
     pub custodians: Option<HashSet<Principal>>,
     pub cap: Option<Principal>,
+    pub icp_ledger: Option<Principal>,
 }
 
 #[derive(CandidType, Default, Deserialize)]
@@ -19,18 +24,319 @@ pub struct MetaData {
     pub logo: Option<String>,
     pub symbol: Option<String>,
     pub custodians: HashSet<Principal>,
+    pub paused: HashSet<Operation>,
+    pub roles: HashMap<Principal, HashSet<Role>>,
+    pub royalty: Option<Royalty>,
+    pub marketplace_fee: Option<Royalty>,
+    pub icp_ledger: Option<Principal>,
     pub created_at: u64,
     pub upgraded_at: u64,
 }
 
-#[derive(CandidType)]
-pub struct Status {
-    pub total_transactions: Nat,
-    pub total_supply: Nat,
-    pub cycles: Nat,
-    pub total_unique_holders: Nat,
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Operation {
+    Mint,
+    Transfer,
+    Approve,
+    Burn,
 }
-#[warn(non_camel_case_types)]
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Role {
+    Admin,
+    Minter,
+    MetadataEditor,
+    Pauser,
+    Burner,
+    GameOperator,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Royalty {
+    pub recipient: AccountIdentifier,
+    pub basis_points: u16,
+}
+
+impl Royalty {
+    pub const MAX_BASIS_POINTS: u16 = 10_000;
+
+    pub fn is_valid(&self) -> bool {
+        self.basis_points <= Self::MAX_BASIS_POINTS
+            && self.recipient.len() == 64
+            && self.recipient.chars().all(|c| c.is_ascii_hexdigit())
+    }
+
+    pub fn amount(&self, sale_price: u64) -> u64 {
+        (sale_price as u128 * self.basis_points as u128 / Self::MAX_BASIS_POINTS as u128) as u64
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RecipeItem {
+    pub class: String,
+    pub quantity: u32,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Recipe {
+    pub id: String,
+    pub inputs: Vec<RecipeItem>,
+    pub outputs: Vec<RecipeItem>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BoxOutcome {
+    pub class: String,
+    pub weight: u32,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct MysteryBox {
+    pub class: String,
+    pub outcomes: Vec<BoxOutcome>,
+}
+
+// `seed` stays inside the canister until the reveal; queries go through
+// `RevealStatus`.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct RevealConfig {
+    pub class: Option<String>,
+    pub placeholder: Vec<(String, GeneralValue)>,
+    pub seed: Vec<u8>,
+    pub seed_hash: Vec<u8>,
+    pub configured_at: u64,
+    pub revealed_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct RevealStatus {
+    pub class: Option<String>,
+    pub placeholder: Vec<(String, GeneralValue)>,
+    pub seed_hash: Vec<u8>,
+    pub configured_at: u64,
+    pub revealed_at: Option<u64>,
+    pub seed: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SwapProposal {
+    pub counterparty: Principal,
+    pub offered: Vec<Token_ID>,
+    pub requested: Vec<Token_ID>,
+    pub expires_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SwapOffer {
+    pub id: u64,
+    pub proposer: Principal,
+    pub counterparty: Principal,
+    pub offered: Vec<Token_ID>,
+    pub requested: Vec<Token_ID>,
+    pub created_at: u64,
+    pub expires_at: u64,
+}
+
+// Prices are in e8s. A Dutch price falls linearly from `start_price` at the
+// start to `end_price` at `end_at`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum AuctionKind {
+    English { min_bid: u64, increment: u64 },
+    Dutch { start_price: u64, end_price: u64 },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AuctionRequest {
+    pub token: Token_ID,
+    pub kind: AuctionKind,
+    pub end_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Bid {
+    pub bidder: Principal,
+    pub amount: u64,
+    pub placed_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuctionStatus {
+    Open,
+    Settling,
+    Sold,
+    Unsold,
+    Cancelled,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Auction {
+    pub id: u64,
+    pub token: Token_ID,
+    pub seller: Principal,
+    pub kind: AuctionKind,
+    pub start_at: u64,
+    pub end_at: u64,
+    pub highest_bid: Option<Bid>,
+    pub status: AuctionStatus,
+    // Failed settlements so far, and when the heartbeat may try again.
+    pub settle_attempts: u32,
+    pub next_settle_at: u64,
+}
+
+// A paid public sale of one class. Prices are in e8s and proceeds are sent to
+// the `recipient` account identifier.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct MintSaleRequest {
+    pub class: String,
+    pub price: u64,
+    pub supply: u64,
+    pub start_at: u64,
+    pub end_at: Option<u64>,
+    pub recipient: AccountIdentifier,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct MintSale {
+    pub class: String,
+    pub price: u64,
+    pub supply: u64,
+    pub sold: u64,
+    pub start_at: u64,
+    pub end_at: Option<u64>,
+    pub recipient: AccountIdentifier,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MintPhaseKind {
+    Allowlist,
+    Public,
+}
+
+// A launch window. Allowlist phases give each listed wallet its own quota;
+// public phases are open to every wallet up to `wallet_cap`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct MintPhase {
+    pub name: String,
+    pub kind: MintPhaseKind,
+    pub start_at: u64,
+    pub end_at: u64,
+    pub wallet_cap: Option<u32>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct MintPhases {
+    pub phases: Vec<MintPhase>,
+    pub allowlists: HashMap<String, HashMap<AccountIdentifier, u32>>,
+    pub minted: HashMap<String, HashMap<AccountIdentifier, u32>>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AirdropEntry {
+    pub to: User,
+    pub class: String,
+    pub metadata: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub enum JobKind {
+    BatchMint,
+    BatchTransferV1,
+    BatchTransferV2,
+    Airdrop,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum JobItem {
+    Mint {
+        to: Principal,
+        class: String,
+        metadata: Option<Vec<u8>>,
+    },
+    Transfer { from: Principal, to: Principal, token: Token_ID },
+}
+
+// `Done` carries the transaction id, as the synchronous batch endpoints return.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum JobOutcome {
+    Done(Nat),
+    Failed(String),
+}
+
+// A batch processed over several messages; `outcomes[i]` is the result of
+// `items[i]`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Job {
+    pub id: u64,
+    pub kind: JobKind,
+    pub submitted_by: Principal,
+    pub submitted_at: u64,
+    pub items: Vec<JobItem>,
+    pub outcomes: Vec<JobOutcome>,
+    pub completed_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct JobProgress {
+    pub id: u64,
+    pub kind: JobKind,
+    pub submitted_by: Principal,
+    pub submitted_at: u64,
+    pub total: u64,
+    pub processed: u64,
+    pub failed: u64,
+    pub completed_at: Option<u64>,
+}
+
+// Events waiting to be delivered to CAP, in emission order.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct CapOutbox {
+    pub disabled: bool,
+    pub pending: Vec<IndefiniteEvent>,
+    pub delivered: u64,
+    pub failed: u64,
+    pub dropped: u64,
+    pub backoff: u64,
+    pub next_attempt_at: u64,
+    pub flush_started_at: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CapMetrics {
+    pub enabled: bool,
+    pub pending: u64,
+    pub delivered: u64,
+    pub failed: u64,
+    pub dropped: u64,
+    pub next_attempt_at: u64,
+    pub last_error: Option<String>,
+}
+
+// A request rejected by validation, kept out of the public history.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Diagnostic {
+    pub recorded_at: u64,
+    pub caller: Principal,
+    pub operation: String,
+    pub details: Vec<(String, DetailValue)>,
+}
+
+#[allow(non_camel_case_types)]
 pub type Token_ID = Nat;
 
 #[derive(CandidType, Deserialize, Serialize, Clone, PartialEq)]
@@ -68,9 +374,48 @@ pub struct TokenMetaData {
     pub approved_by: Option<Principal>,
     pub burned_at: Option<u64>,
     pub burned_by: Option<Principal>,
+    pub soulbound: bool,
+    pub reveal_commitment: Option<Vec<u8>>,
+    pub user: Option<Principal>,
+    pub user_expires: Option<u64>,
+}
+
+impl TokenMetaData {
+    // The renter of the token, if the rental has not expired yet.
+    pub fn active_user(&self, now: u64) -> Option<Principal> {
+        match (self.user, self.user_expires) {
+            (Some(user), Some(expires)) if now < expires => Some(user),
+            _ => None,
+        }
+    }
+
+    pub fn class(&self) -> Option<&String> {
+        self.properties
+            .iter()
+            .find(|(key, _)| key == "class")
+            .and_then(|(_, value)| match value {
+                GeneralValue::TextContent(class) => Some(class),
+                _ => None,
+            })
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TokenLock {
+    pub owner: Principal,
+    pub locked_by: Principal,
+    pub reason: String,
+    pub locked_at: u64,
+    pub expires_at: u64,
 }
 
-#[derive(Debug, CandidType)]
+impl TokenLock {
+    pub fn is_active(&self, now: u64) -> bool {
+        now < self.expires_at
+    }
+}
+
+#[derive(Debug, CandidType, Deserialize)]
 pub enum NftError {
     UnauthorizedOwner,
     UnauthorizedOperator,
@@ -79,6 +424,199 @@ pub enum NftError {
     TokenNotFound,
     ExistedNFT,
     SelfApprove,
+    OperationPaused(Operation),
+    MissingRole(Role),
+    InvalidRoyalty,
+    ClassNotFound(String),
+    NonTransferable,
+    TokenLocked,
+    InvalidExpiry,
+    InvalidRecipe,
+    RecipeNotFound(String),
+    InsufficientInputs(String),
+    NotAMysteryBox,
+    InvalidMysteryBox,
+    RandomnessUnavailable(String),
+    RevealAlreadyConfigured,
+    RevealNotConfigured,
+    AlreadyRevealed,
+    NotRevealed,
+    OfferNotFound,
+    OfferExpired,
+    InvalidOffer,
+    InvalidAuction,
+    AuctionNotFound,
+    AuctionEnded,
+    AuctionNotEnded,
+    BidTooLow,
+    InsufficientFunds,
+    LedgerError(String),
+    InvalidMintSale,
+    MintSaleNotFound(String),
+    MintSaleNotActive,
+    SoldOut,
+    InvalidMintPhase,
+    MintPhaseNotFound(String),
+    MintPhaseNotActive,
+    QuotaExceeded,
+    InvalidRecipient(u64),
+    CertificateUnavailable,
+    TooOld,
+    CreatedInFuture(u64),
+    FlushInProgress,
+    InvalidTokenIdentifier(String),
+    InvalidMetadata(String),
+    InvalidQuantity,
+    DuplicateToken(Token_ID),
+    SalePending,
+    ClaimInProgress,
+    ArchiveInUse,
+}
+
+impl NftError {
+    // Stable numeric code reported alongside the message, e.g. as the ICRC
+    // `error_code`. Codes follow declaration order; new variants take the next
+    // free number and existing codes are never reused.
+    pub fn code(&self) -> u32 {
+        match self {
+            NftError::UnauthorizedOwner => 1,
+            NftError::UnauthorizedOperator => 2,
+            NftError::OwnerNotFound => 3,
+            NftError::OperatorNotFound => 4,
+            NftError::TokenNotFound => 5,
+            NftError::ExistedNFT => 6,
+            NftError::SelfApprove => 7,
+            NftError::OperationPaused(_) => 8,
+            NftError::MissingRole(_) => 9,
+            NftError::InvalidRoyalty => 10,
+            NftError::ClassNotFound(_) => 11,
+            NftError::NonTransferable => 12,
+            NftError::TokenLocked => 13,
+            NftError::InvalidExpiry => 14,
+            NftError::InvalidRecipe => 15,
+            NftError::RecipeNotFound(_) => 16,
+            NftError::InsufficientInputs(_) => 17,
+            NftError::NotAMysteryBox => 18,
+            NftError::InvalidMysteryBox => 19,
+            NftError::RandomnessUnavailable(_) => 20,
+            NftError::RevealAlreadyConfigured => 21,
+            NftError::RevealNotConfigured => 22,
+            NftError::AlreadyRevealed => 23,
+            NftError::NotRevealed => 24,
+            NftError::OfferNotFound => 25,
+            NftError::OfferExpired => 26,
+            NftError::InvalidOffer => 27,
+            NftError::InvalidAuction => 28,
+            NftError::AuctionNotFound => 29,
+            NftError::AuctionEnded => 30,
+            NftError::AuctionNotEnded => 31,
+            NftError::BidTooLow => 32,
+            NftError::InsufficientFunds => 33,
+            NftError::LedgerError(_) => 34,
+            NftError::InvalidMintSale => 35,
+            NftError::MintSaleNotFound(_) => 36,
+            NftError::MintSaleNotActive => 37,
+            NftError::SoldOut => 38,
+            NftError::InvalidMintPhase => 39,
+            NftError::MintPhaseNotFound(_) => 40,
+            NftError::MintPhaseNotActive => 41,
+            NftError::QuotaExceeded => 42,
+            NftError::InvalidRecipient(_) => 43,
+            NftError::CertificateUnavailable => 45,
+            NftError::TooOld => 46,
+            NftError::CreatedInFuture(_) => 47,
+            NftError::FlushInProgress => 48,
+            NftError::InvalidTokenIdentifier(_) => 49,
+            NftError::InvalidMetadata(_) => 50,
+            NftError::InvalidQuantity => 51,
+            NftError::DuplicateToken(_) => 52,
+            NftError::SalePending => 53,
+            NftError::ClaimInProgress => 54,
+            NftError::ArchiveInUse => 55,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            NftError::UnauthorizedOwner => "caller is not the owner of the token".into(),
+            NftError::UnauthorizedOperator => "caller is not an operator of the token".into(),
+            NftError::OwnerNotFound => "owner not found".into(),
+            NftError::OperatorNotFound => "operator not found".into(),
+            NftError::TokenNotFound => "token not found".into(),
+            NftError::ExistedNFT => "token already exists".into(),
+            NftError::SelfApprove => "cannot approve oneself".into(),
+            NftError::OperationPaused(operation) => format!("{:?} is paused", operation),
+            NftError::MissingRole(role) => format!("caller lacks the {:?} role", role),
+            NftError::InvalidRoyalty => "invalid royalty".into(),
+            NftError::ClassNotFound(class) => format!("class {} not found", class),
+            NftError::NonTransferable => "token is not transferable".into(),
+            NftError::TokenLocked => "token is locked".into(),
+            NftError::InvalidExpiry => "expiry is not in the future".into(),
+            NftError::InvalidRecipe => "invalid recipe".into(),
+            NftError::RecipeNotFound(id) => format!("recipe {} not found", id),
+            NftError::InsufficientInputs(class) => format!("not enough tokens of class {}", class),
+            NftError::NotAMysteryBox => "token is not a mystery box".into(),
+            NftError::InvalidMysteryBox => "invalid mystery box".into(),
+            NftError::RandomnessUnavailable(reason) => {
+                format!("randomness unavailable: {}", reason)
+            }
+            NftError::RevealAlreadyConfigured => "reveal already configured".into(),
+            NftError::RevealNotConfigured => "reveal not configured".into(),
+            NftError::AlreadyRevealed => "already revealed".into(),
+            NftError::NotRevealed => "not revealed yet".into(),
+            NftError::OfferNotFound => "offer not found".into(),
+            NftError::OfferExpired => "offer expired".into(),
+            NftError::InvalidOffer => "invalid offer".into(),
+            NftError::InvalidAuction => "invalid auction".into(),
+            NftError::AuctionNotFound => "auction not found".into(),
+            NftError::AuctionEnded => "auction has ended".into(),
+            NftError::AuctionNotEnded => "auction has not ended".into(),
+            NftError::BidTooLow => "bid too low".into(),
+            NftError::InsufficientFunds => "insufficient funds".into(),
+            NftError::LedgerError(reason) => format!("ledger error: {}", reason),
+            NftError::InvalidMintSale => "invalid mint sale".into(),
+            NftError::MintSaleNotFound(class) => format!("no mint sale for class {}", class),
+            NftError::MintSaleNotActive => "mint sale is not active".into(),
+            NftError::SoldOut => "sold out".into(),
+            NftError::InvalidMintPhase => "invalid mint phase".into(),
+            NftError::MintPhaseNotFound(name) => format!("mint phase {} not found", name),
+            NftError::MintPhaseNotActive => "no mint phase is active".into(),
+            NftError::QuotaExceeded => "mint quota exceeded".into(),
+            NftError::InvalidRecipient(index) => format!("invalid recipient at entry {}", index),
+            NftError::CertificateUnavailable => {
+                "certificates are only available in query calls".into()
+            }
+            NftError::TooOld => "created_at_time is too old".into(),
+            NftError::CreatedInFuture(ledger_time) => {
+                format!("created_at_time is ahead of canister time {}", ledger_time)
+            }
+            NftError::FlushInProgress => "a CAP flush is already in progress".into(),
+            NftError::InvalidTokenIdentifier(token) => format!("invalid token identifier {}", token),
+            NftError::InvalidMetadata(reason) => format!("invalid metadata: {}", reason),
+            NftError::InvalidQuantity => "quantity must be positive".into(),
+            NftError::DuplicateToken(token) => format!("token {} is listed twice", token),
+            NftError::SalePending => "token has a marketplace sale awaiting settlement".into(),
+            NftError::ClaimInProgress => "a claim for this buyer is already in progress".into(),
+            NftError::ArchiveInUse => "blocks were already moved to another archive".into(),
+        }
+    }
+}
+
+// "<code>: <message>", for error shapes that only carry text.
+impl fmt::Display for NftError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.code(), self.message())
+    }
+}
+
+// EXT endpoints report errors as `CommonError`.
+impl From<NftError> for CommonError {
+    fn from(err: NftError) -> Self {
+        match err {
+            NftError::InvalidTokenIdentifier(token) => CommonError::InvalidToken(token),
+            err => CommonError::Other(err.to_string()),
+        }
+    }
 }
 
 /////////////// YUMI TYPES ////////////
@@ -105,18 +643,12 @@ impl User {
     }
 }
 
-#[derive(Debug, CandidType, Clone, Deserialize)]
-pub struct AllowanceRequest {
-    pub owner: User,
-    pub spender: Principal,
-    pub token: TokenIdentifier__1,
-}
-
 #[derive(Debug, CandidType, Clone, Deserialize)]
 pub struct MintRequest {
     pub to: User,
     pub metadata: Option<Vec<u8>>,
     pub class: String,
+    pub created_at_time: Option<u64>,
 }
 
 #[derive(Debug, CandidType, Clone, Deserialize)]
@@ -127,30 +659,10 @@ pub struct ApproveRequest {
     pub token: token_identifier::TokenIdentifier,
 }
 
-#[derive(Debug, CandidType, Clone, Deserialize)]
-#[allow(non_camel_case_types)]
-pub enum Result__1_1 {
-    #[allow(non_camel_case_types)]
-    err(CommonError),
-    #[allow(non_camel_case_types)]
-    ok(AccountIdentifier__1),
-}
-
-#[allow(non_camel_case_types)]
-pub type AccountIdentifier__1 = String;
 pub type AccountIdentifier = String;
 #[allow(non_camel_case_types)]
-pub type TokenIdentifier__1 = String;
-#[allow(non_camel_case_types)]
 pub type Balance = Nat;
 
-#[derive(Debug, CandidType, Clone, Deserialize)]
-#[allow(non_camel_case_types)]
-pub enum CommonError__1 {
-    InvalidToken(String),
-    Other(String),
-}
-
 #[derive(Debug, CandidType, Clone, Deserialize)]
 pub enum CommonError {
     InvalidToken(String),
@@ -180,13 +692,64 @@ pub enum TokenMetaDataExt {
 
 #[derive(Debug, CandidType, Clone, Deserialize)]
 pub struct Listing {
-    locked: Option<Time>,
-    price: u64,
-    seller: Principal,
+    pub locked: Option<Time>,
+    pub price: u64,
+    pub seller: Principal,
+}
+
+#[derive(Debug, CandidType, Clone, Deserialize)]
+pub struct ListRequest {
+    pub token: token_identifier::TokenIdentifier,
+    pub from_subaccount: Option<SubAccount>,
+    pub price: Option<u64>,
+}
+
+// A buyer's pending purchase, paid into `subaccount` of this canister.
+#[derive(Debug, CandidType, Clone, Deserialize)]
+pub struct Sale {
+    pub buyer: Principal,
+    pub seller: Principal,
+    pub price: u64,
+    pub subaccount: Vec<u8>,
+    pub expires_at: u64,
+}
+
+#[derive(Debug, CandidType, Clone, Deserialize)]
+// `memo` and `created_at_time` are fixed when the payout is created and reused
+// on every retry, so the ICP ledger recognises a retry of a transfer that did
+// go through as a duplicate instead of paying twice.
+pub struct Payout {
+    pub subaccount: Vec<u8>,
+    pub to: AccountIdentifier,
+    pub amount: u64,
+    pub memo: u64,
+    pub created_at_time: u64,
+    pub error: String,
+}
+
+#[derive(Debug, CandidType, Clone, Deserialize)]
+pub enum ExtResult {
+    #[allow(non_camel_case_types)]
+    err(CommonError),
+    #[allow(non_camel_case_types)]
+    ok(()),
 }
 
 #[derive(Debug, CandidType, Clone, Deserialize)]
-pub struct Registry(TokenIndex, AccountIdentifier__1);
+pub enum LockResponse {
+    #[allow(non_camel_case_types)]
+    err(CommonError),
+    #[allow(non_camel_case_types)]
+    ok(AccountIdentifier),
+}
+
+#[derive(Debug, CandidType, Clone, Deserialize)]
+pub enum DetailsResponse {
+    #[allow(non_camel_case_types)]
+    err(CommonError),
+    #[allow(non_camel_case_types)]
+    ok((AccountIdentifier, Option<Listing>)),
+}
 
 #[derive(Debug, CandidType, Clone, Deserialize)]
 pub enum TransferResponse {
@@ -215,6 +778,7 @@ pub struct TransferRequest {
     pub subaccount: Option<SubAccount>,
     pub to: User,
     pub token: token_identifier::TokenIdentifier,
+    pub created_at_time: Option<u64>,
 }
 
 #[derive(Debug, Clone, CandidType, Deserialize)]
@@ -226,7 +790,8 @@ pub struct TransferRequestV1 {
     pub subaccount: Option<SubAccount>,
     pub to: User,
     pub class: String,
-    pub num: usize,
+    pub num: u64,
+    pub created_at_time: Option<u64>,
 }
 
 #[derive(Debug, Clone, CandidType, Deserialize)]
@@ -238,28 +803,11 @@ pub struct TransferRequestV2 {
     pub subaccount: Option<SubAccount>,
     pub to: User,
     pub token_list: Vec<Token_ID>,
+    pub created_at_time: Option<u64>,
 }
 
 pub type Memo = Vec<u8>;
 
-#[derive(Debug, Clone, CandidType, Deserialize)]
-#[allow(non_camel_case_types)]
-pub enum Result__1_2 {
-    #[allow(non_camel_case_types)]
-    err(CommonError),
-    #[allow(non_camel_case_types)]
-    ok(Balance__1),
-}
-
-#[derive(Debug, Clone, CandidType, Deserialize)]
-#[allow(non_camel_case_types)]
-pub enum Result__1 {
-    #[allow(non_camel_case_types)]
-    err(CommonError),
-    #[allow(non_camel_case_types)]
-    ok(TokenMetaDataExt),
-}
-
 #[derive(Debug, Clone, CandidType, Deserialize)]
 #[allow(non_camel_case_types)]
 pub enum Result_2 {
@@ -270,12 +818,11 @@ pub enum Result_2 {
 }
 
 #[derive(Debug, Clone, CandidType, Deserialize)]
-#[allow(non_camel_case_types)]
-pub enum Result_1 {
+pub enum RoyaltyResponse {
     #[allow(non_camel_case_types)]
     err(CommonError),
     #[allow(non_camel_case_types)]
-    ok(Vec<TokenIndex>),
+    ok(Option<Royalty>),
 }
 
 #[derive(Debug, Clone, CandidType, Deserialize)]
@@ -291,18 +838,6 @@ pub enum NFTResult {
 #[allow(non_camel_case_types)]
 pub type Balance__1 = Nat;
 
-#[derive(Debug, Clone, CandidType, Deserialize)]
-pub enum BalanceResponse {
-    #[allow(non_camel_case_types)]
-    err(CommonError__1),
-    #[allow(non_camel_case_types)]
-    ok(Balance),
-}
-#[derive(Debug, Clone, CandidType, Deserialize)]
-pub struct BalanceRequest {
-    token: u64,
-    user: User,
-}
 
 pub fn pid2aid(pid: &Principal) -> String {
     let sub_acc = ic_ledger_types::Subaccount([0u8; 32]);
@@ -313,3 +848,307 @@ pub fn pid2aid(pid: &Principal) -> String {
     //  }
     account_id.to_string()
 }
+
+/////////////// ICRC TYPES ////////////
+
+#[derive(Debug, Clone, CandidType, Deserialize, PartialEq, Eq)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+impl Account {
+    // Tokens are owned by principals, so only the default subaccount can hold them.
+    pub fn is_default(&self) -> bool {
+        self.subaccount
+            .as_ref()
+            .map_or(true, |subaccount| subaccount.iter().all(|byte| *byte == 0))
+    }
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize, PartialEq)]
+pub enum Value {
+    Nat(Nat),
+    Int(Int),
+    Text(String),
+    Blob(Vec<u8>),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+impl From<GeneralValue> for Value {
+    fn from(value: GeneralValue) -> Self {
+        match value {
+            GeneralValue::BoolContent(value) => Value::Nat(Nat::from(value as u8)),
+            GeneralValue::TextContent(value) => Value::Text(value),
+            GeneralValue::BlobContent(value) => Value::Blob(value),
+            GeneralValue::Principal(value) => Value::Text(value.to_text()),
+            GeneralValue::Nat8Content(value) => Value::Nat(Nat::from(value)),
+            GeneralValue::Nat16Content(value) => Value::Nat(Nat::from(value)),
+            GeneralValue::Nat32Content(value) => Value::Nat(Nat::from(value)),
+            GeneralValue::Nat64Content(value) => Value::Nat(Nat::from(value)),
+            GeneralValue::NatContent(value) => Value::Nat(value),
+            GeneralValue::Int8Content(value) => Value::Int(Int::from(value)),
+            GeneralValue::Int16Content(value) => Value::Int(Int::from(value)),
+            GeneralValue::Int32Content(value) => Value::Int(Int::from(value)),
+            GeneralValue::Int64Content(value) => Value::Int(Int::from(value)),
+            GeneralValue::IntContent(value) => Value::Int(value),
+            GeneralValue::FloatContent(value) => Value::Text(value.to_string()),
+            GeneralValue::NestedContent(values) => Value::Map(
+                values
+                    .into_iter()
+                    .map(|(key, value)| (key, Value::from(value)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct SupportedStandard {
+    pub name: String,
+    pub url: String,
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct Icrc7TransferArg {
+    pub from_subaccount: Option<Vec<u8>>,
+    pub to: Account,
+    pub token_id: Nat,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub enum Icrc7TransferError {
+    NonExistingTokenId,
+    InvalidRecipient,
+    Unauthorized,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub type Icrc7TransferResult = Result<Nat, Icrc7TransferError>;
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct ApprovalInfo {
+    pub spender: Account,
+    pub from_subaccount: Option<Vec<u8>>,
+    pub expires_at: Option<u64>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: u64,
+}
+
+impl ApprovalInfo {
+    pub fn is_active(&self, now: u64) -> bool {
+        self.expires_at.map_or(true, |expires_at| now < expires_at)
+    }
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct ApproveTokenArg {
+    pub token_id: Nat,
+    pub approval_info: ApprovalInfo,
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub enum ApproveTokenError {
+    InvalidSpender,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub type ApproveTokenResult = Result<Nat, ApproveTokenError>;
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct ApproveCollectionArg {
+    pub approval_info: ApprovalInfo,
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub enum ApproveCollectionError {
+    InvalidSpender,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub type ApproveCollectionResult = Result<Nat, ApproveCollectionError>;
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct RevokeTokenApprovalArg {
+    pub spender: Option<Account>,
+    pub from_subaccount: Option<Vec<u8>>,
+    pub token_id: Nat,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub enum RevokeTokenApprovalError {
+    ApprovalDoesNotExist,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub type RevokeTokenApprovalResult = Result<Nat, RevokeTokenApprovalError>;
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct RevokeCollectionApprovalArg {
+    pub spender: Option<Account>,
+    pub from_subaccount: Option<Vec<u8>>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub enum RevokeCollectionApprovalError {
+    ApprovalDoesNotExist,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub type RevokeCollectionApprovalResult = Result<Nat, RevokeCollectionApprovalError>;
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct IsApprovedArg {
+    pub spender: Account,
+    pub from_subaccount: Option<Vec<u8>>,
+    pub token_id: Nat,
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct TokenApproval {
+    pub token_id: Nat,
+    pub approval_info: ApprovalInfo,
+}
+
+pub type CollectionApproval = ApprovalInfo;
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct TransferFromArg {
+    pub spender_subaccount: Option<Vec<u8>>,
+    pub from: Account,
+    pub to: Account,
+    pub token_id: Nat,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub enum TransferFromError {
+    InvalidRecipient,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub type TransferFromResult = Result<Nat, TransferFromError>;
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct GetBlocksArgs {
+    pub start: Nat,
+    pub length: Nat,
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct BlockWithId {
+    pub id: Nat,
+    pub block: Value,
+}
+
+// `func (vec GetBlocksArgs) -> (GetBlocksResult) query`. A bare `Func` would be
+// typed as `func () -> ()`, which clients cannot call.
+#[derive(Debug, Clone, Deserialize)]
+pub struct GetBlocksCallback(pub Func);
+
+impl CandidType for GetBlocksCallback {
+    fn _ty() -> Type {
+        Type::Func(Function {
+            modes: vec![FuncMode::Query],
+            args: vec![Vec::<GetBlocksArgs>::ty()],
+            rets: vec![GetBlocksResult::ty()],
+        })
+    }
+
+    fn idl_serialize<S: Serializer>(&self, serializer: S) -> Result<(), S::Error> {
+        self.0.idl_serialize(serializer)
+    }
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct ArchivedBlocks {
+    pub args: Vec<GetBlocksArgs>,
+    pub callback: GetBlocksCallback,
+}
+
+// Blocks moved to the block archive are listed in `archived_blocks` rather
+// than returned, with the callback that fetches them from there.
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct GetBlocksResult {
+    pub log_length: Nat,
+    pub blocks: Vec<BlockWithId>,
+    pub archived_blocks: Vec<ArchivedBlocks>,
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct DataCertificate {
+    pub certificate: Vec<u8>,
+    pub hash_tree: Vec<u8>,
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct GetArchivesArgs {
+    pub from: Option<Principal>,
+}
+
+// `start` and `end` are the first and last block index the archive holds.
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct ArchiveInfo {
+    pub canister_id: Principal,
+    pub start: Nat,
+    pub end: Nat,
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct SupportedBlockType {
+    pub block_type: String,
+    pub url: String,
+}
+
+/////////////// CERTIFIED TYPES ////////////
+
+// `certificate.hash_tree` witnesses the token's leaf under the `tokens` label.
+#[derive(Clone, CandidType, Deserialize)]
+pub struct CertifiedToken {
+    pub token_identifier: Nat,
+    pub owner: Option<Principal>,
+    pub properties: Vec<(String, GeneralValue)>,
+    pub certificate: DataCertificate,
+}
+
+// `certificate.hash_tree` witnesses the owner's leaf under the `owners` label.
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct CertifiedTokens {
+    pub owner: Principal,
+    pub token_identifiers: Vec<Nat>,
+    pub certificate: DataCertificate,
+}
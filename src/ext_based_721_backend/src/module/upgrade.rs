@@ -0,0 +1,365 @@
+use crate::module::cap;
+use crate::module::certification;
+use crate::module::craft;
+use crate::module::dip721;
+use crate::module::env::time;
+use crate::module::ledger::{self, Ledger};
+use crate::module::mint_phase;
+use crate::module::mint_sale;
+use crate::module::mystery_box;
+use crate::module::reveal;
+use crate::module::types::{
+    CapOutbox, GeneralValue, MetaData, MintPhases, MintSale, MysteryBox, Recipe, RevealConfig,
+    TokenMetaData, Token_ID,
+};
+use crate::prop::{self, PropMetadata};
+use ic_cdk::export::candid::de::IDLDeserialize;
+use ic_cdk::export::candid::{CandidType, Deserialize, Nat};
+use ic_cdk::export::Principal;
+use std::collections::{HashMap, HashSet};
+
+// Version of the layout `pre_upgrade` writes, saved ahead of the state itself.
+// Whenever the saved state changes shape, bump it, keep the previous layout's
+// types below and teach `decode` to migrate them.
+pub const STATE_VERSION: u32 = 1;
+
+#[derive(CandidType, Deserialize)]
+pub struct StableState {
+    pub ledger: Ledger,
+    pub tid: u32,
+    pub props: Vec<PropMetadata>,
+    pub recipes: Vec<Recipe>,
+    pub boxes: Vec<MysteryBox>,
+    pub reveals: Vec<RevealConfig>,
+    pub mint_sales: Vec<MintSale>,
+    pub mint_phases: MintPhases,
+    pub cap_outbox: CapOutbox,
+}
+
+// Takes the state out of every registry; only called from `pre_upgrade`.
+pub fn snapshot() -> StableState {
+    StableState {
+        ledger: ledger::with_mut(std::mem::take),
+        tid: dip721::tid_info(),
+        props: prop::prop_info(),
+        recipes: craft::recipes_info(),
+        boxes: mystery_box::boxes_info(),
+        reveals: reveal::reveals_info(),
+        mint_sales: mint_sale::sales_info(),
+        mint_phases: mint_phase::phases_info(),
+        cap_outbox: cap::outbox_info(),
+    }
+}
+
+pub fn restore(state: StableState) {
+    ledger::with_mut(|ledger| {
+        *ledger = state.ledger;
+        ledger.metadata_mut().upgraded_at = time();
+    });
+    dip721::restore_tid_info(state.tid);
+    prop::restore_prop_info(state.props);
+    craft::restore_recipes_info(state.recipes);
+    mystery_box::restore_boxes_info(state.boxes);
+    reveal::restore_reveals_info(state.reveals);
+    mint_sale::restore_sales_info(state.mint_sales);
+    mint_phase::restore_phases_info(state.mint_phases);
+    cap::restore_outbox_info(state.cap_outbox);
+    // Certified data does not survive an upgrade. Rebuild it last: the leaves
+    // hash what clients may see, which depends on the restored reveals.
+    ledger::with(certification::rebuild);
+}
+
+// Reads `(version, state, archive)`. Releases before versioning saved a bare
+// tuple starting with the ledger record, so a first value that is not a
+// version number means the baseline layout. `A` is the CAP archive, left
+// generic so tests do not depend on cap_sdk.
+pub fn decode<A>(bytes: &[u8]) -> Result<(StableState, A), String>
+where
+    A: CandidType + for<'de> Deserialize<'de>,
+{
+    let mut de = IDLDeserialize::new(bytes).map_err(|err| format!("{:?}", err))?;
+    match de.get_value::<u32>() {
+        Ok(STATE_VERSION) => {
+            let state = de
+                .get_value::<StableState>()
+                .map_err(|err| format!("{:?}", err))?;
+            let archive = de.get_value::<A>().map_err(|err| format!("{:?}", err))?;
+            Ok((state, archive))
+        }
+        Ok(version) => Err(format!("unsupported stable state version {}", version)),
+        Err(_) => decode_v0(bytes),
+    }
+}
+
+// The baseline layout: `(Ledger, Archive, u32, Vec<PropMetadata>)`.
+fn decode_v0<A>(bytes: &[u8]) -> Result<(StableState, A), String>
+where
+    A: CandidType + for<'de> Deserialize<'de>,
+{
+    let mut de = IDLDeserialize::new(bytes).map_err(|err| format!("{:?}", err))?;
+    let ledger = de
+        .get_value::<LedgerV0>()
+        .map_err(|err| format!("{:?}", err))?;
+    let archive = de.get_value::<A>().map_err(|err| format!("{:?}", err))?;
+    let tid = de.get_value::<u32>().map_err(|err| format!("{:?}", err))?;
+    let props = de
+        .get_value::<Vec<PropMetadataV0>>()
+        .map_err(|err| format!("{:?}", err))?;
+
+    let state = StableState {
+        ledger: ledger.migrate(),
+        tid,
+        props: props.into_iter().map(PropMetadataV0::migrate).collect(),
+        recipes: vec![],
+        boxes: vec![],
+        reveals: vec![],
+        mint_sales: vec![],
+        mint_phases: MintPhases::default(),
+        cap_outbox: CapOutbox::default(),
+    };
+    Ok((state, archive))
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct MetaDataV0 {
+    pub name: Option<String>,
+    pub logo: Option<String>,
+    pub symbol: Option<String>,
+    pub custodians: HashSet<Principal>,
+    pub created_at: u64,
+    pub upgraded_at: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct TokenMetaDataV0 {
+    pub token_identifier: Token_ID,
+    pub owner: Option<Principal>,
+    pub operator: Option<Principal>,
+    pub is_burned: bool,
+    pub properties: Vec<(String, GeneralValue)>,
+    pub minted_at: u64,
+    pub minted_by: Principal,
+    pub transferred_at: Option<u64>,
+    pub transferred_by: Option<Principal>,
+    pub approved_at: Option<u64>,
+    pub approved_by: Option<Principal>,
+    pub burned_at: Option<u64>,
+    pub burned_by: Option<Principal>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct LedgerV0 {
+    pub metadata: MetaDataV0,
+    pub tokens: HashMap<Token_ID, TokenMetaDataV0>,
+    pub owners: HashMap<Principal, HashSet<Token_ID>>,
+    pub operators: HashMap<Principal, HashSet<Token_ID>>,
+    pub tx_count: Nat,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct PropMetadataV0 {
+    pub calss: String,
+    pub desc: String,
+    pub image_uri: String,
+}
+
+impl LedgerV0 {
+    // Everything the baseline did not have starts empty: no pauses, roles,
+    // royalties, locks or history, and no token is soulbound or rented.
+    fn migrate(self) -> Ledger {
+        let metadata = MetaData {
+            name: self.metadata.name,
+            logo: self.metadata.logo,
+            symbol: self.metadata.symbol,
+            custodians: self.metadata.custodians,
+            created_at: self.metadata.created_at,
+            upgraded_at: self.metadata.upgraded_at,
+            ..MetaData::default()
+        };
+        let tokens = self
+            .tokens
+            .into_iter()
+            .map(|(token_identifier, token)| {
+                let token = TokenMetaData {
+                    token_identifier: token.token_identifier,
+                    owner: token.owner,
+                    operator: token.operator,
+                    is_burned: token.is_burned,
+                    properties: token.properties,
+                    minted_at: token.minted_at,
+                    minted_by: token.minted_by,
+                    transferred_at: token.transferred_at,
+                    transferred_by: token.transferred_by,
+                    approved_at: token.approved_at,
+                    approved_by: token.approved_by,
+                    burned_at: token.burned_at,
+                    burned_by: token.burned_by,
+                    soulbound: false,
+                    reveal_commitment: None,
+                    user: None,
+                    user_expires: None,
+                };
+                (token_identifier, token)
+            })
+            .collect();
+        Ledger {
+            metadata,
+            tokens,
+            owners: self.owners,
+            operators: self.operators,
+            tx_count: self.tx_count,
+            ..Ledger::default()
+        }
+    }
+}
+
+impl PropMetadataV0 {
+    fn migrate(self) -> PropMetadata {
+        PropMetadata::from_parts(self.calss, self.desc, self.image_uri)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::env;
+    use crate::module::testing;
+    use ic_cdk::export::candid::encode_args;
+
+    fn principal(n: u8) -> Principal {
+        Principal::from_slice(&[n; 10])
+    }
+
+    // Shaped exactly like what the deployed baseline canister saves.
+    fn baseline_blob() -> Vec<u8> {
+        let owner = principal(1);
+        let custodian = principal(2);
+        let token = TokenMetaDataV0 {
+            token_identifier: Nat::from(7u32),
+            owner: Some(owner),
+            operator: None,
+            is_burned: false,
+            properties: vec![("class".into(), GeneralValue::TextContent("H".into()))],
+            minted_at: 10,
+            minted_by: custodian,
+            transferred_at: None,
+            transferred_by: None,
+            approved_at: None,
+            approved_by: None,
+            burned_at: None,
+            burned_by: None,
+        };
+        let ledger = LedgerV0 {
+            metadata: MetaDataV0 {
+                name: Some("Shiku".into()),
+                logo: None,
+                symbol: Some("SHK".into()),
+                custodians: [custodian].into_iter().collect(),
+                created_at: 1,
+                upgraded_at: 2,
+            },
+            tokens: [(Nat::from(7u32), token)].into_iter().collect(),
+            owners: [(owner, [Nat::from(7u32)].into_iter().collect())]
+                .into_iter()
+                .collect(),
+            operators: HashMap::new(),
+            tx_count: Nat::from(3u32),
+        };
+        let props = vec![PropMetadataV0 {
+            calss: "H".into(),
+            desc: "hydrogen".into(),
+            image_uri: "https://example.com/h.jpg".into(),
+        }];
+        encode_args((ledger, (), 7u32, props)).unwrap()
+    }
+
+    #[test]
+    fn baseline_layout_is_migrated() {
+        let (state, ()) = decode::<()>(&baseline_blob()).unwrap();
+
+        let metadata = &state.ledger.metadata;
+        assert_eq!(metadata.name.as_deref(), Some("Shiku"));
+        assert!(metadata.custodians.contains(&principal(2)));
+        assert!(metadata.paused.is_empty());
+        assert!(metadata.roles.is_empty());
+        assert_eq!(metadata.created_at, 1);
+
+        let token = &state.ledger.tokens[&Nat::from(7u32)];
+        assert_eq!(token.owner, Some(principal(1)));
+        assert!(!token.soulbound);
+        assert!(token.user.is_none());
+        assert!(state.ledger.owners[&principal(1)].contains(&Nat::from(7u32)));
+        assert_eq!(state.ledger.tx_count, Nat::from(3u32));
+        assert!(state.ledger.escrows.is_empty());
+
+        assert_eq!(state.tid, 7);
+        assert_eq!(state.props.len(), 1);
+        assert_eq!(state.props[0].class(), "H");
+        assert!(!state.props[0].soulbound());
+        assert!(state.recipes.is_empty());
+    }
+
+    #[test]
+    fn baseline_layout_does_not_decode_as_the_current_ledger() {
+        let blob = baseline_blob();
+        let mut de = IDLDeserialize::new(&blob).unwrap();
+        assert!(de.get_value::<Ledger>().is_err());
+    }
+
+    #[test]
+    fn current_layout_round_trips() {
+        let (mut state, ()) = decode::<()>(&baseline_blob()).unwrap();
+        state.ledger.metadata.paused.insert(crate::module::types::Operation::Mint);
+        state.tid = 9;
+        let bytes = encode_args((STATE_VERSION, state, ())).unwrap();
+
+        let (state, ()) = decode::<()>(&bytes).unwrap();
+        assert_eq!(state.tid, 9);
+        assert!(state
+            .ledger
+            .metadata
+            .paused
+            .contains(&crate::module::types::Operation::Mint));
+        assert_eq!(state.ledger.tokens.len(), 1);
+    }
+
+    #[test]
+    fn unknown_versions_are_rejected() {
+        let bytes = encode_args((STATE_VERSION + 1, ())).unwrap();
+        assert!(decode::<()>(&bytes).is_err());
+    }
+
+    // Restores `state` the way `post_upgrade` does, on a thread whose
+    // registries start out empty like those of the upgraded canister, and
+    // returns the certified data it publishes.
+    fn upgrade(state: StableState) -> Vec<u8> {
+        std::thread::spawn(move || {
+            restore(state);
+            env::certified_data()
+        })
+        .join()
+        .unwrap()
+    }
+
+    #[test]
+    fn hidden_tokens_stay_hidden_in_the_certified_state_after_an_upgrade() {
+        testing::setup();
+        reveal::with_mut(|reveals| {
+            reveals.insert(
+                None,
+                RevealConfig {
+                    class: None,
+                    placeholder: vec![("class".into(), GeneralValue::TextContent("?".into()))],
+                    seed: vec![1; 32],
+                    seed_hash: vec![2; 32],
+                    configured_at: 0,
+                    revealed_at: None,
+                },
+            )
+        });
+        testing::mint(testing::principal(1));
+        let certified = env::certified_data();
+
+        assert_eq!(upgrade(snapshot()), certified);
+    }
+}
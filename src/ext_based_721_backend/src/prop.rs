@@ -3,6 +3,7 @@ use ic_cdk::export::candid::{Nat};
 use candid::{Deserialize, CandidType};
 use serde::Serialize;
 use crate::module::token_identifier;
+use crate::module::types::{GeneralValue, Royalty};
 use serde_json::Result;
 use std::collections::HashMap;
 pub static PROP_STR: &str =r###"
@@ -19,15 +20,28 @@ pub struct PropMetadata {
     calss: String,
     desc: String,
     image_uri: String,
+    #[serde(default)]
+    royalty: Option<Royalty>,
+    #[serde(default)]
+    soulbound: bool,
 }
 
 impl PropMetadata {
-    pub fn new(data: &String) -> Result<PropMetadata> {
+    pub fn new(data: &str) -> Result<PropMetadata> {
         let prop: PropMetadata = serde_json::from_str(data)?;
 
         Ok(prop)
     }
 
+    pub fn from_parts(class: String, desc: String, image_uri: String) -> PropMetadata {
+        PropMetadata {
+            calss: class,
+            desc,
+            image_uri,
+            ..PropMetadata::default()
+        }
+    }
+
     pub fn class(&self) -> &String {
         &self.calss
     }
@@ -39,10 +53,26 @@ impl PropMetadata {
     pub fn image_uri(&self) -> &String {
         &self.image_uri
     }
+
+    pub fn royalty(&self) -> &Option<Royalty> {
+        &self.royalty
+    }
+
+    pub fn set_royalty(&mut self, royalty: Option<Royalty>) {
+        self.royalty = royalty;
+    }
+
+    pub fn soulbound(&self) -> bool {
+        self.soulbound
+    }
+
+    pub fn set_soulbound(&mut self, soulbound: bool) {
+        self.soulbound = soulbound;
+    }
 }
 
 thread_local! {
-    static PROPS: RefCell<Vec<PropMetadata>> = RefCell::new(Vec::new());
+    static PROPS: RefCell<Vec<PropMetadata>> = const { RefCell::new(Vec::new()) };
 
     static ID2TOKEN :RefCell<HashMap<Nat, token_identifier::TokenIdentifier>> = RefCell::new(HashMap::new());
 
@@ -83,9 +113,39 @@ pub fn init() -> Vec<PropMetadata>  {
     prop_info()
 }
 
-pub fn add_token(id: &Nat, prop: &String) {
+pub fn properties_of(class: &str) -> Vec<(String, GeneralValue)> {
+    with(|props| {
+        props
+            .iter()
+            .filter(|p| p.class() == class)
+            .map(|p| {
+                vec![
+                    (
+                        String::from("class"),
+                        GeneralValue::TextContent(p.class().clone()),
+                    ),
+                    (
+                        String::from("desc"),
+                        GeneralValue::TextContent(p.desc().clone()),
+                    ),
+                    (
+                        String::from("imageUri"),
+                        GeneralValue::TextContent(p.image_uri().clone()),
+                    ),
+                ]
+            })
+            .next()
+            .unwrap_or(vec![])
+    })
+}
+
+pub fn is_soulbound_class(class: &str) -> bool {
+    with(|props| props.iter().any(|p| p.class() == class && p.soulbound()))
+}
+
+pub fn add_token(id: &Nat, prop: &str) {
     ID2TOKEN.with(|tokenmap| {
-        tokenmap.borrow_mut().insert(id.to_owned(), prop.clone());
+        tokenmap.borrow_mut().insert(id.to_owned(), prop.to_owned());
     })
 }
 